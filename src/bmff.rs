@@ -0,0 +1,242 @@
+//! A small zero-copy reader for ISO base media file format (ISO BMFF) boxes, as used by CR3, plus
+//! just enough writing to wrap a HEVC preview up as a standalone HEIF file.
+//!
+//! Like the TIFF reader, everything borrows from the underlying buffer, and boxes we aren't
+//! interested in are skipped over without touching their contents. Offsets reported by [`BmffBox`]
+//! are absolute, relative to the start of the outermost buffer given to [`boxes`].
+
+use anyhow::{ensure, Context, Result};
+use byteorder::{BigEndian, ByteOrder as _};
+
+/// Whether `buf` looks like an ISO BMFF file, which always starts with an `ftyp` box.
+pub fn is_bmff(buf: &[u8]) -> bool {
+    buf.get(4..8) == Some(b"ftyp")
+}
+
+/// Iterate over the top level boxes in `buf`.
+pub fn boxes(buf: &[u8]) -> Boxes<'_> {
+    Boxes {
+        buf,
+        pos: 0,
+        base: 0,
+    }
+}
+
+/// A box, with its header parsed.
+#[derive(Clone, Copy, Debug)]
+pub struct BmffBox<'a> {
+    box_type: [u8; 4],
+    uuid: Option<[u8; 16]>,
+    data: &'a [u8],
+    data_offset: usize,
+}
+
+impl<'a> BmffBox<'a> {
+    pub fn box_type(&self) -> &[u8; 4] {
+        &self.box_type
+    }
+
+    /// The extended type of a `uuid` box.
+    pub fn uuid(&self) -> Option<&[u8; 16]> {
+        self.uuid.as_ref()
+    }
+
+    /// The contents of the box, after the header.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The absolute offset of [`BmffBox::data`].
+    pub fn data_offset(&self) -> usize {
+        self.data_offset
+    }
+
+    /// Iterate over child boxes, starting `skip` bytes into the contents. That's needed for boxes
+    /// which have fields before their children, like full boxes or sample entries.
+    pub fn children(&self, skip: usize) -> Boxes<'a> {
+        Boxes {
+            buf: self.data,
+            pos: skip,
+            base: self.data_offset,
+        }
+    }
+}
+
+/// An iterator over sibling boxes.
+#[derive(Clone, Debug)]
+pub struct Boxes<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    base: usize,
+}
+
+impl<'a> Boxes<'a> {
+    /// Find the first box of the given type, skipping any which are malformed.
+    pub fn find(self, box_type: &[u8; 4]) -> Option<BmffBox<'a>> {
+        self.map_while(Result::ok)
+            .find(|b| b.box_type() == box_type)
+    }
+
+    /// Find the first `uuid` box with the given extended type.
+    pub fn find_uuid(self, uuid: &[u8; 16]) -> Option<BmffBox<'a>> {
+        self.map_while(Result::ok).find(|b| b.uuid() == Some(uuid))
+    }
+
+    fn parse(&self) -> Result<(BmffBox<'a>, usize)> {
+        let rest = &self.buf[self.pos..];
+        ensure!(rest.len() >= 8, "Truncated box header");
+        let box_type = rest[4..8].try_into()?;
+        let (size, mut header_len) = match BigEndian::read_u32(&rest[0..4]) {
+            // The box extends to the end of its parent.
+            0 => (rest.len(), 8),
+            1 => {
+                let size = rest.get(8..16).context("Truncated box header")?;
+                (BigEndian::read_u64(size).try_into()?, 16)
+            }
+            size => (size.try_into()?, 8),
+        };
+        let uuid = if &box_type == b"uuid" {
+            let uuid = rest
+                .get(header_len..header_len + 16)
+                .context("Truncated box header")?;
+            header_len += 16;
+            Some(uuid.try_into()?)
+        } else {
+            None
+        };
+        ensure!(
+            size >= header_len && size <= rest.len(),
+            "Invalid box size {} at {}",
+            size,
+            self.base + self.pos
+        );
+        let parsed = BmffBox {
+            box_type,
+            uuid,
+            data: &rest[header_len..size],
+            data_offset: self.base + self.pos + header_len,
+        };
+        Ok((parsed, size))
+    }
+}
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = Result<BmffBox<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+        match self.parse() {
+            Ok((parsed, size)) => {
+                self.pos += size;
+                Some(Ok(parsed))
+            }
+            Err(e) => {
+                // Don't try to make sense of anything after a malformed box.
+                self.pos = self.buf.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn write_box(out: &mut Vec<u8>, box_type: &[u8; 4], contents: &[u8]) {
+    let size = u32::try_from(8 + contents.len()).expect("box too large");
+    out.extend_from_slice(&size.to_be_bytes());
+    out.extend_from_slice(box_type);
+    out.extend_from_slice(contents);
+}
+
+fn write_full_box(out: &mut Vec<u8>, box_type: &[u8; 4], version: u8, contents: &[u8]) {
+    let mut full = vec![version, 0, 0, 0];
+    full.extend_from_slice(contents);
+    write_box(out, box_type, &full);
+}
+
+/// Build the `meta` box for a HEIF file holding one HEVC image item, whose offset and length in
+/// the file are `data`.
+fn heif_meta(hvcc: &[u8], width: u32, height: u32, rotation: u8, data: (u32, u32)) -> Vec<u8> {
+    let mut meta = Vec::new();
+
+    let mut hdlr = vec![0; 4];
+    hdlr.extend_from_slice(b"pict");
+    hdlr.extend_from_slice(&[0; 13]);
+    write_full_box(&mut meta, b"hdlr", 0, &hdlr);
+
+    write_full_box(&mut meta, b"pitm", 0, &1u16.to_be_bytes());
+
+    let mut infe = Vec::new();
+    infe.extend_from_slice(&1u16.to_be_bytes()); // item_ID
+    infe.extend_from_slice(&0u16.to_be_bytes()); // item_protection_index
+    infe.extend_from_slice(b"hvc1");
+    infe.push(0); // item_name
+    let mut iinf = 1u16.to_be_bytes().to_vec();
+    write_full_box(&mut iinf, b"infe", 2, &infe);
+    write_full_box(&mut meta, b"iinf", 0, &iinf);
+
+    let mut iloc = vec![0x44, 0x00]; // 4 byte offsets and lengths, no base offset
+    iloc.extend_from_slice(&1u16.to_be_bytes()); // item_count
+    iloc.extend_from_slice(&1u16.to_be_bytes()); // item_ID
+    iloc.extend_from_slice(&0u16.to_be_bytes()); // data_reference_index
+    iloc.extend_from_slice(&1u16.to_be_bytes()); // extent_count
+    iloc.extend_from_slice(&data.0.to_be_bytes());
+    iloc.extend_from_slice(&data.1.to_be_bytes());
+    write_full_box(&mut meta, b"iloc", 0, &iloc);
+
+    let mut ipco = Vec::new();
+    write_box(&mut ipco, b"hvcC", hvcc);
+    let mut ispe = width.to_be_bytes().to_vec();
+    ispe.extend_from_slice(&height.to_be_bytes());
+    write_full_box(&mut ipco, b"ispe", 0, &ispe);
+    // Property indices are 1-based, with the top bit marking the property as essential.
+    let mut associations = vec![0x81, 0x02];
+    if rotation != 0 {
+        write_box(&mut ipco, b"irot", &[rotation & 3]);
+        associations.push(0x83);
+    }
+    let mut ipma = 1u32.to_be_bytes().to_vec(); // entry_count
+    ipma.extend_from_slice(&1u16.to_be_bytes()); // item_ID
+    ipma.push(associations.len() as u8);
+    ipma.extend_from_slice(&associations);
+    let mut iprp = Vec::new();
+    write_box(&mut iprp, b"ipco", &ipco);
+    write_full_box(&mut iprp, b"ipma", 0, &ipma);
+    write_box(&mut meta, b"iprp", &iprp);
+
+    let mut out = Vec::new();
+    write_full_box(&mut out, b"meta", 0, &meta);
+    out
+}
+
+/// Wrap a HEVC coded image up as a standalone HEIF file.
+///
+/// `hvcc` is the contents of the `hvcC` box describing the bitstream, and `data` is the bitstream
+/// itself, with length prefixed NAL units as stored in ISO BMFF samples. `rotation` is the number
+/// of anticlockwise quarter turns to display the image with, as stored in an `irot` property.
+pub fn wrap_hevc_image(
+    hvcc: &[u8],
+    width: u32,
+    height: u32,
+    rotation: u8,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let mut ftyp = b"heic".to_vec();
+    ftyp.extend_from_slice(&0u32.to_be_bytes());
+    ftyp.extend_from_slice(b"mif1heic");
+    let mut out = Vec::new();
+    write_box(&mut out, b"ftyp", &ftyp);
+
+    // Leave room for the mdat header too, so that writing it can't overflow.
+    u32::try_from(data.len() + 8).context("HEVC image too large for HEIF")?;
+    let length = data.len() as u32;
+    // The item's location depends on the size of the meta box, which doesn't depend on the
+    // location, so build it once to measure it.
+    let meta_len = heif_meta(hvcc, width, height, rotation, (0, length)).len();
+    let offset = u32::try_from(out.len() + meta_len + 8)?;
+    out.extend(heif_meta(hvcc, width, height, rotation, (offset, length)));
+
+    out.reserve(data.len() + 8);
+    write_box(&mut out, b"mdat", data);
+    Ok(out)
+}
@@ -0,0 +1,138 @@
+//! Output encoders, for when something other than the embedded JPEG itself is wanted.
+//!
+//! JPEG output is a pass-through of the embedded preview (subject to rotation and resizing as
+//! requested), so it's handled directly in the parser. Every other format needs the preview decoded
+//! to pixels first, which is what lives here.
+
+use anyhow::{anyhow, bail, ensure, Result};
+
+use crate::resize::{self, Pixels};
+
+/// The format to write extracted previews in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// The embedded JPEG, passed through without re-encoding where possible.
+    #[default]
+    Jpeg,
+    /// Lossless PNG, mostly useful for small thumbnails.
+    Png,
+    /// Lossy WebP at the given quality, from 1 to 100.
+    Webp { quality: u8 },
+    /// Lossless WebP.
+    WebpLossless,
+}
+
+impl OutputFormat {
+    /// The file extension for this format, without a leading dot.
+    pub const fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Webp { .. } | Self::WebpLossless => "webp",
+        }
+    }
+}
+
+/// Rearrange pixels so that an image stored with the given EXIF orientation displays correctly
+/// without it. None of our non-JPEG outputs can carry an orientation, so this has to be done
+/// before encoding them. A downscaled JPEG is rotated this way too, since it's re-encoded anyway.
+pub(crate) fn orient(src: Pixels, orientation: u16) -> Pixels {
+    if !(2..=8).contains(&orientation) {
+        return src;
+    }
+    let (w, h, ch) = (src.width, src.height, src.channels);
+    let transposed = orientation >= 5;
+    let (dw, dh) = if transposed { (h, w) } else { (w, h) };
+    let mut data = vec![0; src.data.len()];
+    for y in 0..h {
+        for x in 0..w {
+            let (dx, dy) = match orientation {
+                2 => (w - 1 - x, y),
+                3 => (w - 1 - x, h - 1 - y),
+                4 => (x, h - 1 - y),
+                5 => (y, x),
+                6 => (h - 1 - y, x),
+                7 => (h - 1 - y, w - 1 - x),
+                8 => (y, w - 1 - x),
+                _ => unreachable!("checked above"),
+            };
+            data[(dy * dw + dx) * ch..][..ch].copy_from_slice(&src.data[(y * w + x) * ch..][..ch]);
+        }
+    }
+    Pixels {
+        width: dw,
+        height: dh,
+        channels: ch,
+        data,
+    }
+}
+
+fn encode_png(pixels: &Pixels) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(
+        &mut out,
+        pixels.width.try_into()?,
+        pixels.height.try_into()?,
+    );
+    encoder.set_color(match pixels.channels {
+        1 => png::ColorType::Grayscale,
+        _ => png::ColorType::Rgb,
+    });
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels.data)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+fn encode_webp(pixels: &Pixels, quality: Option<u8>) -> Result<Vec<u8>> {
+    // libwebp only takes RGB(A), so grayscale has to be expanded.
+    let rgb;
+    let data = if pixels.channels == 1 {
+        rgb = pixels.data.iter().flat_map(|&l| [l, l, l]).collect();
+        &rgb
+    } else {
+        &pixels.data
+    };
+    let encoder =
+        webp::Encoder::from_rgb(data, pixels.width.try_into()?, pixels.height.try_into()?);
+    // encode() and encode_lossless() unwrap the result, and libwebp refuses images over 16383
+    // pixels on either side, so that would panic.
+    let encoded = encoder
+        .encode_simple(quality.is_none(), quality.map_or(75.0, f32::from))
+        .map_err(|e| anyhow!("Encoding WebP failed: {:?}", e))?;
+    Ok(encoded.to_vec())
+}
+
+/// Decode an extracted JPEG and re-encode it as `format`, applying `orientation` to the pixels and
+/// downscaling to `max_size` on the way if requested.
+///
+/// `format` must not be [`OutputFormat::Jpeg`], which doesn't need decoding.
+pub fn encode_preview(
+    jpeg: &[u8],
+    orientation: u16,
+    format: OutputFormat,
+    max_size: Option<u32>,
+) -> Result<Vec<u8>> {
+    let pixels = match max_size {
+        Some(max_size) => match resize::decode_fitted(jpeg, max_size)? {
+            Some(pixels) => pixels,
+            None => resize::decode(jpeg, None)?,
+        },
+        None => resize::decode(jpeg, None)?,
+    };
+    let pixels = orient(pixels, orientation);
+
+    match format {
+        OutputFormat::Jpeg => bail!("JPEG output is passed through, not re-encoded"),
+        OutputFormat::Png => encode_png(&pixels),
+        OutputFormat::Webp { quality } => {
+            ensure!(
+                (1..=100).contains(&quality),
+                "WebP quality must be between 1 and 100"
+            );
+            encode_webp(&pixels, Some(quality))
+        }
+        OutputFormat::WebpLossless => encode_webp(&pixels, None),
+    }
+}
@@ -0,0 +1,88 @@
+//! The RAW formats which are looked for when walking a directory, and how previews are found in
+//! each of them.
+
+use std::path::Path;
+
+/// How previews are found in a RAW format.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Container {
+    /// TIFF or BigTIFF based, with previews in the IFDs, SubIFDs and MakerNote.
+    Tiff,
+    /// ISO BMFF based, like CR3, with previews in their own boxes.
+    IsoBmff,
+    /// Phase One's own directory, with a TIFF structure in front in newer files.
+    PhaseOne,
+    /// Something else, which is searched for an Exif TIFF structure to find previews from. Files
+    /// without one are copied as-is.
+    EmbeddedExif,
+}
+
+impl Container {
+    pub const fn description(&self) -> &'static str {
+        match self {
+            Self::Tiff => "TIFF",
+            Self::IsoBmff => "ISO BMFF",
+            Self::PhaseOne => "Phase One IIQ",
+            Self::EmbeddedExif => "embedded Exif",
+        }
+    }
+}
+
+/// A RAW format, by the extensions its files have.
+#[derive(Clone, Copy, Debug)]
+pub struct RawFormat {
+    /// The extensions, lowercase and without a leading dot.
+    pub extensions: &'static [&'static str],
+    pub name: &'static str,
+    pub container: Container,
+}
+
+const fn format(
+    extensions: &'static [&'static str],
+    name: &'static str,
+    container: Container,
+) -> RawFormat {
+    RawFormat {
+        extensions,
+        name,
+        container,
+    }
+}
+
+/// Every RAW format looked for by default.
+pub const RAW_FORMATS: &[RawFormat] = &[
+    format(&["dng"], "Adobe Digital Negative", Container::Tiff),
+    format(&["cr2"], "Canon", Container::Tiff),
+    format(&["cr3"], "Canon", Container::IsoBmff),
+    format(&["crw"], "Canon CIFF", Container::EmbeddedExif),
+    format(&["erf"], "Epson", Container::Tiff),
+    format(&["raf"], "Fujifilm", Container::EmbeddedExif),
+    format(&["3fr", "fff"], "Hasselblad", Container::Tiff),
+    format(&["kdc"], "Kodak", Container::Tiff),
+    format(&["rwl"], "Leica", Container::Tiff),
+    format(&["mef"], "Mamiya", Container::Tiff),
+    format(&["mrw"], "Minolta", Container::EmbeddedExif),
+    format(&["nef", "nrw"], "Nikon", Container::Tiff),
+    format(&["orf"], "Olympus", Container::Tiff),
+    format(&["raw", "rw2"], "Panasonic", Container::Tiff),
+    format(&["pef"], "Pentax", Container::Tiff),
+    format(&["iiq"], "Phase One", Container::PhaseOne),
+    format(&["srw"], "Samsung", Container::Tiff),
+    format(&["x3f"], "Sigma", Container::EmbeddedExif),
+    format(&["arw", "sr2", "srf"], "Sony", Container::Tiff),
+];
+
+/// Every extension in [`RAW_FORMATS`].
+pub fn raw_extensions() -> impl Iterator<Item = &'static str> {
+    RAW_FORMATS
+        .iter()
+        .flat_map(|format| format.extensions.iter().copied())
+}
+
+/// The format of the RAW at `path`, going by its extension in any case.
+pub fn format_of(path: &Path) -> Option<&'static RawFormat> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    RAW_FORMATS
+        .iter()
+        .find(|format| format.extensions.contains(&extension.as_str()))
+}
@@ -0,0 +1,141 @@
+//! Working out which RAWs to process from the paths given on the command line.
+//!
+//! Directories are walked recursively for files with a RAW extension, and files given directly
+//! are processed whatever their extension. Each RAW is written mirroring its path relative to the
+//! deepest directory which holds everything given, so RAWs with the same name in different places
+//! never end up at the same output. With a single directory, that's the directory itself, and
+//! with a single file, the directory it's in.
+
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+
+/// A RAW to process.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Input {
+    pub path: PathBuf,
+    /// Where the output goes relative to the output directory, before any name template.
+    pub relative_path: PathBuf,
+}
+
+/// Split a list of files, as passed to `--files-from`, into paths. The list is NUL separated if
+/// it has any NULs in it, as from `find -print0`, and newline separated otherwise. Empty entries
+/// are ignored.
+pub fn parse_file_list(data: &[u8]) -> Result<Vec<PathBuf>> {
+    let separator = if data.contains(&0) { b'\0' } else { b'\n' };
+    data.split(|&byte| byte == separator)
+        .map(|entry| match separator {
+            b'\n' => entry.strip_suffix(b"\r").unwrap_or(entry),
+            _ => entry,
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::ffi::OsStr::from_bytes(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Result<PathBuf> {
+    let path = std::str::from_utf8(bytes).context("File list entry isn't valid UTF-8")?;
+    Ok(path.into())
+}
+
+/// `path` made absolute, with `.` and `..` resolved lexically, so that the same file given in
+/// different ways is recognised as such.
+pub(crate) fn absolute(path: &Path) -> Result<PathBuf> {
+    let mut absolute = std::env::current_dir()?;
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+    }
+    Ok(absolute)
+}
+
+/// The deepest directory which both `a` and `b` are in.
+fn common_ancestor(a: &Path, b: &Path) -> PathBuf {
+    a.components()
+        .zip(b.components())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a)
+        .collect()
+}
+
+/// Find every RAW to process from `paths`, which may be any mix of files and directories.
+/// `extensions` are the ones looked for in directories. Anything found more than once is only
+/// processed the first time.
+///
+/// Paths which don't exist are returned as files, so that they fail when processed like any other
+/// unreadable RAW, rather than stopping the whole run.
+pub async fn collect(paths: &[PathBuf], extensions: &HashSet<OsString>) -> Result<Vec<Input>> {
+    let mut roots = Vec::with_capacity(paths.len());
+    let mut base: Option<PathBuf> = None;
+    for root in paths {
+        let is_dir = tokio::fs::metadata(root)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir());
+        let absolute = absolute(root)?;
+        let dir = match is_dir {
+            true => absolute.as_path(),
+            false => absolute
+                .parent()
+                .filter(|_| root.file_name().is_some())
+                .with_context(|| format!("{} has no file name", root.display()))?,
+        };
+        base = Some(match base {
+            Some(base) => common_ancestor(&base, dir),
+            None => dir.to_path_buf(),
+        });
+        roots.push((root, absolute, is_dir));
+    }
+    let Some(base) = base else {
+        return Ok(Vec::new());
+    };
+
+    let mut inputs = Vec::new();
+    let mut seen = HashSet::new();
+    for (root, absolute, is_dir) in roots {
+        let relative_root = absolute.strip_prefix(&base)?;
+        if !is_dir {
+            if seen.insert(absolute.clone()) {
+                inputs.push(Input {
+                    path: root.clone(),
+                    relative_path: relative_root.to_path_buf(),
+                });
+            }
+            continue;
+        }
+
+        let mut dir_queue = vec![root.clone()];
+        while let Some(current_dir) = dir_queue.pop() {
+            let mut read_dir = tokio::fs::read_dir(&current_dir)
+                .await
+                .with_context(|| format!("Reading directory {}", current_dir.display()))?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    dir_queue.push(path);
+                } else if path.extension().is_some_and(|ext| extensions.contains(ext)) {
+                    let relative_path = relative_root.join(path.strip_prefix(root)?);
+                    if seen.insert(base.join(&relative_path)) {
+                        inputs.push(Input {
+                            path,
+                            relative_path,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(inputs)
+}
@@ -1,7 +1,46 @@
+#[macro_use]
+mod verbose;
+
+pub mod bmff;
+pub mod encode;
+pub mod formats;
+pub mod inputs;
+pub mod mpf;
+pub mod output;
 pub mod parser;
+pub mod report;
+pub mod resize;
+pub mod sync;
+pub mod template;
+pub mod tiff;
+pub mod transform;
+pub mod verify;
+
+pub use encode::OutputFormat;
+
+pub use output::{OutputClaims, OverwritePolicy, Turn, WriteOutcome};
 
 pub use parser::process_file;
+pub use parser::process_file_in_turn;
+pub use parser::process_file_with_options;
+pub use parser::process_file_with_report;
 
 pub use parser::process_file_bytes;
+pub use parser::process_file_bytes_with_options;
+
+pub use parser::find_all_embedded_jpegs;
+pub use parser::find_embedded_jpeg;
+pub use parser::metadata::{read_metadata, RawMetadata};
+pub use parser::EmbeddedJpegInfo;
+pub use parser::ExtractOptions;
+pub use parser::FileReport;
+pub use parser::FindJpegType;
+pub use parser::PreviewColorSpace;
+pub use parser::PreviewKind;
+pub use parser::Timestamps;
+
+pub use resize::ResizeOptions;
+
+pub use template::NameTemplate;
 
-pub use parser::FindJpegType;
\ No newline at end of file
+pub use verbose::set_verbose;
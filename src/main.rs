@@ -1,23 +1,138 @@
-use anyhow::{bail, Result};
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 use indicatif::{ProgressBar, ProgressStyle};
-use jpgfromraw::parser::process_file;
+use jpgfromraw::inputs::{self, Input};
+use jpgfromraw::parser::process_file_in_turn;
+use jpgfromraw::parser::{find_all_embedded_jpegs, find_embedded_jpeg};
+use jpgfromraw::report::ReportWriter;
+use jpgfromraw::sync::{Record, SyncState};
+use jpgfromraw::verify::{self, Problem};
+use jpgfromraw::{formats, output, read_metadata};
+use jpgfromraw::{
+    ExtractOptions, FileReport, NameTemplate, OutputFormat, OverwritePolicy, ResizeOptions,
+    Timestamps, Turn, WriteOutcome,
+};
 use std::collections::HashSet;
 use std::ffi::OsString;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs::{self};
 use tokio::sync::Semaphore;
 
 #[derive(Parser)]
-#[command(author, version, about)]
-struct Args {
-    /// Input directory containing RAW files
-    input_dir: PathBuf,
+#[command(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Output directory to store extracted JPEGs
-    #[arg(default_value = ".")]
-    output_dir: PathBuf,
+    /// Print diagnostics about each file to stderr, like where its preview was found and how long
+    /// each stage took.
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Without a subcommand, extract as with `extract`, for compatibility.
+    #[command(flatten)]
+    extract: ExtractArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Extract previews from RAW files and directories of them.
+    Extract(ExtractArgs),
+    /// List every embedded preview in a RAW file, and its key tags. The preview which would be
+    /// extracted is marked with a *.
+    Info {
+        /// The RAW file to look at
+        file: PathBuf,
+    },
+    /// List the RAWs which extracting would process, with their format and their path relative to
+    /// the output directory, before any name template.
+    List {
+        /// RAW files, and directories to look for them in recursively. The current directory is
+        /// used if there are none.
+        #[arg(value_name = "PATH")]
+        paths: Vec<PathBuf>,
+
+        /// Also list the files listed in this file, or on stdin with "-", as with extract.
+        #[arg(long, value_name = "FILE")]
+        files_from: Option<PathBuf>,
+
+        /// Look for this extension in addition to the default list.
+        #[arg(short, long)]
+        extension: Option<OsString>,
+    },
+    /// Print a RAW file's camera metadata as JSON.
+    Metadata {
+        /// The RAW file to look at
+        file: PathBuf,
+    },
+    /// Check that every JPEG in an output directory is complete and valid, and with --input-dir,
+    /// that every RAW's outputs are there and match it.
+    Verify {
+        /// The output directory to check
+        output_dir: PathBuf,
+
+        /// Also check that every RAW in this directory has its outputs, and that they match it.
+        #[arg(long)]
+        input_dir: Option<PathBuf>,
+
+        /// The outputs were rotated with --rotate.
+        #[arg(long, requires = "input_dir")]
+        rotate: bool,
+
+        /// The outputs were downscaled with --max-size.
+        #[arg(long, requires = "input_dir")]
+        max_size: Option<u32>,
+
+        /// The quality the outputs were re-encoded with, with --max-size or --format webp. 85 by
+        /// default.
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+        quality: Option<u8>,
+
+        /// The format the outputs were written in.
+        #[arg(long, value_enum, default_value_t = Format::Jpeg, requires = "input_dir")]
+        format: Format,
+
+        /// The outputs were extracted with --jxl.
+        #[arg(long, requires = "input_dir")]
+        jxl: bool,
+
+        /// The name template the outputs were written with.
+        #[arg(long, requires = "input_dir")]
+        name_template: Option<NameTemplate>,
+
+        /// The outputs were sorted into folders with --by-date.
+        #[arg(long, conflicts_with = "name_template", requires = "input_dir")]
+        by_date: bool,
+    },
+    /// List the RAW formats which are looked for.
+    Formats,
+}
+
+#[derive(clap::Args)]
+struct ExtractArgs {
+    /// RAW files, and directories to look for them in recursively, followed by the output
+    /// directory unless --output-dir is given. The current directory is used if there's nothing
+    /// left over for it. Outputs mirror each RAW's path relative to the deepest directory which
+    /// holds all of them.
+    #[arg(value_name = "PATH", required_unless_present = "files_from")]
+    paths: Vec<PathBuf>,
+
+    /// Output directory to store extracted JPEGs. Everything positional is then an input.
+    #[arg(short, long)]
+    output_dir: Option<PathBuf>,
+
+    /// Also process the files listed in this file, or on stdin with "-". Entries are separated by
+    /// NULs if there are any, as from `find -print0`, and newlines otherwise.
+    #[arg(long, value_name = "FILE")]
+    files_from: Option<PathBuf>,
 
     /// How many files to process at once
     #[arg(short, long, default_value_t = 8)]
@@ -25,63 +140,479 @@ struct Args {
 
     /// Look for this extension in addition to the default list.
     ///
-    /// Default list: arw, cr2, crw, dng, erf, kdc, mef, mrw, nef, nrw, orf, pef, raf, raw, rw2,
-    /// rwl, sr2, srf, srw, x3f
+    /// Default list: 3fr, arw, cr2, cr3, crw, dng, erf, fff, iiq, kdc, mef, mrw, nef, nrw, orf, pef,
+    /// raf, raw, rw2, rwl, sr2, srf, srw, x3f
     #[arg(short, long)]
     extension: Option<OsString>,
+
+    /// Losslessly rotate JPEGs to match their orientation, for viewers which ignore EXIF.
+    #[arg(long)]
+    rotate: bool,
+
+    /// Downscale JPEGs so that their long edge is at most this many pixels.
+    #[arg(long)]
+    max_size: Option<u32>,
+
+    /// Quality to use for lossy re-encoding: JPEGs downscaled with --max-size, and lossy WebP.
+    /// Only allowed with one of those, and 85 by default.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: Option<u8>,
+
+    /// Format to write previews in. Anything other than JPEG requires re-encoding the preview.
+    #[arg(long, value_enum, default_value_t = Format::Jpeg)]
+    format: Format,
+
+    /// Also consider JPEG XL previews in DNG 1.7 files, and write them as-is as .jxl files.
+    #[arg(long)]
+    jxl: bool,
+
+    /// Also extract secondary images linked from the preview's MPF segment, as NAME-mpfN.jpg.
+    #[arg(long)]
+    mpf_images: bool,
+
+    /// Where to write each preview, relative to the output directory, instead of mirroring the
+    /// input directory. For example: "{date:%Y/%m/%d}/{model}_{stem}_{seq}.jpg".
+    ///
+    /// Placeholders: {stem}, {dir}, {ext}, {date} or {date:FORMAT}, {make}, {model}, {lens},
+    /// {width}, {height}, and {seq} or {seq:WIDTH}, which counts up to avoid overwriting files.
+    #[arg(long)]
+    name_template: Option<NameTemplate>,
+
+    /// Sort previews into YYYY/YYYY-MM-DD/ folders by when they were taken, from the EXIF date or
+    /// else the RAW's modification time.
+    #[arg(long, conflicts_with = "name_template")]
+    by_date: bool,
+
+    /// What to do when an output file already exists. Two RAWs in one run which would be written
+    /// to the same place, like IMG_0001.CR2 and IMG_0001.DNG, are always renamed rather than
+    /// overwriting each other.
+    #[arg(long, value_enum, default_value_t = Overwrite::Always)]
+    overwrite: Overwrite,
+
+    /// Flush each output to disk before moving on, so that it survives a crash or power cut.
+    /// Slower, especially on spinning disks.
+    #[arg(long)]
+    fsync: bool,
+
+    /// What to set the timestamps of written files to. Note that with --overwrite=if-newer,
+    /// "capture" makes outputs look older than their RAWs, so they're always extracted again.
+    #[arg(long, value_enum, default_value_t = Times::Written)]
+    timestamps: Times,
+
+    /// Only extract RAWs which are new or have changed since the last incremental run into the
+    /// same output directory, going by their size and modification time. What was extracted is
+    /// kept track of in .jpgfromraw-state.json in the output directory.
+    #[arg(long)]
+    incremental: bool,
+
+    /// With --incremental, also fingerprint RAWs' contents, so that ones whose modification time
+    /// changed but whose contents didn't aren't extracted again.
+    #[arg(long, requires = "incremental")]
+    fingerprint: bool,
+
+    /// With --incremental, delete the outputs of RAWs which are no longer in the input directory.
+    /// Only possible with a single input directory.
+    #[arg(long, requires = "incremental")]
+    delete_orphans: bool,
+
+    /// Write a report to this file as JSON Lines, with one object per RAW: what happened to it,
+    /// details of the preview found, and how long each stage took.
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Don't write anything, just show what would be done: the preview found in each RAW and
+    /// where it would be written.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Jpeg,
+    Png,
+    Webp,
+    WebpLossless,
+}
+
+/// The quality for lossy re-encoding when nothing else is asked for.
+const DEFAULT_QUALITY: u8 = 85;
+
+/// The quality to re-encode with, from `--quality`. Giving one when nothing is re-encoded lossily
+/// is an error, rather than silently ignored.
+fn quality_for(quality: Option<u8>, max_size: Option<u32>, format: Format) -> Result<u8> {
+    if quality.is_some() && max_size.is_none() && !matches!(format, Format::Webp) {
+        bail!("--quality only applies with --max-size or --format webp");
+    }
+    Ok(quality.unwrap_or(DEFAULT_QUALITY))
+}
+
+impl Format {
+    /// The format to write, with `quality` for lossy WebP.
+    fn output_format(self, quality: u8) -> OutputFormat {
+        match self {
+            Self::Jpeg => OutputFormat::Jpeg,
+            Self::Png => OutputFormat::Png,
+            Self::Webp => OutputFormat::Webp { quality },
+            Self::WebpLossless => OutputFormat::WebpLossless,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Overwrite {
+    /// Replace it.
+    Always,
+    /// Keep it, and skip the RAW.
+    Never,
+    /// Replace it only if the RAW is newer.
+    IfNewer,
+    /// Keep it, and write to NAME-N instead.
+    Rename,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Times {
+    /// Leave them as when the file was written.
+    Written,
+    /// Copy the RAW's access and modification times.
+    Source,
+    /// Set the modification time to when the photo was taken, adjusted for the time zone if the
+    /// camera recorded it, or else the RAW's modification time.
+    Capture,
+}
+
+/// How to run incrementally, with `--incremental`.
+#[derive(Clone, Copy)]
+struct Incremental {
+    fingerprint: bool,
+    delete_orphans: bool,
+}
+
+enum Processed {
+    Extracted(FileReport),
+    /// Unchanged since the last incremental run.
+    Unchanged,
 }
 
 struct ProcessingResult {
-    result: Result<()>,
+    result: Result<Processed>,
     path: PathBuf,
+    relative_path: PathBuf,
+    /// What to remember about the file for the next incremental run.
+    record: Option<Record>,
 }
 
-/// Recursively process a directory of RAW files, extracting embedded JPEGs and writing them to the
+/// Process one file, unless it's unchanged since `previous`.
+async fn process_incrementally(
+    in_path: &Path,
+    out_dir: &Path,
+    relative_path: &Path,
+    options: &ExtractOptions,
+    turn: Turn,
+    incremental: Option<Incremental>,
+    previous: Option<Record>,
+) -> Result<(Processed, Option<Record>)> {
+    let Some(incremental) = incremental else {
+        let report = process_file_in_turn(in_path, out_dir, relative_path, options, turn).await?;
+        return Ok((Processed::Extracted(report), None));
+    };
+    if let Some(previous) = previous {
+        if let Some(record) = previous
+            .check(in_path, out_dir, options, incremental.fingerprint)
+            .await?
+        {
+            // Its outputs were reserved for it up front, so it has nothing to claim.
+            return Ok((Processed::Unchanged, Some(record)));
+        }
+    }
+
+    let report = process_file_in_turn(in_path, out_dir, relative_path, options, turn).await?;
+    let record = Record::from_outcome(
+        in_path,
+        out_dir,
+        &report.outcome,
+        options,
+        incremental.fingerprint,
+    )
+    .await?;
+    Ok((Processed::Extracted(report), Some(record)))
+}
+
+/// Print `rows` as a table, with the first row as the header.
+fn print_table(rows: &[Vec<String>]) {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<_> = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for row in rows {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+fn human_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+fn dimensions(dimensions: Option<(u32, u32)>) -> String {
+    match dimensions {
+        Some((width, height)) => format!("{}x{}", width, height),
+        None => "-".to_string(),
+    }
+}
+
+/// A row of the `--dry-run` table for one RAW.
+fn dry_run_row(path: &Path, result: &Result<Processed>) -> Vec<String> {
+    let input = path.display().to_string();
+    let file_report = match result {
+        Ok(Processed::Extracted(file_report)) => file_report,
+        Ok(Processed::Unchanged) => return vec![input, "-".into(), "-".into(), "unchanged".into()],
+        Err(e) => return vec![input, "-".into(), "-".into(), format!("error: {:#}", e)],
+    };
+    let preview = match (&file_report.preview, &file_report.outcome) {
+        (Some(preview), _) => format!(
+            "{} {}",
+            preview.kind().extension(),
+            human_size(preview.length())
+        ),
+        (None, WriteOutcome::Skipped(_)) => "-".to_string(),
+        (None, _) => "none, copy as-is".to_string(),
+    };
+    let output = match &file_report.outcome {
+        WriteOutcome::Written(output) => output.display().to_string(),
+        WriteOutcome::Skipped(output) => format!("{} (exists, skip)", output.display()),
+        WriteOutcome::Renamed { written, .. } => format!("{} (renamed)", written.display()),
+    };
+    vec![input, preview, dimensions(file_report.dimensions), output]
+}
+
+/// List every embedded preview in `path`, for the `info` subcommand.
+async fn info(path: &Path) -> Result<()> {
+    let previews = find_all_embedded_jpegs(path).await?;
+    let chosen = find_embedded_jpeg(path, &ExtractOptions::default())
+        .await
+        .ok();
+
+    let mut rows = vec![
+        ["", "KIND", "OFFSET", "LENGTH", "DIMENSIONS", "ORIENTATION"]
+            .map(String::from)
+            .to_vec(),
+    ];
+    for preview in &previews {
+        rows.push(vec![
+            if Some(*preview) == chosen { "*" } else { "" }.to_string(),
+            preview.kind().extension().to_string(),
+            preview.offset().to_string(),
+            human_size(preview.length()),
+            dimensions(preview.dimensions()),
+            preview
+                .orientation()
+                .map_or_else(|| "-".to_string(), |o| o.to_string()),
+        ]);
+    }
+    if previews.is_empty() {
+        println!("No embedded previews found in {}", path.display());
+    } else {
+        print_table(&rows);
+    }
+
+    let metadata = read_metadata(path).await.unwrap_or_default();
+    let exposure = metadata.exposure_time.map(|exposure_time| {
+        match (exposure_time.numerator, exposure_time.denominator) {
+            (1, denominator) => format!("1/{} s", denominator),
+            _ => format!("{} s", exposure_time.to_f64().unwrap_or_default()),
+        }
+    });
+    let tags = [
+        ("Make", metadata.make.clone()),
+        ("Model", metadata.model.clone()),
+        ("Lens", metadata.lens_model.clone()),
+        ("Serial number", metadata.serial_number.clone()),
+        ("Taken", metadata.date_time_original.clone()),
+        ("Time zone", metadata.offset_time_original.clone()),
+        ("Exposure", exposure),
+        ("Aperture", metadata.f_number.map(|f| format!("f/{}", f))),
+        ("ISO", metadata.iso.map(|iso| iso.to_string())),
+        (
+            "Focal length",
+            metadata.focal_length.map(|f| format!("{} mm", f)),
+        ),
+        (
+            "GPS",
+            metadata
+                .gps
+                .map(|gps| format!("{:.6}, {:.6}", gps.latitude, gps.longitude)),
+        ),
+    ];
+    let rows: Vec<_> = tags
+        .into_iter()
+        .filter_map(|(name, value)| Some(vec![format!("{}:", name), value?]))
+        .collect();
+    if !rows.is_empty() {
+        println!();
+        print_table(&rows);
+    }
+    Ok(())
+}
+
+/// Print a RAW's metadata as JSON, for the `metadata` subcommand.
+async fn metadata(path: &Path) -> Result<()> {
+    let metadata = read_metadata(path).await?;
+    println!("{}", serde_json::to_string_pretty(&metadata)?);
+    Ok(())
+}
+
+/// Check an output directory, for the `verify` subcommand. `options` are what the outputs were
+/// extracted with.
+async fn verify(
+    output_dir: &Path,
+    input_dir: Option<&Path>,
+    options: &ExtractOptions,
+) -> Result<()> {
+    let raw_extensions = formats::raw_extensions().map(OsString::from).collect();
+    let verification = verify::verify(output_dir, input_dir, &raw_extensions, options).await?;
+    for problem in &verification.problems {
+        match problem {
+            Problem::Invalid { path, reason } => {
+                println!("Invalid: {}: {}", path.display(), reason)
+            }
+            Problem::Missing { source, expected } => {
+                println!("Missing: {} for {}", expected.display(), source.display())
+            }
+            Problem::Mismatch { source, output } => {
+                println!(
+                    "Mismatch: {} doesn't match {}",
+                    output.display(),
+                    source.display()
+                )
+            }
+            Problem::Extract { source, error } => {
+                println!("Cannot extract: {}: {}", source.display(), error)
+            }
+        }
+    }
+    println!("Checked {} JPEGs", verification.checked);
+    if !verification.problems.is_empty() {
+        bail!("Found {} problems", verification.problems.len());
+    }
+    Ok(())
+}
+
+/// List the RAW formats looked for, for the `formats` subcommand.
+fn formats() {
+    let mut rows = vec![["EXTENSIONS", "FORMAT", "PREVIEWS FROM"]
+        .map(String::from)
+        .to_vec()];
+    for format in formats::RAW_FORMATS {
+        rows.push(vec![
+            format.extensions.join(", "),
+            format.name.to_string(),
+            format.container.description().to_string(),
+        ]);
+    }
+    print_table(&rows);
+}
+
+/// The extensions to look for in directories: every RAW format's, in lower and upper case, and
+/// `extra`.
+fn valid_extensions(extra: Option<OsString>) -> HashSet<OsString> {
+    formats::raw_extensions()
+        .flat_map(|ext| [OsString::from(ext), OsString::from(ext.to_uppercase())])
+        .chain(extra)
+        .collect()
+}
+
+/// Read the paths listed in `path`, for `--files-from`, or from stdin for "-".
+async fn read_file_list(path: &Path) -> Result<Vec<PathBuf>> {
+    let list = if path == Path::new("-") {
+        let mut list = Vec::new();
+        std::io::stdin().read_to_end(&mut list)?;
+        list
+    } else {
+        fs::read(path)
+            .await
+            .with_context(|| format!("Reading {}", path.display()))?
+    };
+    inputs::parse_file_list(&list)
+}
+
+/// List the RAWs found in `paths` and `files_from`, for the `list` subcommand.
+async fn list(
+    mut paths: Vec<PathBuf>,
+    files_from: Option<&Path>,
+    extension: Option<OsString>,
+) -> Result<()> {
+    if let Some(files_from) = files_from {
+        paths.extend(read_file_list(files_from).await?);
+    } else if paths.is_empty() {
+        paths.push(PathBuf::from("."));
+    }
+    let mut rows = vec![["INPUT", "FORMAT", "RELATIVE PATH"]
+        .map(String::from)
+        .to_vec()];
+    for input in inputs::collect(&paths, &valid_extensions(extension)).await? {
+        let format = formats::format_of(&input.path).map_or("-", |format| format.name);
+        rows.push(vec![
+            input.path.display().to_string(),
+            format.to_string(),
+            input.relative_path.display().to_string(),
+        ]);
+    }
+    print_table(&rows);
+    Ok(())
+}
+
+/// Process RAW files and directories of them, extracting embedded JPEGs and writing them to the
 /// output directory.
 ///
-/// This function recursively searches the input directory for RAW files with valid extensions,
+/// This function recursively searches each input directory for RAW files with valid extensions,
 /// processes each file to extract the embedded JPEG, and writes the JPEGs to the corresponding
-/// location in the output directory. The directory structure relative to the input directory is
-/// maintained.
-async fn process_directory(
-    in_dir: &Path,
+/// location in the output directory. The directory structure relative to the deepest directory
+/// holding every input is maintained, unless a name template (including the date based one from
+/// `--by-date`) says otherwise.
+///
+/// With `incremental`, RAWs which haven't changed since the last incremental run are skipped. With
+/// `report`, what happened to each RAW is written there. With [`ExtractOptions::dry_run`], nothing
+/// is written, and a table of what would have been done is printed instead.
+async fn process_paths(
+    paths: &[PathBuf],
     out_dir: &'static Path,
     ext: Option<OsString>,
     transfers: usize,
+    options: ExtractOptions,
+    incremental: Option<Incremental>,
+    report: Option<&Path>,
 ) -> Result<()> {
-    let valid_extensions = [
-        "arw", "cr2", "crw", "dng", "erf", "kdc", "mef", "mrw", "nef", "nrw", "orf", "pef", "raf",
-        "raw", "rw2", "rwl", "sr2", "srf", "srw", "x3f",
-    ]
-    .iter()
-    .flat_map(|&ext| [OsString::from(ext), OsString::from(ext.to_uppercase())])
-    .chain(ext.into_iter())
-    .collect::<HashSet<_>>();
-
-    let mut entries = Vec::new();
-    let mut dir_queue = vec![in_dir.to_path_buf()];
-
-    while let Some(current_dir) = dir_queue.pop() {
-        let mut read_dir = fs::read_dir(&current_dir).await?;
-        let mut found_raw = false;
-
-        while let Some(entry) = read_dir.next_entry().await? {
-            let path = entry.path();
-            if entry.file_type().await?.is_dir() {
-                dir_queue.push(path);
-            } else if path
-                .extension()
-                .is_some_and(|ext| valid_extensions.contains(ext))
-            {
-                found_raw = true;
-                entries.push(path);
-            }
-        }
+    let entries = inputs::collect(paths, &valid_extensions(ext)).await?;
 
-        if found_raw {
-            let relative_dir = current_dir.strip_prefix(in_dir)?;
-            let output_subdir = out_dir.join(relative_dir);
-            fs::create_dir_all(&output_subdir).await?;
+    // With a name template, directories are created as needed instead.
+    if options.name_template.is_none() && !options.dry_run {
+        let output_subdirs: HashSet<_> = entries
+            .iter()
+            .filter_map(|entry| entry.relative_path.parent())
+            .collect();
+        for output_subdir in output_subdirs {
+            fs::create_dir_all(out_dir.join(output_subdir)).await?;
         }
     }
 
@@ -92,37 +623,167 @@ async fn process_directory(
             .progress_chars("##-"),
     );
 
+    let mut report = report.map(ReportWriter::create).transpose()?;
+    let mut state = match incremental {
+        Some(_) => SyncState::load(out_dir).await?,
+        None => SyncState::default(),
+    };
+
+    // Output paths are decided in input order, so that reruns pick the same names whatever order
+    // extraction finishes in. Permits are taken in the same order, so that everything a task might
+    // wait for its turn behind already has one.
+    //
+    // Whatever a RAW was written to last time is kept for it, so that a changed RAW can't take
+    // the name of an unchanged one next to it, which never gets as far as claiming anything.
+    let mut turns = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        turns.push(options.claims.enqueue());
+        if let Some(record) = state
+            .get(&entry.relative_path)
+            .filter(|record| record.is_for(&entry.path))
+        {
+            let outputs = record.outputs.iter().map(|output| out_dir.join(output));
+            options.claims.reserve(&entry.path, outputs);
+        }
+    }
     let semaphore = Arc::new(Semaphore::new(transfers));
     let mut tasks = Vec::with_capacity(entries.len());
 
-    for in_path in entries {
-        let semaphore = semaphore.clone();
-        let relative_path = in_path.strip_prefix(in_dir)?.to_path_buf();
+    for (
+        Input {
+            path: in_path,
+            relative_path,
+        },
+        turn,
+    ) in entries.into_iter().zip(turns)
+    {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let previous = state.get(&relative_path).cloned();
         let progress_bar = progress_bar.clone();
+        let options = options.clone();
         let task: tokio::task::JoinHandle<Result<ProcessingResult>> = tokio::spawn(async move {
-            let permit = semaphore.acquire_owned().await?;
-            let result = process_file(&in_path, out_dir, &relative_path, jpgfromraw::FindJpegType::Largest).await;
+            let result = process_incrementally(
+                &in_path,
+                out_dir,
+                &relative_path,
+                &options,
+                turn,
+                incremental,
+                previous,
+            )
+            .await;
             drop(permit);
             progress_bar.inc(1);
+            let (result, record) = match result {
+                Ok((processed, record)) => (Ok(processed), record),
+                Err(e) => (Err(e), None),
+            };
             Ok(ProcessingResult {
                 result,
                 path: in_path,
+                relative_path,
+                record,
             })
         });
         tasks.push(task);
     }
 
     let mut nr_failed = 0;
+    let mut nr_skipped = 0;
+    let mut nr_renamed = 0;
+    let mut nr_unchanged = 0;
+    let mut inputs = HashSet::new();
+    let mut table = vec![["INPUT", "PREVIEW", "DIMENSIONS", "OUTPUT"]
+        .map(String::from)
+        .to_vec()];
     for task in tasks {
-        let pr_res = task.await??;
-        if let Err(e) = pr_res.result {
-            nr_failed += 1;
-            let msg = format!("Error processing file {}: {:?}", pr_res.path.display(), e);
-            progress_bar.println(msg);
+        let pr_res = match task.await.map_err(anyhow::Error::from).and_then(|res| res) {
+            Ok(pr_res) => pr_res,
+            Err(e) => {
+                // Keep what's been reported so far, rather than a truncated line.
+                if let Some(report) = &mut report {
+                    report.flush()?;
+                }
+                return Err(e);
+            }
+        };
+        match pr_res.record {
+            Some(record) => state.insert(&pr_res.relative_path, record),
+            // Failed, so try again next time.
+            None => state.remove(&pr_res.relative_path),
+        }
+        inputs.insert(pr_res.relative_path);
+        if let Some(report) = &mut report {
+            match &pr_res.result {
+                Ok(Processed::Extracted(file_report)) => {
+                    report.processed(&pr_res.path, file_report)?
+                }
+                Ok(Processed::Unchanged) => report.unchanged(&pr_res.path)?,
+                Err(e) => report.error(&pr_res.path, e)?,
+            }
+        }
+        if options.dry_run {
+            table.push(dry_run_row(&pr_res.path, &pr_res.result));
+        }
+        let outcome = pr_res.result.map(|processed| match processed {
+            Processed::Extracted(file_report) => Some(file_report.outcome),
+            Processed::Unchanged => None,
+        });
+        match outcome {
+            Ok(None) => nr_unchanged += 1,
+            Ok(Some(WriteOutcome::Written(_))) => {}
+            Ok(Some(WriteOutcome::Skipped(_) | WriteOutcome::Renamed { .. }))
+                if options.dry_run => {}
+            Ok(Some(WriteOutcome::Skipped(output))) => {
+                nr_skipped += 1;
+                let msg = format!(
+                    "Skipped {}: {} already exists",
+                    pr_res.path.display(),
+                    output.display()
+                );
+                progress_bar.println(msg);
+            }
+            Ok(Some(WriteOutcome::Renamed { wanted, written })) => {
+                nr_renamed += 1;
+                let msg = format!(
+                    "Renamed {}: {} was taken, wrote {}",
+                    pr_res.path.display(),
+                    wanted.display(),
+                    written.display()
+                );
+                progress_bar.println(msg);
+            }
+            Err(e) => {
+                nr_failed += 1;
+                let msg = format!("Error processing file {}: {:?}", pr_res.path.display(), e);
+                progress_bar.println(msg);
+            }
         }
     }
 
     progress_bar.abandon();
+    if let Some(report) = &mut report {
+        report.flush()?;
+    }
+
+    if options.dry_run {
+        print_table(&table);
+    } else if let Some(incremental) = incremental {
+        if incremental.delete_orphans {
+            for deleted in state.remove_orphans(out_dir, &inputs).await? {
+                println!("Deleted {}", deleted.display());
+            }
+        }
+        state.save(out_dir).await?;
+        println!("{} unchanged since the last run", nr_unchanged);
+    }
+
+    if nr_skipped != 0 || nr_renamed != 0 {
+        println!(
+            "{} skipped as already existing, {} renamed",
+            nr_skipped, nr_renamed
+        );
+    }
 
     if nr_failed != 0 {
         bail!("Failed to process {} files", nr_failed);
@@ -133,13 +794,123 @@ async fn process_directory(
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    jpgfromraw::set_verbose(cli.verbose);
+    match cli.command {
+        None => extract(cli.extract).await,
+        Some(Command::Extract(args)) => extract(args).await,
+        Some(Command::Info { file }) => info(&file).await,
+        Some(Command::List {
+            paths,
+            files_from,
+            extension,
+        }) => list(paths, files_from.as_deref(), extension).await,
+        Some(Command::Metadata { file }) => metadata(&file).await,
+        Some(Command::Verify {
+            output_dir,
+            input_dir,
+            rotate,
+            max_size,
+            quality,
+            format,
+            jxl,
+            name_template,
+            by_date,
+        }) => {
+            let quality = quality_for(quality, max_size, format)?;
+            let options = ExtractOptions {
+                apply_orientation: rotate,
+                resize: max_size.map(|max_size| ResizeOptions { max_size, quality }),
+                format: format.output_format(quality),
+                allow_jxl: jxl,
+                name_template: name_template.or_else(|| by_date.then(NameTemplate::by_date)),
+                ..Default::default()
+            };
+            verify(&output_dir, input_dir.as_deref(), &options).await
+        }
+        Some(Command::Formats) => {
+            formats();
+            Ok(())
+        }
+    }
+}
+
+/// Extract previews, for the `extract` subcommand or when there isn't one.
+async fn extract(args: ExtractArgs) -> Result<()> {
+    let mut paths = args.paths;
+    let output_dir = match args.output_dir {
+        Some(output_dir) => output_dir,
+        // Like cp, the last path is where to write to, as long as there's an input before it.
+        None if paths.len() + usize::from(args.files_from.is_some()) >= 2 => {
+            let output_dir = paths.pop().expect("checked above");
+            if output_dir.exists() && !output_dir.is_dir() {
+                bail!(
+                    "Output directory {} is a file, use --output-dir if it's meant as an input",
+                    output_dir.display()
+                );
+            }
+            output_dir
+        }
+        None => PathBuf::from("."),
+    };
+    if args.delete_orphans && (paths.len() != 1 || args.files_from.is_some() || !paths[0].is_dir())
+    {
+        bail!("--delete-orphans needs a single input directory");
+    }
+    if let Some(files_from) = &args.files_from {
+        paths.extend(read_file_list(files_from).await?);
+    }
 
     // We would need a copy for each task otherwise, so better just to make it &'static
-    let output_dir = Box::leak(Box::new(args.output_dir));
+    let output_dir = Box::leak(Box::new(output_dir));
+
+    let quality = quality_for(args.quality, args.max_size, args.format)?;
+    let options = ExtractOptions {
+        apply_orientation: args.rotate,
+        resize: args
+            .max_size
+            .map(|max_size| ResizeOptions { max_size, quality }),
+        format: args.format.output_format(quality),
+        allow_jxl: args.jxl,
+        extract_mpf_images: args.mpf_images,
+        name_template: args
+            .name_template
+            .or_else(|| args.by_date.then(NameTemplate::by_date)),
+        overwrite: match args.overwrite {
+            Overwrite::Always => OverwritePolicy::Always,
+            Overwrite::Never => OverwritePolicy::Never,
+            Overwrite::IfNewer => OverwritePolicy::IfNewer,
+            Overwrite::Rename => OverwritePolicy::Rename,
+        },
+        fsync: args.fsync,
+        timestamps: match args.timestamps {
+            Times::Written => Timestamps::Written,
+            Times::Source => Timestamps::Source,
+            Times::Capture => Timestamps::Capture,
+        },
+        dry_run: args.dry_run,
+        ..Default::default()
+    };
 
-    fs::create_dir_all(&output_dir).await?;
-    process_directory(&args.input_dir, output_dir, args.extension, args.transfers).await?;
+    if !args.dry_run {
+        fs::create_dir_all(&output_dir).await?;
+        for removed in output::remove_abandoned_files(output_dir).await? {
+            println!("Removed {}, left by an interrupted run", removed.display());
+        }
+    }
+    process_paths(
+        &paths,
+        output_dir,
+        args.extension,
+        args.transfers,
+        options,
+        args.incremental.then_some(Incremental {
+            fingerprint: args.fingerprint,
+            delete_orphans: args.delete_orphans,
+        }),
+        args.report.as_deref(),
+    )
+    .await?;
 
     Ok(())
 }
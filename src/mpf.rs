@@ -0,0 +1,146 @@
+//! Multi-Picture Format (CIPA DC-007) segments in extracted JPEGs.
+//!
+//! MPF lives in an APP2 segment of the first image, and lists every image in the file with its
+//! size and offset. Offsets are relative to the MP header (the TIFF header inside the segment),
+//! but sizes are absolute, so the first image's entry goes stale as soon as we add or remove
+//! anything before its EOI. The secondary images usually sit right after the first one in the RAW,
+//! outside of the range the RAW's own tags say the preview occupies.
+
+use std::ops::Range;
+
+use crate::tiff::{ByteOrder, IfdReader};
+
+const MPF_IDENTIFIER: &[u8; 4] = b"MPF\0";
+const MP_ENTRY_TAG: u16 = 0xb002;
+const MP_ENTRY_SIZE: usize = 16;
+
+/// One image listed in the MP Index IFD.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MpEntry {
+    /// The image attribute flags and type, like 0x030000 for the baseline primary image.
+    pub attribute: u32,
+    pub size: u32,
+    /// The offset of the image from the MP header, or 0 for the first image.
+    pub offset: u32,
+}
+
+/// A parsed MPF segment. All positions are relative to the start of the JPEG it was parsed from.
+#[derive(Clone, Debug)]
+pub struct Mpf {
+    /// The whole APP2 segment, including its marker.
+    segment: Range<usize>,
+    /// Where the MP header (a TIFF header) starts.
+    header: usize,
+    /// Where the MP entries start.
+    entries_at: usize,
+    order: ByteOrder,
+    entries: Vec<MpEntry>,
+}
+
+impl Mpf {
+    pub fn entries(&self) -> &[MpEntry] {
+        &self.entries
+    }
+
+    /// Where the image for `entry` starts, relative to the start of the JPEG.
+    pub fn image_offset(&self, entry: &MpEntry) -> usize {
+        self.header + entry.offset as usize
+    }
+}
+
+/// Find the segments before the scan data, as (marker, segment range including the marker).
+fn segments(jpeg: &[u8]) -> impl Iterator<Item = (u8, Range<usize>)> + '_ {
+    let mut pos = 2;
+    std::iter::from_fn(move || {
+        let header = jpeg.get(pos..pos + 4)?;
+        // Stop at SOS, since everything after it is entropy coded data.
+        if header[0] != 0xff || header[1] == 0xda {
+            return None;
+        }
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let segment = pos..pos + 2 + length;
+        pos = segment.end;
+        Some((header[1], segment))
+    })
+}
+
+/// Parse the MPF segment of a JPEG, if it has a well formed one.
+pub fn parse(jpeg: &[u8]) -> Option<Mpf> {
+    const APP2: u8 = 0xe2;
+
+    let (_, segment) = segments(jpeg).find(|(marker, segment)| {
+        *marker == APP2 && jpeg.get(segment.start + 4..segment.start + 8) == Some(MPF_IDENTIFIER)
+    })?;
+    let header = segment.start + 8;
+    let tiff = IfdReader::new(jpeg.get(header..segment.end)?).ok()?;
+    let index = tiff.ifd(tiff.first_ifd_offset().ok()?).ok()?;
+    let entry = index.entry(MP_ENTRY_TAG)?;
+    let data = entry.data().ok()?;
+    let order = tiff.byte_order();
+    let entries = data
+        .chunks_exact(MP_ENTRY_SIZE)
+        .map(|e| MpEntry {
+            attribute: order.read_u32(&e[0..4]),
+            size: order.read_u32(&e[4..8]),
+            offset: order.read_u32(&e[8..12]),
+        })
+        .collect();
+    Some(Mpf {
+        segment,
+        header,
+        entries_at: header + entry.value_offset(),
+        order,
+        entries,
+    })
+}
+
+/// Get the secondary images listed in the MPF segment of `jpeg`, which starts at `jpeg_offset` in
+/// `file`. Anything which runs past the end of `file` or doesn't start with SOI is skipped.
+pub fn secondary_images<'a>(jpeg: &[u8], file: &'a [u8], jpeg_offset: usize) -> Vec<&'a [u8]> {
+    let Some(mpf) = parse(jpeg) else {
+        return Vec::new();
+    };
+    mpf.entries
+        .iter()
+        .skip(1)
+        .filter_map(|entry| {
+            let start = jpeg_offset.checked_add(mpf.image_offset(entry))?;
+            let image = file.get(start..start.checked_add(entry.size as usize)?)?;
+            image.starts_with(&[0xff, 0xd8]).then_some(image)
+        })
+        .collect()
+}
+
+/// Make the MPF segment in `jpeg` consistent after `size_delta` bytes were added to (or removed
+/// from) the first image, before the MPF segment.
+///
+/// If the secondary images are all present after the first image, the first image's size is
+/// corrected. Since offsets are relative to the MP header, which moved along with everything else,
+/// they stay valid. Otherwise the secondary images weren't extracted along with the first one, so
+/// the MPF segment would only point at garbage, and it's removed.
+pub fn fix_up(jpeg: &mut Vec<u8>, size_delta: isize) {
+    let Some(mpf) = parse(jpeg) else {
+        return;
+    };
+    let secondaries_present = mpf.entries.len() > 1
+        && mpf.entries.iter().skip(1).all(|entry| {
+            let start = mpf.image_offset(entry);
+            start
+                .checked_add(entry.size as usize)
+                .and_then(|end| jpeg.get(start..end))
+                .is_some_and(|image| image.starts_with(&[0xff, 0xd8]))
+        });
+
+    if !secondaries_present {
+        jpeg.drain(mpf.segment);
+        return;
+    }
+
+    let first_size = mpf.entries_at + 4..mpf.entries_at + 8;
+    let size = mpf.entries[0].size as isize + size_delta;
+    let bytes = match mpf.order {
+        ByteOrder::LittleEndian => (size as u32).to_le_bytes(),
+        ByteOrder::BigEndian => (size as u32).to_be_bytes(),
+    };
+    jpeg[first_size].copy_from_slice(&bytes);
+}
@@ -0,0 +1,528 @@
+//! Deciding where a preview is written when something is already there.
+//!
+//! There are two ways an output path can be taken: by a file from an earlier run, which is what
+//! [`OverwritePolicy`] is about, or by another RAW in the same run, like `IMG_0001.CR2` and
+//! `IMG_0001.DNG` side by side. The latter is never right to clobber, so it always means picking
+//! another name. Paths are claimed in an [`OutputClaims`] as they're decided on, which is shared
+//! between every task in a run. RAWs queued with [`OutputClaims::enqueue`] decide in the order they
+//! were queued, however their extraction finishes, so the same inputs always get the same names.
+//! Each holds a [`Turn`] for that, which lets the next one go when it's dropped.
+//! Paths can also be set aside for a RAW up front with [`OutputClaims::reserve`], like the ones
+//! an incremental run knows it wrote last time, so that nothing else in the run takes them.
+//!
+//! Whatever is decided, files are only ever written with [`write_atomic`], so that a run which is
+//! killed part way through doesn't leave truncated previews behind looking like finished ones.
+//! What it does leave, its temporary files and the lock files from [`reserve`], are named after its
+//! pid, so a later run can tell that they're abandoned and clear them up, with
+//! [`remove_abandoned_files`] and when it comes across a lock.
+
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+
+/// What to do when the output file already exists.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OverwritePolicy {
+    /// Replace it.
+    #[default]
+    Always,
+    /// Leave it alone, and skip the RAW.
+    Never,
+    /// Replace it only if the RAW was modified after it.
+    IfNewer,
+    /// Write to a new name instead, with a `-N` suffix.
+    Rename,
+}
+
+/// The output paths decided on so far in a run. Clones share the same set.
+#[derive(Clone, Debug, Default)]
+pub struct OutputClaims(Arc<Claims>);
+
+#[derive(Debug, Default)]
+struct Claims {
+    /// Each path decided on, with the RAW it's reserved for if only that RAW may take it.
+    paths: Mutex<HashMap<PathBuf, Claim>>,
+    queue: Mutex<Queue>,
+    turn_taken: Notify,
+}
+
+#[derive(Debug)]
+enum Claim {
+    /// Set aside for this RAW, which hasn't taken it yet.
+    Reserved(PathBuf),
+    Taken,
+}
+
+/// The RAWs waiting for their turn to claim paths.
+#[derive(Debug, Default)]
+struct Queue {
+    queued: usize,
+    /// Positions which are done, past `next`.
+    done: BTreeSet<usize>,
+    /// The first position which isn't done.
+    next: usize,
+}
+
+impl OutputClaims {
+    /// Claim `path` for `input`, returning whether it was free, or reserved for `input`.
+    fn claim(&self, path: &Path, input: &Path) -> bool {
+        let mut paths = self.0.paths.lock().unwrap();
+        match paths.get(path) {
+            None => {}
+            Some(Claim::Reserved(owner)) if owner == input => {}
+            Some(_) => return false,
+        }
+        paths.insert(path.to_path_buf(), Claim::Taken);
+        true
+    }
+
+    /// Whether `path` is claimed, or reserved for anything other than `input`.
+    fn is_claimed(&self, path: &Path, input: &Path) -> bool {
+        match self.0.paths.lock().unwrap().get(path) {
+            None => false,
+            Some(Claim::Reserved(owner)) => owner != input,
+            Some(Claim::Taken) => true,
+        }
+    }
+
+    /// Set `outputs` aside for `input`, so that no other RAW in this run claims them. This must be
+    /// done before any RAW which could want them decides its paths. Paths already claimed or
+    /// reserved are left as they are.
+    pub fn reserve(&self, input: &Path, outputs: impl IntoIterator<Item = PathBuf>) {
+        let mut paths = self.0.paths.lock().unwrap();
+        for output in outputs {
+            paths
+                .entry(output)
+                .or_insert_with(|| Claim::Reserved(input.to_path_buf()));
+        }
+    }
+
+    /// Queue a RAW to claim its paths after everything queued before it has, by passing the turn
+    /// to [`process_file_in_turn`](crate::parser::process_file_in_turn). RAWs which aren't queued
+    /// claim paths whenever they get to it.
+    pub fn enqueue(&self) -> Turn {
+        let mut queue = self.0.queue.lock().unwrap();
+        let position = queue.queued;
+        queue.queued += 1;
+        Turn {
+            claims: self.clone(),
+            position,
+        }
+    }
+
+    /// Mark `position` as done with claiming paths, letting the next in the queue go.
+    fn done(&self, position: usize) {
+        let mut queue = self.0.queue.lock().unwrap();
+        queue.done.insert(position);
+        loop {
+            let next = queue.next;
+            if !queue.done.remove(&next) {
+                break;
+            }
+            queue.next += 1;
+        }
+        drop(queue);
+        self.0.turn_taken.notify_waiters();
+    }
+}
+
+/// A RAW's place in the queue of an [`OutputClaims`]. Dropping it marks the RAW as done with
+/// claiming paths, whether it claimed any or not, and lets the next in the queue go.
+#[must_use = "dropping a turn gives it up straight away"]
+#[derive(Debug)]
+pub struct Turn {
+    claims: OutputClaims,
+    position: usize,
+}
+
+impl Turn {
+    /// Wait until everything queued before this is done.
+    pub(crate) async fn wait(&self) {
+        let claims = &self.claims.0;
+        loop {
+            // Created before checking, so that a turn finishing in between isn't missed.
+            let turn_taken = claims.turn_taken.notified();
+            if self.position <= claims.queue.lock().unwrap().next {
+                return;
+            }
+            turn_taken.await;
+        }
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.claims.done(self.position);
+    }
+}
+
+/// What happened to one RAW's preview.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WriteOutcome {
+    Written(PathBuf),
+    /// Not written, because the policy said to keep the existing file at this path.
+    Skipped(PathBuf),
+    /// Written somewhere other than `wanted`, because it was already taken.
+    Renamed {
+        wanted: PathBuf,
+        written: PathBuf,
+    },
+}
+
+impl WriteOutcome {
+    /// Where the preview was written, if it was.
+    pub fn written(&self) -> Option<&Path> {
+        match self {
+            Self::Written(path) | Self::Renamed { written: path, .. } => Some(path),
+            Self::Skipped(_) => None,
+        }
+    }
+}
+
+async fn exists(path: &Path) -> Result<Option<std::fs::Metadata>> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether `policy` says to keep an existing file at `output` rather than write `input`'s preview
+/// there.
+pub(crate) async fn keeps_existing(
+    policy: OverwritePolicy,
+    output: &Path,
+    input: &Path,
+) -> Result<bool> {
+    let Some(existing) = exists(output).await? else {
+        return Ok(false);
+    };
+    Ok(match policy {
+        OverwritePolicy::Always | OverwritePolicy::Rename => false,
+        OverwritePolicy::Never => true,
+        OverwritePolicy::IfNewer => {
+            let input_mtime = tokio::fs::metadata(input).await?.modified()?;
+            input_mtime <= existing.modified()?
+        }
+    })
+}
+
+/// `path` with `-n` added to its file stem, or `path` itself for 0. These are the names
+/// [`resolve`] tries, in order.
+pub(crate) fn with_suffix(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+/// Decide what to do about writing `input`'s preview to `wanted`, and claim the path.
+///
+/// Another RAW in this run having claimed `wanted` means taking the first `-N` name which nobody
+/// else in the run has. `policy` then applies to whichever name that is, so a rerun finds the same
+/// names, and overwrites or keeps them, rather than adding yet another suffix. Only
+/// [`OverwritePolicy::Rename`] carries on past names which exist from earlier runs.
+pub(crate) async fn resolve(
+    wanted: PathBuf,
+    input: &Path,
+    policy: OverwritePolicy,
+    claims: &OutputClaims,
+) -> Result<WriteOutcome> {
+    let mut candidate = wanted.clone();
+    for n in 1.. {
+        if claims.claim(&candidate, input)
+            && (policy != OverwritePolicy::Rename || exists(&candidate).await?.is_none())
+        {
+            break;
+        }
+        candidate = with_suffix(&wanted, n);
+    }
+
+    Ok(if keeps_existing(policy, &candidate, input).await? {
+        WriteOutcome::Skipped(candidate)
+    } else if candidate == wanted {
+        WriteOutcome::Written(wanted)
+    } else {
+        WriteOutcome::Renamed {
+            wanted,
+            written: candidate,
+        }
+    })
+}
+
+/// Whether `a` and `b` are the same existing file, however they're written.
+pub(crate) async fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (
+        tokio::fs::canonicalize(a).await,
+        tokio::fs::canonicalize(b).await,
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Whether `output` can be skipped without extracting anything, because it's already there and
+/// nothing queued before `input`'s `turn` in this run has claimed it.
+pub(crate) async fn can_skip_early(
+    output: &Path,
+    input: &Path,
+    policy: OverwritePolicy,
+    claims: &OutputClaims,
+    turn: Option<&Turn>,
+) -> Result<bool> {
+    if claims.is_claimed(output, input) || !keeps_existing(policy, output, input).await? {
+        return Ok(false);
+    }
+    if let Some(turn) = turn {
+        turn.wait().await;
+    }
+    Ok(claims.claim(output, input))
+}
+
+/// What [`reserve`] found at a path.
+pub(crate) enum Reservation {
+    /// Nobody has it, so it's been taken. Outside of a dry run, it's held against other processes
+    /// by the lock until that's dropped.
+    Free(Option<Lock>),
+    /// It exists with exactly the contents to be written, most likely from an earlier run of the
+    /// same RAW. It's been claimed for this run.
+    Same,
+    Taken,
+}
+
+/// A lock file next to an output path, which is removed when dropped.
+#[derive(Debug)]
+pub(crate) struct Lock(PathBuf);
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Try to take `path` for a name template's `{seq}`, to write `input`'s `data` to. Within a run it's claimed
+/// in `claims`, and against other processes by creating a lock file next to it, so that nothing
+/// is ever created at `path` itself until it's written. With `dry_run`, nothing is created, and
+/// existing files are never the same, since there's no data to compare.
+pub(crate) async fn reserve(
+    path: &Path,
+    input: &Path,
+    data: &[u8],
+    claims: &OutputClaims,
+    dry_run: bool,
+) -> Result<Reservation> {
+    if !claims.claim(path, input) {
+        return Ok(Reservation::Taken);
+    }
+    if let Some(existing) = exists(path).await? {
+        let same =
+            !dry_run && existing.len() == data.len() as u64 && tokio::fs::read(path).await? == data;
+        return Ok(if same {
+            Reservation::Same
+        } else {
+            Reservation::Taken
+        });
+    }
+    if dry_run {
+        return Ok(Reservation::Free(None));
+    }
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let lock_path = path.with_file_name(format!(".{}.lock", name));
+    let mut broken = false;
+    let lock = loop {
+        let locked = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .await;
+        match locked {
+            Ok(mut file) => {
+                let lock = Lock(lock_path);
+                file.write_all(std::process::id().to_string().as_bytes())
+                    .await?;
+                break lock;
+            }
+            // Only try to break it once, in case another process is doing the same.
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && !broken => {
+                let abandoned = match read_lock(&lock_path).await? {
+                    Some((pid, modified)) => is_abandoned(pid, modified),
+                    None => true,
+                };
+                if !abandoned {
+                    return Ok(Reservation::Taken);
+                }
+                verbose!("Removing abandoned lock {}", lock_path.display());
+                match tokio::fs::remove_file(&lock_path).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+                broken = true;
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(Reservation::Taken),
+            Err(e) => return Err(e.into()),
+        }
+    };
+    // Another process may have written it between checking and locking.
+    if exists(path).await?.is_some() {
+        return Ok(Reservation::Taken);
+    }
+    Ok(Reservation::Free(Some(lock)))
+}
+
+/// How old a lock or temporary file has to be to count as abandoned, whether or not the process
+/// which made it can be told to be gone. Either is only around for as long as it takes to write
+/// one preview.
+const ABANDONED_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Whether a file which the process `pid` made, and which was last modified at `modified`, was
+/// abandoned by it.
+fn is_abandoned(pid: Option<u32>, modified: SystemTime) -> bool {
+    if modified.elapsed().is_ok_and(|age| age > ABANDONED_AFTER) {
+        return true;
+    }
+    match pid {
+        Some(pid) if pid != std::process::id() => !process_exists(pid),
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_exists(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Without a cheap way to tell, processes are assumed to be running, so only age counts.
+#[cfg(not(target_os = "linux"))]
+fn process_exists(_pid: u32) -> bool {
+    true
+}
+
+/// The pid in the lock file at `lock_path`, if it has one yet, and when it was last modified.
+/// `None` if it's gone.
+async fn read_lock(lock_path: &Path) -> Result<Option<(Option<u32>, SystemTime)>> {
+    let read = async {
+        let contents = tokio::fs::read(lock_path).await?;
+        let modified = tokio::fs::metadata(lock_path).await?.modified()?;
+        Ok::<_, std::io::Error>((contents, modified))
+    };
+    let (contents, modified) = match read.await {
+        Ok(read) => read,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let pid = std::str::from_utf8(&contents)
+        .ok()
+        .and_then(|pid| pid.parse().ok());
+    Ok(Some((pid, modified)))
+}
+
+/// The pid in the name of a temporary file from [`write_atomic`], if `name` is one.
+fn temp_file_pid(name: &str) -> Option<u32> {
+    let rest = name.strip_prefix('.')?.strip_suffix(".tmp")?;
+    let mut parts = rest.rsplitn(3, '.');
+    let _counter: u64 = parts.next()?.parse().ok()?;
+    let pid = parts.next()?.parse().ok()?;
+    parts.next()?;
+    Some(pid)
+}
+
+/// Delete the temporary files and locks under `out_dir` which runs that were killed part way
+/// through left behind, returning them. Anything another run which is still going is using is
+/// left alone.
+pub async fn remove_abandoned_files(out_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    let mut dir_queue = vec![out_dir.to_path_buf()];
+    while let Some(current_dir) = dir_queue.pop() {
+        let mut read_dir = match tokio::fs::read_dir(&current_dir).await {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dir_queue.push(path);
+                continue;
+            }
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            // Only locks with a pid in them are known to be ours, rather than anything else which
+            // happens to be called that.
+            let abandoned = if name.starts_with('.') && name.ends_with(".lock") {
+                match read_lock(&path).await? {
+                    Some((Some(pid), modified)) => is_abandoned(Some(pid), modified),
+                    _ => false,
+                }
+            } else if let Some(pid) = temp_file_pid(&name) {
+                is_abandoned(Some(pid), entry.metadata().await?.modified()?)
+            } else {
+                false
+            };
+            if !abandoned {
+                continue;
+            }
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => removed.push(path),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Write `data` to `path` through a temporary file in the same directory which is renamed into
+/// place, so that `path` either doesn't exist or is complete. With `sync`, the data and the rename
+/// are flushed to disk before returning, too.
+pub(crate) async fn write_atomic(path: &Path, data: &[u8], sync: bool) -> Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = async {
+        let mut file = tokio::fs::File::create(&temp).await?;
+        file.write_all(data).await?;
+        file.flush().await?;
+        if sync {
+            file.sync_all().await?;
+        }
+        drop(file);
+        tokio::fs::rename(&temp, path).await
+    }
+    .await;
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e.into());
+    }
+
+    // The rename itself is only durable once the directory is.
+    #[cfg(unix)]
+    if sync {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            tokio::fs::File::open(parent).await?.sync_all().await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! Canon CR3, which is ISO BMFF rather than TIFF based.
+//!
+//! A CR3 has up to three previews:
+//!
+//! - THMB: a small JPEG thumbnail, in Canon's uuid box inside moov.
+//! - PRVW: a medium sized JPEG, in its own top level uuid box.
+//! - The first track: a full size image in mdat. This is a JPEG normally, but HEVC when the
+//!   camera is in HDR PQ mode, in which case the track's sample entry carries an `hvcC` box.
+//!
+//! The metadata is in the same uuid box as THMB, as a series of TIFF structures (CMT1 to CMT4).
+
+use anyhow::{bail, Result};
+use byteorder::{BigEndian, ByteOrder as _};
+
+use super::{EmbeddedJpegInfo, PreviewKind};
+use crate::bmff::{self, BmffBox};
+use crate::tiff::IfdReader;
+
+const CANON_UUID: [u8; 16] = [
+    0x85, 0xc0, 0xb6, 0x87, 0x82, 0x0f, 0x11, 0xe0, 0x81, 0x11, 0xf4, 0xce, 0x46, 0x2b, 0x6a, 0x48,
+];
+const PREVIEW_UUID: [u8; 16] = [
+    0xea, 0xf4, 0x2b, 0x5e, 0x1c, 0x98, 0x4b, 0x88, 0xb9, 0xfb, 0xb7, 0xdc, 0x40, 0x6e, 0x4d, 0x16,
+];
+
+/// The size of a VisualSampleEntry's fields, before its child boxes.
+const VISUAL_SAMPLE_ENTRY_SIZE: usize = 78;
+
+fn is_jpeg(data: &[u8]) -> bool {
+    data.starts_with(&[0xff, 0xd8])
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(BigEndian::read_u16(data.get(offset..offset + 2)?).into())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<usize> {
+    BigEndian::read_u32(data.get(offset..offset + 4)?)
+        .try_into()
+        .ok()
+}
+
+/// Get the orientation from IFD0 in CMT1.
+fn cmt1_orientation(canon: &BmffBox) -> Option<u16> {
+    const ORIENTATION_TAG: u16 = 0x112;
+
+    let cmt1 = canon.children(0).find(b"CMT1")?;
+    let tiff = IfdReader::new(cmt1.data()).ok()?;
+    let ifd0 = tiff.ifd(tiff.first_ifd_offset().ok()?).ok()?;
+    ifd0.entry(ORIENTATION_TAG)?.u32()?.try_into().ok()
+}
+
+/// The TIFF structures holding a CR3's metadata, each with the IFD of interest first.
+pub(super) struct MetadataTiffs<'a> {
+    /// IFD0, from CMT1.
+    pub ifd0: &'a [u8],
+    /// The Exif IFD, from CMT2.
+    pub exif: Option<&'a [u8]>,
+    /// The GPS IFD, from CMT4.
+    pub gps: Option<&'a [u8]>,
+}
+
+/// Find the TIFF structures with a CR3's metadata in.
+pub(super) fn metadata_tiffs(raw_buf: &[u8]) -> Option<MetadataTiffs<'_>> {
+    let canon = bmff::boxes(raw_buf)
+        .find(b"moov")?
+        .children(0)
+        .find_uuid(&CANON_UUID)?;
+    let cmt = |box_type| canon.children(0).find(box_type).map(|b| b.data());
+    Some(MetadataTiffs {
+        ifd0: cmt(b"CMT1")?,
+        exif: cmt(b"CMT2"),
+        gps: cmt(b"CMT4"),
+    })
+}
+
+/// Get a JPEG from a THMB or PRVW box, which both have a width, height and length before it, at
+/// slightly different offsets.
+fn embedded_jpeg(
+    b: &BmffBox,
+    dimensions_at: usize,
+    length_at: usize,
+    start: usize,
+) -> Option<EmbeddedJpegInfo> {
+    let data = b.data();
+    let length = u32_at(data, length_at)?;
+    if !is_jpeg(data.get(start..start.checked_add(length)?)?) {
+        return None;
+    }
+    Some(EmbeddedJpegInfo {
+        offset: b.data_offset() + start,
+        length,
+        dimensions: Some((
+            u16_at(data, dimensions_at)?,
+            u16_at(data, dimensions_at + 2)?,
+        )),
+        ..Default::default()
+    })
+}
+
+/// Get the full size image from a track, if it has one.
+fn track_image(raw_buf: &[u8], trak: &BmffBox) -> Option<EmbeddedJpegInfo> {
+    let stbl = trak
+        .children(0)
+        .find(b"mdia")?
+        .children(0)
+        .find(b"minf")?
+        .children(0)
+        .find(b"stbl")?;
+
+    // Full box header, then the entry count.
+    let entry = stbl.children(0).find(b"stsd")?.children(8).next()?.ok()?;
+    if entry.box_type() != b"CRAW" {
+        return None;
+    }
+    let dimensions = (u16_at(entry.data(), 24)?, u16_at(entry.data(), 26)?);
+    let mut kind = None;
+    let mut codec_config = None;
+    for child in entry
+        .children(VISUAL_SAMPLE_ENTRY_SIZE)
+        .map_while(Result::ok)
+    {
+        match child.box_type() {
+            b"JPEG" => kind = Some(PreviewKind::Jpeg),
+            b"hvcC" => {
+                kind = Some(PreviewKind::Heif);
+                codec_config = Some((child.data_offset(), child.data().len()));
+            }
+            _ => {}
+        }
+    }
+    let kind = kind?;
+
+    let stsz = stbl.children(0).find(b"stsz")?.data();
+    let length = match u32_at(stsz, 4)? {
+        0 => u32_at(stsz, 12)?,
+        length => length,
+    };
+    let offset = if let Some(co64) = stbl.children(0).find(b"co64") {
+        BigEndian::read_u64(co64.data().get(8..16)?)
+            .try_into()
+            .ok()?
+    } else {
+        u32_at(stbl.children(0).find(b"stco")?.data(), 8)?
+    };
+
+    let data = raw_buf.get(offset..offset.checked_add(length)?)?;
+    if kind == PreviewKind::Jpeg && !is_jpeg(data) {
+        return None;
+    }
+    Some(EmbeddedJpegInfo {
+        offset,
+        length,
+        dimensions: Some(dimensions),
+        kind,
+        codec_config,
+        ..Default::default()
+    })
+}
+
+/// Find all the previews in a CR3.
+pub(super) fn find_previews(raw_buf: &[u8]) -> Result<Vec<EmbeddedJpegInfo>> {
+    let mut candidates = Vec::new();
+    let mut orientation = None;
+
+    if let Some(moov) = bmff::boxes(raw_buf).find(b"moov") {
+        if let Some(canon) = moov.children(0).find_uuid(&CANON_UUID) {
+            orientation = cmt1_orientation(&canon);
+            if let Some(thmb) = canon.children(0).find(b"THMB") {
+                candidates.extend(embedded_jpeg(&thmb, 4, 8, 16));
+            }
+        }
+        let traks = moov.children(0).map_while(Result::ok);
+        for trak in traks.filter(|b| b.box_type() == b"trak") {
+            candidates.extend(track_image(raw_buf, &trak));
+        }
+    }
+
+    if let Some(preview) = bmff::boxes(raw_buf).find_uuid(&PREVIEW_UUID) {
+        // The PRVW box comes after 8 bytes of something else.
+        if let Some(prvw) = preview.children(8).find(b"PRVW") {
+            candidates.extend(embedded_jpeg(&prvw, 6, 12, 16));
+        }
+    }
+
+    if candidates.is_empty() {
+        bail!("No previews found in ISO BMFF file");
+    }
+    for candidate in &mut candidates {
+        candidate.orientation = orientation;
+    }
+    Ok(candidates)
+}
+
+/// Wrap a HEVC preview found by [`find_previews`] up as a standalone HEIF file.
+pub(super) fn wrap_heif(raw_buf: &[u8], info: &EmbeddedJpegInfo, data: &[u8]) -> Result<Vec<u8>> {
+    let Some((offset, length)) = info.codec_config else {
+        bail!("HEVC preview has no decoder configuration");
+    };
+    let Some(hvcc) = raw_buf.get(offset..offset + length) else {
+        bail!("HEVC decoder configuration exceeds file size");
+    };
+    let Some((width, height)) = info.dimensions else {
+        bail!("HEVC preview has no dimensions");
+    };
+    // HEIF rotation is in anticlockwise quarter turns. Mirroring would need an imir property as
+    // well, but cameras don't produce mirrored orientations.
+    let rotation = match info.orientation {
+        Some(3) => 2,
+        Some(6) => 3,
+        Some(8) => 1,
+        _ => 0,
+    };
+    bmff::wrap_hevc_image(hvcc, width, height, rotation, data)
+}
@@ -0,0 +1,259 @@
+//! MakerNote parsing, for the vendor specific data in the EXIF IFD.
+//!
+//! Every vendor does their own thing here. Some MakerNotes are a bare IFD, some have a header
+//! first, some embed an entire TIFF structure of their own, and offsets inside them can be
+//! relative to the main TIFF header, the MakerNote itself, or the embedded TIFF header. All of
+//! that is resolved in [`MakerNote::locate`], so the rest of the code can just read tags.
+
+use memchr::memmem;
+
+use crate::tiff::{ByteOrder, Ifd, IfdReader, Value};
+
+const MAKER_NOTE_TAG: u16 = 0x927c;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Vendor {
+    Canon,
+    Nikon,
+    Olympus,
+    Pentax,
+    Samsung,
+    Sony,
+}
+
+/// A located MakerNote.
+pub(super) struct MakerNote<'a> {
+    pub vendor: Vendor,
+    /// A reader whose offsets are based wherever this vendor's offsets are based.
+    pub reader: IfdReader<'a>,
+    /// Where the start of `reader`'s buffer is, relative to the main TIFF header.
+    pub base: usize,
+    /// The MakerNote's own IFD.
+    pub ifd: Ifd<'a>,
+}
+
+/// An embedded preview found in a MakerNote, relative to the main TIFF header.
+pub(super) struct MakerNotePreview {
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl<'a> MakerNote<'a> {
+    /// Find and identify the MakerNote in the EXIF IFD. `relative` forces offsets to be based at
+    /// the start of the MakerNote, for bodies which do so unlike the rest of their vendor's range.
+    pub fn locate(
+        tiff: &IfdReader<'a>,
+        exif: &Ifd<'a>,
+        make: &str,
+        relative: bool,
+    ) -> Option<Self> {
+        let offset = exif.entry(MAKER_NOTE_TAG)?.value_offset();
+        let data = tiff.buf().get(offset..)?;
+        let order = tiff.byte_order();
+
+        // (vendor, base relative to TIFF header, IFD offset relative to base, byte order)
+        let (vendor, base, ifd_offset, order) = if data.starts_with(b"Nikon\0\x02") {
+            // A complete TIFF structure starts 10 bytes in, and offsets are relative to it.
+            let base = offset + 10;
+            let reader = IfdReader::new(tiff.buf().get(base..)?).ok()?;
+            let ifd_offset = reader.first_ifd_offset().ok()?;
+            (Vendor::Nikon, base, ifd_offset, reader.byte_order())
+        } else if data.starts_with(b"OLYMPUS\0") {
+            let order = ByteOrder::from_marker(data.get(8..10)?)?;
+            (Vendor::Olympus, offset, 12, order)
+        } else if data.starts_with(b"OM SYSTEM\0") {
+            let order = ByteOrder::from_marker(data.get(12..14)?)?;
+            (Vendor::Olympus, offset, 16, order)
+        } else if data.starts_with(b"OLYMP\0") {
+            // Older Olympus MakerNotes have offsets relative to the main TIFF header.
+            (Vendor::Olympus, 0, offset + 8, order)
+        } else if data.starts_with(b"AOC\0") {
+            let order = ByteOrder::from_marker(data.get(4..6)?)?;
+            (Vendor::Pentax, 0, offset + 6, order)
+        } else if data.starts_with(b"PENTAX \0") {
+            let order = ByteOrder::from_marker(data.get(8..10)?)?;
+            (Vendor::Pentax, offset, 10, order)
+        } else if data.starts_with(b"SONY DSC \0\0\0") || data.starts_with(b"SONY CAM \0\0\0") {
+            // Offsets are relative to the TIFF header, despite the MakerNote's own header.
+            (Vendor::Sony, 0, offset + 12, order)
+        } else if make.starts_with("SONY") {
+            // Some older bodies write a bare IFD instead.
+            (Vendor::Sony, 0, offset, order)
+        } else if make.starts_with("Canon") {
+            // Canon MakerNotes are a bare IFD in the file's byte order, with offsets relative to
+            // the TIFF header.
+            (Vendor::Canon, 0, offset, order)
+        } else if make.starts_with("SAMSUNG") {
+            // As are Samsung's, at least for the SRW bodies which have a PreviewIFD.
+            (Vendor::Samsung, 0, offset, order)
+        } else {
+            return None;
+        };
+        let (base, ifd_offset) = if relative {
+            (offset, (base + ifd_offset).checked_sub(offset)?)
+        } else {
+            (base, ifd_offset)
+        };
+
+        let reader = IfdReader::with_byte_order(tiff.buf().get(base..)?, order);
+        let ifd = reader.ifd(ifd_offset).ok()?;
+        Some(Self {
+            vendor,
+            reader,
+            base,
+            ifd,
+        })
+    }
+
+    /// Get a preview from a start and length tag pair in `ifd`, which is in this MakerNote.
+    fn preview_from_tags(
+        &self,
+        ifd: &Ifd,
+        start_tag: u16,
+        length_tag: u16,
+    ) -> Option<MakerNotePreview> {
+        let start = ifd.entry(start_tag)?.usize()?;
+        let length = ifd.entry(length_tag)?.usize()?;
+        (start > 0 && length > 0).then(|| MakerNotePreview {
+            offset: self.base + start,
+            length,
+        })
+    }
+
+    /// Find any previews stored in the MakerNote, rather than in the main IFDs.
+    ///
+    /// - Nikon: PreviewIFD (0x11), which is an IFD with the usual JPEG tags.
+    /// - Samsung: PreviewIFD (0x35), likewise.
+    /// - Olympus: CameraSettings (0x2020) PreviewImageStart/Length, or the older
+    ///   PreviewImageStart/Length (0x88/0x89) in the main MakerNote IFD.
+    /// - Pentax: PreviewImageStart/Length (0x4/0x3).
+    /// - Sony: PreviewImage (0x2001), an UNDEFINED blob which is the preview. On bodies with
+    ///   compressed RAW this can be bigger than the JpgFromRaw in IFD0. SR2Private (0xc634 in
+    ///   IFD0) isn't looked at, since its encrypted SR2SubIFD holds processing settings like white
+    ///   balance rather than an image.
+    pub fn previews(&self) -> Vec<MakerNotePreview> {
+        const NIKON_PREVIEW_IFD_TAG: u16 = 0x11;
+        const SAMSUNG_PREVIEW_IFD_TAG: u16 = 0x35;
+        const JPEG_TAG: u16 = 0x201;
+        const JPEG_LENGTH_TAG: u16 = 0x202;
+        const OLYMPUS_CAMERA_SETTINGS_TAG: u16 = 0x2020;
+        const OLYMPUS_PREVIEW_START_TAG: u16 = 0x101;
+        const OLYMPUS_PREVIEW_LENGTH_TAG: u16 = 0x102;
+        const OLYMPUS_OLD_PREVIEW_START_TAG: u16 = 0x88;
+        const OLYMPUS_OLD_PREVIEW_LENGTH_TAG: u16 = 0x89;
+        const PENTAX_PREVIEW_LENGTH_TAG: u16 = 0x3;
+        const PENTAX_PREVIEW_START_TAG: u16 = 0x4;
+        const SONY_PREVIEW_IMAGE_TAG: u16 = 0x2001;
+        // Some Sony previews have a short header of their own before the SOI.
+        const SONY_PREVIEW_MAX_HEADER: usize = 32;
+
+        let mut previews = Vec::new();
+        match self.vendor {
+            Vendor::Nikon | Vendor::Samsung => {
+                let tag = match self.vendor {
+                    Vendor::Nikon => NIKON_PREVIEW_IFD_TAG,
+                    _ => SAMSUNG_PREVIEW_IFD_TAG,
+                };
+                let preview_ifd = self
+                    .ifd
+                    .entry(tag)
+                    .and_then(|e| e.usize())
+                    .and_then(|offset| self.reader.ifd(offset).ok());
+                if let Some(ifd) = preview_ifd {
+                    previews.extend(self.preview_from_tags(&ifd, JPEG_TAG, JPEG_LENGTH_TAG));
+                }
+            }
+            Vendor::Olympus => {
+                // CameraSettings is either an IFD pointer, or an UNDEFINED blob which holds an
+                // IFD. Either way, the value offset is where the IFD is.
+                let camera_settings = self
+                    .ifd
+                    .entry(OLYMPUS_CAMERA_SETTINGS_TAG)
+                    .and_then(|e| self.reader.ifd(e.value_offset()).ok());
+                if let Some(ifd) = camera_settings {
+                    previews.extend(self.preview_from_tags(
+                        &ifd,
+                        OLYMPUS_PREVIEW_START_TAG,
+                        OLYMPUS_PREVIEW_LENGTH_TAG,
+                    ));
+                }
+                previews.extend(self.preview_from_tags(
+                    &self.ifd,
+                    OLYMPUS_OLD_PREVIEW_START_TAG,
+                    OLYMPUS_OLD_PREVIEW_LENGTH_TAG,
+                ));
+            }
+            Vendor::Pentax => {
+                previews.extend(self.preview_from_tags(
+                    &self.ifd,
+                    PENTAX_PREVIEW_START_TAG,
+                    PENTAX_PREVIEW_LENGTH_TAG,
+                ));
+            }
+            Vendor::Sony => {
+                let preview = self
+                    .ifd
+                    .entry(SONY_PREVIEW_IMAGE_TAG)
+                    .filter(|e| e.count() > 0)
+                    .and_then(|e| {
+                        let data = e.data().ok()?;
+                        let header = data.get(..SONY_PREVIEW_MAX_HEADER).unwrap_or(data);
+                        let soi = memmem::find(header, &[0xff, 0xd8, 0xff])?;
+                        Some(MakerNotePreview {
+                            offset: self.base + e.value_offset() + soi,
+                            length: e.count() - soi,
+                        })
+                    });
+                previews.extend(preview);
+            }
+            Vendor::Canon => {}
+        }
+        previews
+    }
+
+    /// Look up the orientation the camera recorded in its MakerNote.
+    ///
+    /// This is the last resort for bodies which don't write 0x112 into the preview IFD or IFD0.
+    /// Only vendors with a reasonably stable MakerNote layout are handled:
+    ///
+    /// - Canon: ShotInfo (0x4) AutoRotate, at index 27 of the array.
+    /// - Pentax/Ricoh: LevelInfo (0x22b) LevelOrientation, in the low nibble of the first byte.
+    ///
+    /// Olympus is located for its preview, but no orientation tag is documented in its
+    /// CameraSettings, and ORFs carry 0x112 in IFD0 anyway, so it isn't an orientation source.
+    /// Neither are Nikon, Samsung and Sony.
+    ///
+    /// Everything here is best effort, so malformed data just means we return `None`.
+    pub fn orientation(&self) -> Option<u16> {
+        const CANON_SHOT_INFO_TAG: u16 = 0x4;
+        const CANON_AUTO_ROTATE_INDEX: usize = 27;
+        const PENTAX_LEVEL_INFO_TAG: u16 = 0x22b;
+
+        match self.vendor {
+            Vendor::Canon => {
+                let Ok(Value::Short(shot_info)) = self.ifd.entry(CANON_SHOT_INFO_TAG)?.value()
+                else {
+                    return None;
+                };
+                match *shot_info.get(CANON_AUTO_ROTATE_INDEX)? as i16 {
+                    0 => Some(1),
+                    1 => Some(6),
+                    2 => Some(3),
+                    3 => Some(8),
+                    _ => None,
+                }
+            }
+            Vendor::Pentax => {
+                let level_info = self.ifd.entry(PENTAX_LEVEL_INFO_TAG)?;
+                match level_info.data().ok()?.first()? & 0x0f {
+                    1 => Some(1),
+                    2 => Some(3),
+                    3 => Some(6),
+                    4 => Some(8),
+                    _ => None,
+                }
+            }
+            Vendor::Nikon | Vendor::Olympus | Vendor::Samsung | Vendor::Sony => None,
+        }
+    }
+}
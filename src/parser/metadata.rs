@@ -0,0 +1,213 @@
+//! Reading camera metadata without touching the preview or sensor data.
+//!
+//! Indexers want the basics (who took it with what, when, where, and at what settings) for a lot
+//! of files quickly, so this walks only the IFDs holding those tags. The RAW is mapped with the
+//! same random access advice as for extraction, so only the pages holding those IFDs are read.
+
+use anyhow::{bail, Result};
+use chrono::{FixedOffset, Local, NaiveDateTime, TimeZone};
+use serde::Serialize;
+use std::path::Path;
+use std::time::SystemTime;
+
+use super::{cr3, find_tiff_header_offset, platform, Container};
+use crate::tiff::{Ifd, IfdReader};
+
+pub use crate::tiff::Rational;
+
+const MAKE_TAG: u16 = 0x10f;
+const MODEL_TAG: u16 = 0x110;
+const EXIF_IFD_TAG: u16 = 0x8769;
+const GPS_IFD_TAG: u16 = 0x8825;
+const CAMERA_SERIAL_NUMBER_TAG: u16 = 0xc62f;
+
+const EXPOSURE_TIME_TAG: u16 = 0x829a;
+const F_NUMBER_TAG: u16 = 0x829d;
+const ISO_TAG: u16 = 0x8827;
+const RECOMMENDED_EXPOSURE_INDEX_TAG: u16 = 0x8832;
+const ISO_SPEED_TAG: u16 = 0x8833;
+const DATE_TIME_ORIGINAL_TAG: u16 = 0x9003;
+const OFFSET_TIME_ORIGINAL_TAG: u16 = 0x9011;
+const FOCAL_LENGTH_TAG: u16 = 0x920a;
+const BODY_SERIAL_NUMBER_TAG: u16 = 0xa431;
+const LENS_MODEL_TAG: u16 = 0xa434;
+
+const GPS_LATITUDE_REF_TAG: u16 = 0x1;
+const GPS_LATITUDE_TAG: u16 = 0x2;
+const GPS_LONGITUDE_REF_TAG: u16 = 0x3;
+const GPS_LONGITUDE_TAG: u16 = 0x4;
+const GPS_ALTITUDE_REF_TAG: u16 = 0x5;
+const GPS_ALTITUDE_TAG: u16 = 0x6;
+
+/// A GPS position, in signed decimal degrees and metres above sea level.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct GpsPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+}
+
+/// Camera metadata read from a RAW file's IFDs. Anything the file doesn't have is `None`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RawMetadata {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens_model: Option<String>,
+    pub serial_number: Option<String>,
+    /// DateTimeOriginal as written by the camera, in the EXIF "YYYY:MM:DD HH:MM:SS" format.
+    pub date_time_original: Option<String>,
+    /// OffsetTimeOriginal, the UTC offset for `date_time_original`, like "+01:00".
+    pub offset_time_original: Option<String>,
+    /// Exposure time in seconds, kept as a rational so that 1/250 stays 1/250.
+    pub exposure_time: Option<Rational>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    /// Focal length in millimetres.
+    pub focal_length: Option<f64>,
+    pub gps: Option<GpsPosition>,
+}
+
+impl RawMetadata {
+    /// DateTimeOriginal, parsed. This is local time, in whatever zone the camera was set to.
+    pub fn date(&self) -> Option<NaiveDateTime> {
+        let date = self.date_time_original.as_deref()?;
+        NaiveDateTime::parse_from_str(date, "%Y:%m:%d %H:%M:%S").ok()
+    }
+
+    /// When the photo was taken, as an instant. Without OffsetTimeOriginal, the camera's clock is
+    /// assumed to have been in the local time zone.
+    pub fn capture_time(&self) -> Option<SystemTime> {
+        let date = self.date()?;
+        let offset = self
+            .offset_time_original
+            .as_deref()
+            .and_then(|offset| offset.trim().parse::<FixedOffset>().ok());
+        match offset {
+            Some(offset) => Some(offset.from_local_datetime(&date).single()?.into()),
+            None => Some(Local.from_local_datetime(&date).earliest()?.into()),
+        }
+    }
+}
+
+fn ascii(ifd: &Ifd, tag: u16) -> Option<String> {
+    ifd.entry(tag)?.ascii()
+}
+
+fn rational(ifd: &Ifd, tag: u16) -> Option<f64> {
+    ifd.entry(tag)?.rational()?.to_f64()
+}
+
+fn gps(ifd: &Ifd) -> Option<GpsPosition> {
+    let degrees = |tag, ref_tag, negative: &str| -> Option<f64> {
+        let dms = ifd.entry(tag)?.rationals()?;
+        let [d, m, s] = dms.as_slice() else {
+            return None;
+        };
+        let value = d.to_f64()? + m.to_f64()? / 60.0 + s.to_f64()? / 3600.0;
+        let sign = match ascii(ifd, ref_tag) {
+            Some(r) if r == negative => -1.0,
+            _ => 1.0,
+        };
+        Some(sign * value)
+    };
+    let altitude = rational(ifd, GPS_ALTITUDE_TAG).map(|alt| {
+        let below_sea_level = ifd
+            .entry(GPS_ALTITUDE_REF_TAG)
+            .and_then(|e| e.u32())
+            .is_some_and(|r| r == 1);
+        if below_sea_level {
+            -alt
+        } else {
+            alt
+        }
+    });
+    Some(GpsPosition {
+        latitude: degrees(GPS_LATITUDE_TAG, GPS_LATITUDE_REF_TAG, "S")?,
+        longitude: degrees(GPS_LONGITUDE_TAG, GPS_LONGITUDE_REF_TAG, "W")?,
+        altitude,
+    })
+}
+
+/// Read metadata from the TIFF structure starting at `tiff_offset` in `raw_buf`.
+pub(crate) fn metadata_from_buf(raw_buf: &[u8], tiff_offset: usize) -> Result<RawMetadata> {
+    let tiff = IfdReader::new(&raw_buf[tiff_offset..])?;
+    let ifd0 = tiff.ifd(tiff.first_ifd_offset()?)?;
+    let sub_ifd = |tag| ifd0.entry(tag)?.usize().and_then(|o| tiff.ifd(o).ok());
+    Ok(metadata_from_ifds(
+        &ifd0,
+        sub_ifd(EXIF_IFD_TAG),
+        sub_ifd(GPS_IFD_TAG),
+    ))
+}
+
+/// The first IFD of the TIFF structure in `buf`.
+fn first_ifd(buf: &[u8]) -> Result<Ifd<'_>> {
+    let tiff = IfdReader::new(buf)?;
+    tiff.ifd(tiff.first_ifd_offset()?)
+}
+
+/// Read metadata from a CR3, which keeps each IFD as a TIFF structure of its own in a CMT box.
+fn metadata_from_cr3(raw_buf: &[u8]) -> Result<RawMetadata> {
+    let Some(tiffs) = cr3::metadata_tiffs(raw_buf) else {
+        bail!("No CMT1 box in CR3");
+    };
+    let ifd0 = first_ifd(tiffs.ifd0)?;
+    Ok(metadata_from_ifds(
+        &ifd0,
+        tiffs.exif.and_then(|buf| first_ifd(buf).ok()),
+        tiffs.gps.and_then(|buf| first_ifd(buf).ok()),
+    ))
+}
+
+/// Collect metadata from IFD0 and, where present, the Exif and GPS IFDs.
+fn metadata_from_ifds(ifd0: &Ifd, exif: Option<Ifd>, gps_ifd: Option<Ifd>) -> RawMetadata {
+    let mut metadata = RawMetadata {
+        make: ascii(ifd0, MAKE_TAG),
+        model: ascii(ifd0, MODEL_TAG),
+        serial_number: ascii(ifd0, CAMERA_SERIAL_NUMBER_TAG),
+        ..Default::default()
+    };
+
+    if let Some(exif) = exif {
+        metadata.lens_model = ascii(&exif, LENS_MODEL_TAG);
+        metadata.serial_number = ascii(&exif, BODY_SERIAL_NUMBER_TAG).or(metadata.serial_number);
+        metadata.date_time_original = ascii(&exif, DATE_TIME_ORIGINAL_TAG);
+        metadata.offset_time_original = ascii(&exif, OFFSET_TIME_ORIGINAL_TAG);
+        metadata.exposure_time = exif.entry(EXPOSURE_TIME_TAG).and_then(|e| e.rational());
+        metadata.f_number = rational(&exif, F_NUMBER_TAG);
+        metadata.focal_length = rational(&exif, FOCAL_LENGTH_TAG);
+        // ISOSpeedRatings saturates at 65535, the real value is then in one of the newer tags.
+        let uint = |tag| exif.entry(tag).and_then(|e| e.u32());
+        metadata.iso = match uint(ISO_TAG) {
+            Some(65535) | None => uint(ISO_SPEED_TAG)
+                .or_else(|| uint(RECOMMENDED_EXPOSURE_INDEX_TAG))
+                .or(uint(ISO_TAG)),
+            iso => iso,
+        };
+    }
+
+    if let Some(gps_ifd) = gps_ifd {
+        metadata.gps = gps(&gps_ifd);
+    }
+
+    metadata
+}
+
+/// Read metadata from a RAW which is already mapped, and laid out as `container`. CR3s are read
+/// from their CMT boxes, and everything else from its TIFF structure.
+pub(super) fn metadata_from_raw(raw_buf: &[u8], container: Container) -> Result<RawMetadata> {
+    match container {
+        Container::Bmff => metadata_from_cr3(raw_buf),
+        Container::Tiff { offset, .. } => metadata_from_buf(raw_buf, offset),
+        Container::Iiq { .. } | Container::Unknown => {
+            metadata_from_buf(raw_buf, find_tiff_header_offset(raw_buf)?)
+        }
+    }
+}
+
+/// Read camera metadata from a RAW file, without reading the preview or sensor data.
+pub async fn read_metadata(path: &Path) -> Result<RawMetadata> {
+    let in_file = platform::open_raw(path).await?;
+    let raw_buf = platform::mmap_raw(in_file)?;
+    metadata_from_raw(&raw_buf, Container::detect(&raw_buf))
+}
@@ -1,8 +1,16 @@
-use anyhow::{ensure, Result};
-use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use anyhow::{bail, ensure, Result};
+use chrono::{DateTime, Local, NaiveDateTime};
+use filetime::FileTime;
 use memchr::memmem;
 use memmap2::Mmap;
-use std::path::Path;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+mod cr3;
+mod iiq;
+mod makernote;
+pub mod metadata;
+mod quirks;
 
 #[cfg(unix)]
 mod unix;
@@ -13,30 +21,197 @@ mod windows;
 #[cfg(unix)]
 use unix as platform;
 
-use std::time::Instant;
+use crate::bmff;
+use crate::encode::{self, OutputFormat};
+use crate::formats;
+use crate::mpf;
+use crate::output::{self, OutputClaims, OverwritePolicy, Reservation, Turn, WriteOutcome};
+use crate::resize::{self, ResizeOptions};
+use crate::template::{NameTemplate, TemplateValues};
+use crate::tiff::{FieldType, Ifd, IfdReader};
+use crate::transform;
+use makernote::MakerNote;
+use metadata::RawMetadata;
+use std::time::{Duration, Instant};
 #[cfg(windows)]
 use windows as platform;
 
 /// An embedded JPEG in a RAW file.
-#[derive(Default, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct EmbeddedJpegInfo {
     offset: usize,
     length: usize,
     orientation: Option<u16>,
+    color_space: Option<PreviewColorSpace>,
+    kind: PreviewKind,
+    dimensions: Option<(u32, u32)>,
+    /// Where the decoder configuration is in the file, for formats which keep it separately.
+    codec_config: Option<(usize, usize)>,
+}
+
+impl EmbeddedJpegInfo {
+    /// Where the JPEG starts, relative to the start of the file.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// The EXIF orientation of the JPEG, if the RAW records one.
+    pub fn orientation(&self) -> Option<u16> {
+        self.orientation
+    }
+
+    /// The colour space of the JPEG, if the RAW says what it is. Only DNG does so far.
+    pub fn color_space(&self) -> Option<PreviewColorSpace> {
+        self.color_space
+    }
+
+    pub fn kind(&self) -> PreviewKind {
+        self.kind
+    }
+
+    /// The width and height of the preview, if the RAW records them outside the preview itself.
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.dimensions
+    }
+}
+
+/// How an embedded preview is compressed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PreviewKind {
+    #[default]
+    Jpeg,
+    /// JPEG XL, which DNG 1.7 allows for previews. These can only be extracted as-is, and only when
+    /// asked for with [`ExtractOptions::allow_jxl`].
+    JpegXl,
+    /// A HEVC coded image, as in CR3 files shot in HDR PQ mode. These are extracted as-is, wrapped
+    /// up as a standalone HEIF file.
+    Heif,
+}
+
+impl PreviewKind {
+    /// The file extension for previews of this kind when written as-is, without a leading dot.
+    pub const fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::JpegXl => "jxl",
+            Self::Heif => "heic",
+        }
+    }
+}
+
+/// The colour space of a DNG preview, from its PreviewColorSpace tag.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PreviewColorSpace {
+    Unknown,
+    GrayGamma22,
+    Srgb,
+    AdobeRgb,
+    ProPhotoRgb,
 }
 
+impl PreviewColorSpace {
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Unknown),
+            1 => Some(Self::GrayGamma22),
+            2 => Some(Self::Srgb),
+            3 => Some(Self::AdobeRgb),
+            4 => Some(Self::ProPhotoRgb),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub enum FindJpegType {
+    #[default]
     Largest,
     Smallest,
 }
 
+/// What was done with one RAW by [`process_file_with_report`].
+#[derive(Clone, Debug)]
+pub struct FileReport {
+    pub outcome: WriteOutcome,
+    /// What kind of file the RAW is: "tiff", "bigtiff", "bmff", "iiq", or "unknown". `None` if it
+    /// was skipped without being read.
+    pub container: Option<&'static str>,
+    /// The preview that was extracted. `None` if the RAW didn't have one, so was copied as-is, or
+    /// if it was skipped without being read.
+    pub preview: Option<EmbeddedJpegInfo>,
+    /// The preview's width and height, before any resizing.
+    pub dimensions: Option<(u32, u32)>,
+    /// How long each stage took, in order.
+    pub timings: Vec<(&'static str, Duration)>,
+    /// Whether this was a dry run, so the outcome is only what would have been done.
+    pub dry_run: bool,
+}
+
+/// What to set the timestamps of written files to.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Timestamps {
+    /// Leave them as the time they were written.
+    #[default]
+    Written,
+    /// Copy the RAW's access and modification times.
+    Source,
+    /// Set the modification time to when the photo was taken, from DateTimeOriginal and
+    /// OffsetTimeOriginal, or to the RAW's modification time if it doesn't say.
+    Capture,
+}
+
+/// Options controlling how the embedded JPEG is found and what we do with it.
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Which of the embedded JPEGs to extract.
+    pub find_type: FindJpegType,
+    /// Losslessly rotate the JPEG to match its orientation and write Orientation=1, rather than
+    /// leaving it to the viewer to honour the EXIF Orientation tag. Falls back to only writing the
+    /// tag if the JPEG can't be transformed.
+    pub apply_orientation: bool,
+    /// Downscale the JPEG to a thumbnail after extraction.
+    pub resize: Option<ResizeOptions>,
+    /// The format to write. Anything other than JPEG means decoding and re-encoding the preview,
+    /// with any orientation applied to the pixels.
+    pub format: OutputFormat,
+    /// Consider JPEG XL previews, and write them out as-is as `.jxl` if chosen. Nothing can be done
+    /// to them on the way, so orientation, resizing and the output format don't apply.
+    pub allow_jxl: bool,
+    /// Also extract the secondary images listed in the preview's MPF segment, like smaller
+    /// previews or depth maps. They're written as-is next to the preview, with a `-mpfN` suffix.
+    pub extract_mpf_images: bool,
+    /// Where to write the preview, relative to the output directory. Without a template, the
+    /// RAW's path relative to the input directory is used, with the extension replaced.
+    pub name_template: Option<NameTemplate>,
+    /// What to do when the output file already exists from an earlier run.
+    pub overwrite: OverwritePolicy,
+    /// The output paths already taken in this run, so that two RAWs which would be written to the
+    /// same place don't overwrite each other. Shared between clones of the options. Queue RAWs in
+    /// it with [`OutputClaims::enqueue`], and process them with [`process_file_in_turn`], to
+    /// decide their paths in a fixed order.
+    pub claims: OutputClaims,
+    /// Flush each output to disk before moving on, rather than leaving it to the OS. Outputs are
+    /// always written atomically either way, but might not survive a power cut without this.
+    pub fsync: bool,
+    /// What to set the timestamps of written files to.
+    pub timestamps: Timestamps,
+    /// Only work out what would be done: find the preview and decide where it would go, but
+    /// don't process it or write anything. The bytes returned are empty.
+    pub dry_run: bool,
+}
+
 const TIFF_HEADER: &[u8; 4] = b"II*\0";
 const TIFF_HEADERMM: &[u8; 4] = b"MM\0*";
 const EXIF_HEADER: &[u8; 6] = b"Exif\0\0";
 const EXIF_HEADER_SIZE: usize = 6;
 
 fn find_tiff_header_offset(raw_buf: &[u8]) -> Result<usize> {
-    if &raw_buf[0..4] == TIFF_HEADER {
+    // Bare TIFF based RAWs, including the ones with a vendor specific magic number.
+    if IfdReader::new(raw_buf).is_ok() {
         return Ok(0);
     }
 
@@ -50,14 +225,14 @@ fn find_tiff_header_offset(raw_buf: &[u8]) -> Result<usize> {
         let slice_end = slice_start + 10;
         ensure!(slice_end <= raw_buf.len(), "Invalid Exif header position");
         let slice = &raw_buf[slice_start..slice_end];
-        let tiff_found = memmem::find_iter(&slice, TIFF_HEADER).next();
+        let tiff_found = memmem::find_iter(slice, TIFF_HEADER).next();
         if let Some(tiff_pos) = tiff_found {
-            println!("Found TIFF header at: {}", tiff_pos);
+            verbose!("Found TIFF header at: {}", tiff_pos);
             return Ok(slice_start + tiff_pos);
         }
-        let tiff_found = memmem::find_iter(&slice, TIFF_HEADERMM).next();
+        let tiff_found = memmem::find_iter(slice, TIFF_HEADERMM).next();
         if let Some(tiff_pos) = tiff_found {
-            println!("Found TIFF header2 at: {}", tiff_pos);
+            verbose!("Found TIFF header2 at: {}", tiff_pos);
             return Ok(slice_start + tiff_pos);
         }
         return Ok(slice_start);
@@ -66,131 +241,332 @@ fn find_tiff_header_offset(raw_buf: &[u8]) -> Result<usize> {
         "No Exif APP1 segment with TIFF header found"
     ))
 }
-/// Find the largest embedded JPEG data in a memory-mapped RAW buffer.
-///
-/// This function parses the IFDs in the TIFF structure of the RAW file to find the largest JPEG
-/// thumbnail embedded in the file.
-///
-/// We hand roll the IFD parsing because libraries do not fit requirements. For example:
+
+fn ifd_orientation(ifd: &Ifd) -> Option<u16> {
+    const ORIENTATION_TAG: u16 = 0x112;
+
+    ifd.entry(ORIENTATION_TAG)?.u32()?.try_into().ok()
+}
+
+/// Get the JPEG stored in an IFD, if it has one.
 ///
-/// - kamadak-exif: Reads into a big `Vec<u8>`, which is huge for our big RAW.
-/// - quickexif: Cannot iterate over IFDs.
-fn find_largest_embedded_jpeg(
-    raw_buf: &[u8],
-    tiff_offset: usize,
-    find_type: FindJpegType,
-) -> Result<EmbeddedJpegInfo> {
-    const IFD_ENTRY_SIZE: usize = 12;
-    const TIFF_MAGIC_LE: &[u8] = b"II*\0";
-    const TIFF_MAGIC_BE: &[u8] = b"MM\0*";
+/// That's usually through the JPEGInterchangeFormat tags. DNG and Hasselblad previews are instead
+/// stored as JPEG compressed strips. For DNG, only reduced resolution IFDs are considered, so that
+/// the main image of a lossy DNG is never mistaken for a preview.
+fn jpeg_from_ifd(ifd: &Ifd, is_dng: bool) -> Option<EmbeddedJpegInfo> {
+    const NEW_SUBFILE_TYPE_TAG: u16 = 0xfe;
+    const COMPRESSION_TAG: u16 = 0x103;
+    const PHOTOMETRIC_INTERPRETATION_TAG: u16 = 0x106;
+    const STRIP_OFFSETS_TAG: u16 = 0x111;
+    const STRIP_BYTE_COUNTS_TAG: u16 = 0x117;
     const JPEG_TAG: u16 = 0x201;
     const JPEG_LENGTH_TAG: u16 = 0x202;
-    const ORIENTATION_TAG: u16 = 0x112;
+    const PREVIEW_COLOR_SPACE_TAG: u16 = 0xc71a;
+    const COMPRESSION_JPEG: u32 = 7;
+    const COMPRESSION_JPEG_XL: u32 = 52546;
+    const PHOTOMETRIC_CFA: u32 = 32803;
+    const PHOTOMETRIC_LINEAR_RAW: u32 = 34892;
 
-    let raw_buf = &raw_buf[tiff_offset..];
+    let uint = |tag| ifd.entry(tag).and_then(|e| e.u32());
 
-    ensure!(raw_buf.len() >= 8, "Not enough data for TIFF header");
-
-    let is_le = &raw_buf[0..4] == TIFF_MAGIC_LE;
-    ensure!(
-        is_le || &raw_buf[0..4] == TIFF_MAGIC_BE,
-        "Not a valid TIFF file"
-    );
+    // NewSubfileType defaults to 0, the main image. Bit 0 marks reduced resolution versions.
+    if is_dng && uint(NEW_SUBFILE_TYPE_TAG).unwrap_or(0) & 1 == 0 {
+        return None;
+    }
 
-    let read_u16 = if is_le {
-        LittleEndian::read_u16
+    let mut kind = PreviewKind::Jpeg;
+    let compression = uint(COMPRESSION_TAG);
+    let photometric = uint(PHOTOMETRIC_INTERPRETATION_TAG);
+    let jpeg_tags = ifd.entry(JPEG_TAG).zip(ifd.entry(JPEG_LENGTH_TAG));
+    let (offset, length) = if let Some((offset, length)) = jpeg_tags {
+        (offset.usize()?, length.usize()?)
+    } else if (compression == Some(COMPRESSION_JPEG)
+        || is_dng && compression == Some(COMPRESSION_JPEG_XL))
+        && !matches!(photometric, Some(PHOTOMETRIC_CFA | PHOTOMETRIC_LINEAR_RAW))
+    {
+        if compression == Some(COMPRESSION_JPEG_XL) {
+            kind = PreviewKind::JpegXl;
+        }
+        let offsets = ifd.entry(STRIP_OFFSETS_TAG)?;
+        let lengths = ifd.entry(STRIP_BYTE_COUNTS_TAG)?;
+        // A multi strip image isn't a file we can just copy out.
+        if offsets.count() != 1 || lengths.count() != 1 {
+            return None;
+        }
+        (offsets.usize()?, lengths.usize()?)
     } else {
-        BigEndian::read_u16
+        return None;
     };
 
-    let read_u32 = if is_le {
-        LittleEndian::read_u32
+    let color_space = if is_dng {
+        uint(PREVIEW_COLOR_SPACE_TAG).and_then(PreviewColorSpace::from_u32)
     } else {
-        BigEndian::read_u32
+        None
     };
+    (length > 0).then_some(EmbeddedJpegInfo {
+        offset,
+        length,
+        orientation: ifd_orientation(ifd),
+        color_space,
+        kind,
+        ..Default::default()
+    })
+}
 
-    let mut next_ifd_offset = read_u32(&raw_buf[4..8]).try_into()?;
-    let mut largest_jpeg = EmbeddedJpegInfo::default();
-
-    while next_ifd_offset != 0 {
-        ensure!(next_ifd_offset + 2 <= raw_buf.len(), "Invalid IFD offset");
-
-        let cursor = &raw_buf[next_ifd_offset..];
-        let num_entries = read_u16(&cursor[..2]).into();
-        let entries_cursor = &cursor[2..];
-
-        let entries_len = num_entries * IFD_ENTRY_SIZE;
-        ensure!(
-            entries_cursor.len() >= entries_len,
-            "Invalid number of IFD entries"
-        );
-
-        let mut cur_offset = None;
-        let mut cur_length = None;
-        let mut cur_orientation = None;
-
-        for entry in entries_cursor
-            .chunks_exact(IFD_ENTRY_SIZE)
-            .take(num_entries)
-        {
-            let tag = read_u16(&entry[..2]);
-
-            match tag {
-                JPEG_TAG => cur_offset = Some(read_u32(&entry[8..12]).try_into()?),
-                JPEG_LENGTH_TAG => cur_length = Some(read_u32(&entry[8..12]).try_into()?),
-                ORIENTATION_TAG => cur_orientation = Some(read_u16(&entry[8..10])),
-                _ => {}
-            }
+/// Find all the embedded JPEGs in a memory-mapped RAW buffer.
+///
+/// This parses the IFDs in the TIFF structure of the RAW file (and their SubIFDs) for JPEG
+/// thumbnails, as well as the MakerNote for the vendors which keep a preview in there instead.
+/// Offsets are relative to the start of `raw_buf`.
+///
+/// Orientation is resolved with the following precedence, since previews in later IFDs usually
+/// don't carry their own: the preview's IFD, then IFD0, then the MakerNote.
+///
+/// We hand roll the IFD parsing because libraries do not fit requirements. For example:
+///
+/// - kamadak-exif: Reads into a big `Vec<u8>`, which is huge for our big RAW.
+/// - quickexif: Cannot iterate over IFDs.
+fn find_embedded_jpegs(raw_buf: &[u8], tiff_offset: usize) -> Result<Vec<EmbeddedJpegInfo>> {
+    const NEW_SUBFILE_TYPE_TAG: u16 = 0xfe;
+    const SUB_IFDS_TAG: u16 = 0x14a;
+    const MAKE_TAG: u16 = 0x10f;
+    const MODEL_TAG: u16 = 0x110;
+    const EXIF_IFD_TAG: u16 = 0x8769;
+    const DNG_VERSION_TAG: u16 = 0xc612;
+    // Panasonic RW2 keeps its full size preview in IFD0 as an UNDEFINED blob.
+    const PANASONIC_JPG_FROM_RAW_TAG: u16 = 0x2e;
+
+    let tiff = IfdReader::new(&raw_buf[tiff_offset..])?;
+
+    let mut candidates = Vec::new();
+    let mut ifd0_orientation = None;
+    let mut make = String::new();
+    let mut model = String::new();
+    let mut exif_ifd_offset = None;
+    let mut sub_ifd_offsets = Vec::new();
+    let mut is_dng = false;
 
-            if let (Some(offset), Some(length)) = (cur_offset, cur_length) {
-                match find_type {
-                    FindJpegType::Smallest => {
-                        if length < largest_jpeg.length || largest_jpeg.length == 0 {
-                            largest_jpeg = EmbeddedJpegInfo {
-                                offset,
-                                length,
-                                orientation: cur_orientation,
-                            };
-                        }
-                    }
-                    FindJpegType::Largest => {
-                        if length > largest_jpeg.length {
-                            largest_jpeg = EmbeddedJpegInfo {
-                                offset,
-                                length,
-                                orientation: cur_orientation,
-                            };
-                        }
-                    }
+    for (index, ifd) in tiff.ifds().enumerate() {
+        let ifd = ifd?;
+        let cur_orientation = ifd_orientation(&ifd);
+
+        if index == 0 {
+            is_dng = ifd.entry(DNG_VERSION_TAG).is_some();
+            ifd0_orientation = cur_orientation;
+            make = ifd
+                .entry(MAKE_TAG)
+                .and_then(|e| e.ascii())
+                .unwrap_or_default();
+            model = ifd
+                .entry(MODEL_TAG)
+                .and_then(|e| e.ascii())
+                .unwrap_or_default();
+            exif_ifd_offset = ifd.entry(EXIF_IFD_TAG).and_then(|e| e.usize());
+
+            if let Some(entry) = ifd.entry(PANASONIC_JPG_FROM_RAW_TAG) {
+                if entry.field_type() == FieldType::Undefined && entry.count() > 0 {
+                    candidates.push(EmbeddedJpegInfo {
+                        offset: entry.value_offset(),
+                        length: entry.count(),
+                        orientation: cur_orientation,
+                        ..Default::default()
+                    });
                 }
-                break;
             }
         }
 
-        let next_ifd_offset_offset = 2 + entries_len;
-        ensure!(
-            cursor.len() >= next_ifd_offset_offset + 4,
-            "Invalid next IFD offset"
-        );
-        next_ifd_offset = read_u32(&cursor[next_ifd_offset_offset..][..4]).try_into()?;
+        candidates.extend(jpeg_from_ifd(&ifd, is_dng));
+        if let Some(offsets) = ifd.entry(SUB_IFDS_TAG).and_then(|e| e.u64s()) {
+            sub_ifd_offsets.extend(offsets);
+        }
     }
 
-    ensure!(
-        largest_jpeg != EmbeddedJpegInfo::default(),
-        "No JPEG data found"
-    );
-    ensure!(
-        largest_jpeg.offset + largest_jpeg.length <= raw_buf.len(),
-        "JPEG data exceeds file size"
-    );
-
-    let jpeg_offset = largest_jpeg.offset + tiff_offset;
-
-    Ok(EmbeddedJpegInfo {
-        offset: jpeg_offset,
-        length: largest_jpeg.length,
-        orientation: largest_jpeg.orientation,
-    })
+    // SubIFDs mostly hold the sensor data, but some bodies keep previews there too, like Sony's
+    // with compressed RAW. Skip the full resolution image, since its data can be lossless JPEG
+    // which would look like a huge preview.
+    for offset in sub_ifd_offsets {
+        let Some(ifd) = usize::try_from(offset).ok().and_then(|o| tiff.ifd(o).ok()) else {
+            continue;
+        };
+        let subfile_type = ifd.entry(NEW_SUBFILE_TYPE_TAG).and_then(|e| e.u32());
+        if subfile_type == Some(0) {
+            continue;
+        }
+        candidates.extend(jpeg_from_ifd(&ifd, is_dng));
+    }
+
+    let quirks = quirks::lookup(&make, &model);
+    let maker_note = exif_ifd_offset
+        .and_then(|offset| tiff.ifd(offset).ok())
+        .and_then(|exif| MakerNote::locate(&tiff, &exif, &make, quirks.maker_note_relative));
+    let mut maker_note_orientation = None;
+    if let Some(maker_note) = &maker_note {
+        maker_note_orientation = maker_note.orientation();
+        // MakerNote offsets are easy to get wrong across firmware versions, but like every other
+        // candidate, ones which don't point at a JPEG that fits in the file are never chosen.
+        for preview in maker_note.previews() {
+            candidates.push(EmbeddedJpegInfo {
+                offset: preview.offset,
+                length: preview.length,
+                orientation: None,
+                ..Default::default()
+            });
+        }
+    }
+
+    for candidate in &mut candidates {
+        candidate.orientation = candidate
+            .orientation
+            .or(ifd0_orientation)
+            .or(maker_note_orientation);
+        if candidate.kind == PreviewKind::Jpeg {
+            if let Some(buf) = tiff.buf().get(candidate.offset..) {
+                candidate.length = quirks.adjust_length(buf, candidate.length);
+            }
+        }
+        // A bogus offset may not fit once rebased, but it would fail the bounds checks anyway.
+        candidate.offset = candidate.offset.saturating_add(tiff_offset);
+    }
+    Ok(candidates)
+}
+
+/// How a RAW is laid out, which decides where its previews are looked for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Container {
+    /// ISO BMFF based, like CR3.
+    Bmff,
+    /// A Phase One IIQ, with its directory at `base`.
+    Iiq {
+        base: usize,
+    },
+    /// TIFF based, or with a TIFF structure at `offset` inside something else.
+    Tiff {
+        offset: usize,
+        big: bool,
+    },
+    Unknown,
+}
+
+impl Container {
+    fn detect(raw_buf: &[u8]) -> Self {
+        if bmff::is_bmff(raw_buf) {
+            return Self::Bmff;
+        }
+        if let Some(base) = iiq::find_header(raw_buf) {
+            return Self::Iiq { base };
+        }
+        match find_tiff_header_offset(raw_buf) {
+            Ok(offset) => {
+                verbose!("Offset found at: {}", offset);
+                let big = IfdReader::new(&raw_buf[offset..]).is_ok_and(|tiff| tiff.is_big());
+                Self::Tiff { offset, big }
+            }
+            Err(_) => Self::Unknown,
+        }
+    }
+
+    /// The name used for it in reports.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Bmff => "bmff",
+            Self::Iiq { .. } => "iiq",
+            Self::Tiff { big: true, .. } => "bigtiff",
+            Self::Tiff { big: false, .. } => "tiff",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// Find every embedded preview in a memory-mapped RAW buffer laid out as `container`, whether it
+/// is TIFF based, ISO BMFF based like CR3, or a Phase One IIQ.
+fn find_candidates(raw_buf: &[u8], container: Container) -> Result<Vec<EmbeddedJpegInfo>> {
+    let candidates = match container {
+        Container::Bmff => cr3::find_previews(raw_buf)?,
+        Container::Iiq { base } => {
+            let mut candidates = iiq::find_previews(raw_buf, base)?;
+            // Newer IIQs put a TIFF structure in front of the Phase One directory, which may have
+            // its own thumbnail.
+            if base > 0 {
+                candidates.extend(find_embedded_jpegs(raw_buf, 0).unwrap_or_default());
+            }
+            candidates
+        }
+        Container::Tiff { offset, .. } => find_embedded_jpegs(raw_buf, offset)?,
+        Container::Unknown => bail!("No Exif APP1 segment with TIFF header found"),
+    };
+    Ok(candidates)
+}
+
+/// Whether `preview` fits inside `raw_buf` and, for a JPEG, actually starts with one. A single
+/// bogus IFD entry with a huge length would otherwise always be the largest.
+fn is_plausible(raw_buf: &[u8], preview: &EmbeddedJpegInfo) -> bool {
+    let Some(data) = preview
+        .offset
+        .checked_add(preview.length)
+        .and_then(|end| raw_buf.get(preview.offset..end))
+    else {
+        return false;
+    };
+    preview.kind != PreviewKind::Jpeg || data.starts_with(&[0xff, 0xd8])
+}
+
+/// Find the largest (or smallest, per `find_type`) embedded preview in a memory-mapped RAW buffer.
+/// Only previews which pass [`is_plausible`] are considered.
+///
+/// JPEG XL previews are only considered if `allow_jxl` is set.
+fn find_largest_embedded_jpeg(
+    raw_buf: &[u8],
+    container: Container,
+    find_type: FindJpegType,
+    allow_jxl: bool,
+) -> Result<EmbeddedJpegInfo> {
+    let chosen = find_candidates(raw_buf, container)?
+        .into_iter()
+        .filter(|c| allow_jxl || c.kind != PreviewKind::JpegXl)
+        .filter(|c| is_plausible(raw_buf, c))
+        .reduce(|best, cur| {
+            let better = match find_type {
+                FindJpegType::Smallest => cur.length < best.length,
+                FindJpegType::Largest => cur.length > best.length,
+            };
+            if better {
+                cur
+            } else {
+                best
+            }
+        });
+    let Some(chosen) = chosen else {
+        bail!("No JPEG data found");
+    };
+    Ok(chosen)
+}
+
+/// Find the embedded preview [`process_file_bytes_with_options`] would extract, without
+/// extracting it.
+pub async fn find_embedded_jpeg(
+    entry_path: &Path,
+    options: &ExtractOptions,
+) -> Result<EmbeddedJpegInfo> {
+    let in_file = platform::open_raw(entry_path).await?;
+    let raw_buf = platform::mmap_raw(in_file)?;
+    let container = Container::detect(&raw_buf);
+    find_largest_embedded_jpeg(&raw_buf, container, options.find_type, options.allow_jxl)
+}
+
+/// Find every embedded preview in a RAW which passes [`is_plausible`], in the order they're found.
+/// JPEG previews get their dimensions from their own headers if the RAW doesn't record them.
+pub async fn find_all_embedded_jpegs(entry_path: &Path) -> Result<Vec<EmbeddedJpegInfo>> {
+    let in_file = platform::open_raw(entry_path).await?;
+    let raw_buf = platform::mmap_raw(in_file)?;
+    let mut previews = find_candidates(&raw_buf, Container::detect(&raw_buf))?;
+    previews.retain(|preview| is_plausible(&raw_buf, preview));
+    for preview in &mut previews {
+        if preview.dimensions.is_none() && preview.kind == PreviewKind::Jpeg {
+            preview.dimensions =
+                resize::dimensions(&raw_buf[preview.offset..preview.offset + preview.length]);
+        }
+    }
+    Ok(previews)
 }
 
 /// Extract the JPEG bytes from the memory-mapped RAW buffer.
@@ -220,10 +596,13 @@ const fn get_header_bytes(orientation: u16) -> [u8; 34] {
     ]
 }
 
-async fn get_jpeg_data(jpeg_buf: &[u8], jpeg_info: &EmbeddedJpegInfo) -> Result<Vec<u8>> {
+async fn get_jpeg_data(jpeg_buf: &[u8], orientation: u16) -> Result<Vec<u8>> {
     let mut jpeg_data = Vec::with_capacity(jpeg_buf.len() + 34);
-    jpeg_data.extend_from_slice(&get_header_bytes(jpeg_info.orientation.unwrap_or(1)));
+    jpeg_data.extend_from_slice(&get_header_bytes(orientation));
     jpeg_data.extend_from_slice(&jpeg_buf[2..]);
+    // Our APP1 makes the first image bigger, which an MPF segment has to know about.
+    let size_delta = jpeg_data.len() as isize - jpeg_buf.len() as isize;
+    mpf::fix_up(&mut jpeg_data, size_delta);
     Ok(jpeg_data)
 }
 
@@ -234,76 +613,550 @@ pub async fn process_file(
     out_dir: &Path,
     relative_path: &Path,
     find_type: FindJpegType,
-) -> Result<()> {
-    let jpeg_data = process_file_bytes(entry_path, find_type).await?;
-    let mut output_file = out_dir.join(relative_path);
-    output_file.set_extension("jpg");
+) -> Result<WriteOutcome> {
+    let options = ExtractOptions {
+        find_type,
+        ..Default::default()
+    };
+    process_file_with_options(entry_path, out_dir, relative_path, &options).await
+}
+
+/// Like [`process_file`], but with full control over extraction through [`ExtractOptions`].
+pub async fn process_file_with_options(
+    entry_path: &Path,
+    out_dir: &Path,
+    relative_path: &Path,
+    options: &ExtractOptions,
+) -> Result<WriteOutcome> {
+    let report = process_file_with_report(entry_path, out_dir, relative_path, options).await?;
+    Ok(report.outcome)
+}
+
+/// Like [`process_file_with_options`], but with the details of what was done, for reporting.
+pub async fn process_file_with_report(
+    entry_path: &Path,
+    out_dir: &Path,
+    relative_path: &Path,
+    options: &ExtractOptions,
+) -> Result<FileReport> {
+    extract_and_write(entry_path, out_dir, relative_path, options, None).await
+}
+
+/// Like [`process_file_with_report`], but deciding where to write in `turn`, from
+/// [`OutputClaims::enqueue`]. The turn is given up as soon as that's decided, or if processing
+/// fails first.
+pub async fn process_file_in_turn(
+    entry_path: &Path,
+    out_dir: &Path,
+    relative_path: &Path,
+    options: &ExtractOptions,
+    turn: Turn,
+) -> Result<FileReport> {
+    extract_and_write(entry_path, out_dir, relative_path, options, Some(turn)).await
+}
+
+async fn extract_and_write(
+    entry_path: &Path,
+    out_dir: &Path,
+    relative_path: &Path,
+    options: &ExtractOptions,
+    turn: Option<Turn>,
+) -> Result<FileReport> {
+    // Without a template the output path is known up front, so an existing one can be left alone
+    // without the cost of extracting anything. A JPEG XL preview, or the HEIF one a CR3 may have,
+    // might be chosen instead, which has its own extension, so that needs extracting first. That's
+    // decided by extension, since opening every RAW to look would defeat the point, so anything
+    // which isn't known not to be ISO BMFF based is extracted.
+    let may_be_heif = formats::format_of(entry_path).map_or(true, |format| {
+        format.container == formats::Container::IsoBmff
+    });
+    // What's written is either the preview or, if an earlier run found none, the RAW itself under
+    // its own name, so both are looked for. Unless the output directory is the input one, when
+    // that's just the RAW.
+    if options.name_template.is_none() && !options.allow_jxl && !may_be_heif {
+        let preview_file = untemplated_output(out_dir, relative_path, options.format.extension());
+        let copied_file = out_dir.join(relative_path);
+        for output_file in [preview_file, copied_file] {
+            if output::is_same_file(&output_file, entry_path).await {
+                continue;
+            }
+            let can_skip = output::can_skip_early(
+                &output_file,
+                entry_path,
+                options.overwrite,
+                &options.claims,
+                turn.as_ref(),
+            )
+            .await?;
+            if can_skip {
+                return Ok(FileReport {
+                    outcome: WriteOutcome::Skipped(output_file),
+                    container: None,
+                    preview: None,
+                    dimensions: None,
+                    timings: Vec::new(),
+                    dry_run: options.dry_run,
+                });
+            }
+        }
+    }
+
+    let mut extracted = extract_preview(entry_path, options).await?;
+    if let Some(turn) = &turn {
+        turn.wait().await;
+    }
+    // Held until the preview is written, and removed whatever happens.
+    let (outcome, _lock) = match &options.name_template {
+        Some(template) => {
+            templated_output(
+                entry_path,
+                out_dir,
+                relative_path,
+                template,
+                &extracted,
+                options,
+            )
+            .await?
+        }
+        None => {
+            let output_file = untemplated_output(out_dir, relative_path, &extracted.extension);
+            let outcome =
+                output::resolve(output_file, entry_path, options.overwrite, &options.claims)
+                    .await?;
+            (outcome, None)
+        }
+    };
+    // The next RAW in the queue can decide where its preview goes now.
+    drop(turn);
+    let mut report = FileReport {
+        outcome,
+        container: Some(extracted.container.name()),
+        preview: extracted.preview,
+        dimensions: extracted.dimensions,
+        timings: std::mem::take(&mut extracted.timings),
+        dry_run: options.dry_run,
+    };
+    let Some(output_file) = report.outcome.written() else {
+        return Ok(report);
+    };
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    let start = Instant::now();
     if let Some(parent) = output_file.parent() {
         tokio::fs::create_dir_all(parent).await?;
     }
-    tokio::fs::write(&output_file, &jpeg_data).await?;
-    Ok(())
+    output::write_atomic(output_file, &extracted.data, options.fsync).await?;
+    let mut written = vec![output_file.to_path_buf()];
+
+    for (index, image) in extracted.mpf_images.iter().enumerate() {
+        let stem = output_file
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let mpf_file = output_file.with_file_name(format!("{}-mpf{}.jpg", stem, index + 1));
+        output::write_atomic(&mpf_file, image, options.fsync).await?;
+        written.push(mpf_file);
+    }
+    set_timestamps(
+        entry_path,
+        &extracted.metadata,
+        &written,
+        options.timestamps,
+    )
+    .await?;
+    time(&mut report.timings, "write", start);
+    Ok(report)
+}
+
+/// Set the timestamps of the files written for `entry_path` as asked for. `metadata` is the RAW's,
+/// for the capture time.
+async fn set_timestamps(
+    entry_path: &Path,
+    metadata: &RawMetadata,
+    written: &[PathBuf],
+    timestamps: Timestamps,
+) -> Result<()> {
+    if timestamps == Timestamps::Written {
+        return Ok(());
+    }
+    let source = tokio::fs::metadata(entry_path).await?;
+    let source_mtime = FileTime::from_last_modification_time(&source);
+    // The access time is only changed when copying the RAW's.
+    let (atime, mtime) = match timestamps {
+        Timestamps::Source => (Some(FileTime::from_last_access_time(&source)), source_mtime),
+        _ => {
+            let capture_time = metadata.capture_time();
+            (
+                None,
+                capture_time.map_or(source_mtime, FileTime::from_system_time),
+            )
+        }
+    };
+
+    let written = written.to_vec();
+    tokio::task::spawn_blocking(move || {
+        for path in written {
+            match atime {
+                Some(atime) => filetime::set_file_times(path, atime, mtime)?,
+                None => filetime::set_file_mtime(path, mtime)?,
+            }
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Where a preview with `extension` is written without a name template: the RAW's path relative to
+/// the input directory, with the extension replaced.
+fn untemplated_output(out_dir: &Path, relative_path: &Path, extension: &str) -> PathBuf {
+    let mut output_file = out_dir.join(relative_path);
+    output_file.set_extension(extension);
+    output_file
+}
+
+/// When the photo in `entry_path` was taken, for a name template: from `metadata`, or else the
+/// RAW's modification time.
+async fn template_date(entry_path: &Path, metadata: &RawMetadata) -> Result<Option<NaiveDateTime>> {
+    Ok(match metadata.date() {
+        Some(date) => Some(date),
+        None => tokio::fs::metadata(entry_path)
+            .await?
+            .modified()
+            .ok()
+            .map(|mtime| DateTime::<Local>::from(mtime).naive_local()),
+    })
+}
+
+/// Work out where to write `extracted` with a name template.
+///
+/// If the template has a `{seq}`, the first path which is free is taken, and held with the
+/// returned lock until it's written. A path which an earlier run wrote exactly this preview to is
+/// used again too, and left alone unless the policy is to overwrite, so that reruns don't pile up
+/// numbered copies. With [`OverwritePolicy::Rename`], only free paths are taken.
+async fn templated_output(
+    entry_path: &Path,
+    out_dir: &Path,
+    relative_path: &Path,
+    template: &NameTemplate,
+    extracted: &Extracted,
+    options: &ExtractOptions,
+) -> Result<(WriteOutcome, Option<output::Lock>)> {
+    let metadata = &extracted.metadata;
+    let values = TemplateValues {
+        relative_path,
+        metadata,
+        date: template_date(entry_path, metadata).await?,
+        dimensions: extracted.dimensions,
+    };
+    if !template.has_seq() {
+        let output_file = out_dir.join(template.render(&values, 0, &extracted.extension)?);
+        let outcome =
+            output::resolve(output_file, entry_path, options.overwrite, &options.claims).await?;
+        return Ok((outcome, None));
+    }
+
+    for seq in 1.. {
+        let output_file = out_dir.join(template.render(&values, seq, &extracted.extension)?);
+        let reservation = output::reserve(
+            &output_file,
+            entry_path,
+            &extracted.data,
+            &options.claims,
+            options.dry_run,
+        )
+        .await?;
+        match (reservation, options.overwrite) {
+            (Reservation::Free(lock), _) => return Ok((WriteOutcome::Written(output_file), lock)),
+            (Reservation::Same, OverwritePolicy::Always) => {
+                return Ok((WriteOutcome::Written(output_file), None))
+            }
+            (Reservation::Same, OverwritePolicy::Never | OverwritePolicy::IfNewer) => {
+                return Ok((WriteOutcome::Skipped(output_file), None))
+            }
+            (Reservation::Same, OverwritePolicy::Rename) | (Reservation::Taken, _) => {}
+        }
+    }
+    bail!("No free output path for {}", entry_path.display())
 }
 
 // Process a single RAW file to extract the embedded JPEG and return the JPEG bytes.
 pub async fn process_file_bytes(entry_path: &Path, find_type: FindJpegType) -> Result<Vec<u8>> {
-    println!("Processing file: {}", entry_path.display());
+    let options = ExtractOptions {
+        find_type,
+        ..Default::default()
+    };
+    process_file_bytes_with_options(entry_path, &options).await
+}
+
+/// Like [`process_file_bytes`], but with full control over extraction through [`ExtractOptions`].
+pub async fn process_file_bytes_with_options(
+    entry_path: &Path,
+    options: &ExtractOptions,
+) -> Result<Vec<u8>> {
+    Ok(extract_preview(entry_path, options).await?.data)
+}
+
+/// A RAW's preview as extracting it with some options gives it, along with where it could have been
+/// written, for checking an earlier run's outputs against.
+pub(crate) struct ExpectedOutput {
+    pub data: Vec<u8>,
+    out_dir: PathBuf,
+    relative_path: PathBuf,
+    template: Option<NameTemplate>,
+    metadata: RawMetadata,
+    date: Option<NaiveDateTime>,
+    dimensions: Option<(u32, u32)>,
+    extension: Cow<'static, str>,
+}
+
+impl ExpectedOutput {
+    /// The `n`th path the preview could have been written to, in the order they're tried when
+    /// writing it: where it's wanted first, then the names it's given when that's taken, by
+    /// another RAW or an earlier run.
+    pub fn path(&self, n: u32) -> Result<PathBuf> {
+        let Some(template) = &self.template else {
+            let wanted = untemplated_output(&self.out_dir, &self.relative_path, &self.extension);
+            return Ok(output::with_suffix(&wanted, n));
+        };
+        let values = TemplateValues {
+            relative_path: &self.relative_path,
+            metadata: &self.metadata,
+            date: self.date,
+            dimensions: self.dimensions,
+        };
+        if template.has_seq() {
+            return Ok(self
+                .out_dir
+                .join(template.render(&values, n + 1, &self.extension)?));
+        }
+        let wanted = self
+            .out_dir
+            .join(template.render(&values, 0, &self.extension)?);
+        Ok(output::with_suffix(&wanted, n))
+    }
+}
+
+/// Extract `entry_path`'s preview with `options` without writing it, to find out what an earlier
+/// run with the same options wrote to `out_dir`.
+pub(crate) async fn expected_output(
+    entry_path: &Path,
+    out_dir: &Path,
+    relative_path: &Path,
+    options: &ExtractOptions,
+) -> Result<ExpectedOutput> {
+    let extracted = extract_preview(entry_path, options).await?;
+    let date = match options.name_template {
+        Some(_) => template_date(entry_path, &extracted.metadata).await?,
+        None => None,
+    };
+    Ok(ExpectedOutput {
+        data: extracted.data,
+        out_dir: out_dir.to_path_buf(),
+        relative_path: relative_path.to_path_buf(),
+        template: options.name_template.clone(),
+        metadata: extracted.metadata,
+        date,
+        dimensions: extracted.dimensions,
+        extension: extracted.extension,
+    })
+}
+
+/// A preview extracted from a RAW, ready to write out.
+struct Extracted {
+    data: Vec<u8>,
+    /// The file extension to write `data` with: the RAW's own if it's copied as-is.
+    extension: Cow<'static, str>,
+    /// The secondary images listed in the preview's MPF segment, if they were asked for.
+    mpf_images: Vec<Vec<u8>>,
+    /// The dimensions of the embedded preview, before anything was done to it.
+    dimensions: Option<(u32, u32)>,
+    /// The preview that was found, or `None` if there wasn't one and the RAW was copied as-is.
+    preview: Option<EmbeddedJpegInfo>,
+    container: Container,
+    /// The RAW's metadata, only read if a name template or capture timestamps need it, and empty
+    /// otherwise or if it can't be read.
+    metadata: RawMetadata,
+    timings: Vec<(&'static str, Duration)>,
+}
+
+/// Note how long a stage of extraction took, and keep it for the report.
+fn time(timings: &mut Vec<(&'static str, Duration)>, stage: &'static str, start: Instant) {
+    let elapsed = start.elapsed();
+    verbose!("Time to {}: {:?}", stage, elapsed);
+    timings.push((stage, elapsed));
+}
+
+/// Extract the preview from a RAW, with everything asked for in `options` done to it.
+async fn extract_preview(entry_path: &Path, options: &ExtractOptions) -> Result<Extracted> {
+    verbose!("Processing file: {}", entry_path.display());
+    let mut timings = Vec::new();
     let start = Instant::now();
     let in_file = platform::open_raw(entry_path).await?;
-    println!("Time to open_raw: {:?}", start.elapsed());
+    time(&mut timings, "open_raw", start);
 
     let start = Instant::now();
     let raw_buf = platform::mmap_raw(in_file)?;
-    println!("Time to mmap_raw: {:?}", start.elapsed());
+    time(&mut timings, "mmap_raw", start);
 
-    let start = Instant::now();
-    let tiff_offset_result = find_tiff_header_offset(&raw_buf);
+    // Anything we can't find a preview in, including files which aren't RAWs at all, is copied
+    // as-is, keeping its own extension.
+    let container = Container::detect(&raw_buf);
+    let mut metadata = RawMetadata::default();
+    if options.name_template.is_some() || options.timestamps == Timestamps::Capture {
+        let start = Instant::now();
+        metadata = metadata::metadata_from_raw(&raw_buf, container).unwrap_or_default();
+        time(&mut timings, "read_metadata", start);
+    }
 
-    //    Format	Hex Signature (First Bytes)	ASCII (if readable)	Offset	Notes
-    //JPEG	FF D8 FF	—	0x00	Followed by E0, E1, etc. (APPx markers)
-    //PNG	89 50 4E 47 0D 0A 1A 0A	.PNG....	0x00	8 bytes long, very specific
-    //GIF	47 49 46 38 37 61 or 47 49 46 38 39 61	GIF87a or GIF89a	0x00	Indicates version
-    //TIFF (little-endian)	49 49 2A 00	II*.	0x00	"Intel" byte order
-    //TIFF (big-endian)	4D 4D 00 2A	MM.*	0x00	"Motorola" byte order
-    /*
-         How to Detect BMP and WebP
-    BMP: Always starts with 42 4D (ASCII "BM") — stands for "Bitmap".
+    let start = Instant::now();
+    let jpeg_info =
+        find_largest_embedded_jpeg(&raw_buf, container, options.find_type, options.allow_jxl);
+    time(&mut timings, "find_largest_embedded_jpeg", start);
 
-    WebP:
+    let mut mpf_images = Vec::new();
+    let mut dimensions = None;
+    let mut preview = None;
+    let (jpeg_data, extension) = if let Ok(jpeg_info) = jpeg_info {
+        let start = Instant::now();
 
-    Is stored inside a RIFF container (like WAV/AVI).
+        let jpeg_buf = extract_jpeg(&raw_buf, &jpeg_info)?;
+        time(&mut timings, "extract_jpeg", start);
 
-    First 4 bytes: 52 49 46 46 (ASCII "RIFF")
+        dimensions = jpeg_info.dimensions.or_else(|| match jpeg_info.kind {
+            PreviewKind::Jpeg => resize::dimensions(jpeg_buf),
+            PreviewKind::JpegXl | PreviewKind::Heif => None,
+        });
+        preview = Some(jpeg_info);
 
-    At offset 8: 57 45 42 50 (ASCII "WEBP")
-     */
+        if options.dry_run {
+            let extension = match jpeg_info.kind {
+                PreviewKind::Jpeg => options.format.extension(),
+                kind => kind.extension(),
+            };
+            return Ok(Extracted {
+                data: Vec::new(),
+                extension: extension.into(),
+                mpf_images,
+                dimensions,
+                preview,
+                container,
+                metadata,
+                timings,
+            });
+        }
 
-    if let Err(_) = tiff_offset_result {
-        return Ok(raw_buf.to_vec());
-    }
+        match jpeg_info.kind {
+            PreviewKind::Jpeg => {}
+            PreviewKind::JpegXl => {
+                verbose!("Writing JPEG XL preview as-is");
+                return Ok(Extracted {
+                    data: jpeg_buf.to_vec(),
+                    extension: PreviewKind::JpegXl.extension().into(),
+                    mpf_images,
+                    dimensions,
+                    preview,
+                    container,
+                    metadata,
+                    timings,
+                });
+            }
+            PreviewKind::Heif => {
+                verbose!("Writing HEIF preview as-is");
+                let heic = cr3::wrap_heif(&raw_buf, &jpeg_info, jpeg_buf)?;
+                return Ok(Extracted {
+                    data: heic,
+                    extension: PreviewKind::Heif.extension().into(),
+                    mpf_images,
+                    dimensions,
+                    preview,
+                    container,
+                    metadata,
+                    timings,
+                });
+            }
+        }
 
-    let tiff_offset = tiff_offset_result.unwrap();
+        if options.extract_mpf_images {
+            mpf_images = mpf::secondary_images(jpeg_buf, &raw_buf, jpeg_info.offset)
+                .into_iter()
+                .map(<[u8]>::to_vec)
+                .collect();
+        }
 
-    println!("Offset found at: {}", tiff_offset);
-    println!("Time to find_tiff_header_offset: {:?}", start.elapsed());
+        let mut orientation = jpeg_info.orientation.unwrap_or(1);
 
-    let start = Instant::now();
-    let jpeg_info = find_largest_embedded_jpeg(&raw_buf, tiff_offset, find_type);
-    println!("Time to find_largest_embedded_jpeg: {:?}", start.elapsed());
+        if options.format != OutputFormat::Jpeg {
+            let start = Instant::now();
+            let max_size = options.resize.as_ref().map(|r| r.max_size);
+            let data = encode::encode_preview(jpeg_buf, orientation, options.format, max_size)?;
+            time(&mut timings, "encode_preview", start);
+            return Ok(Extracted {
+                data,
+                extension: options.format.extension().into(),
+                mpf_images,
+                dimensions,
+                preview,
+                container,
+                metadata,
+                timings,
+            });
+        }
 
-    let jpeg_data = if let Ok(jpeg_info) = jpeg_info {
-        let start = Instant::now();
+        let mut jpeg_buf = Cow::Borrowed(jpeg_buf);
+        // A preview which is downscaled is re-encoded anyway, so it's rotated along the way rather
+        // than losslessly at full size first, which would be wasted work.
+        if let Some(resize_options) = &options.resize {
+            let start = Instant::now();
+            let rotate = if options.apply_orientation {
+                orientation
+            } else {
+                1
+            };
+            if let Some(resized) = resize::downscale_oriented(&jpeg_buf, resize_options, rotate)? {
+                jpeg_buf = Cow::Owned(resized);
+                if options.apply_orientation {
+                    orientation = 1;
+                }
+            }
+            time(&mut timings, "downscale", start);
+        }
 
-        let jpeg_buf = extract_jpeg(&raw_buf, &jpeg_info)?;
-        println!("Time to extract_jpeg: {:?}", start.elapsed());
+        if options.apply_orientation && orientation != 1 {
+            let start = Instant::now();
+            match transform::apply_orientation(&jpeg_buf, orientation) {
+                Ok(rotated) => {
+                    jpeg_buf = Cow::Owned(rotated);
+                    orientation = 1;
+                }
+                Err(e) => verbose!("Keeping EXIF orientation, cannot transform JPEG: {}", e),
+            }
+            time(&mut timings, "apply_orientation", start);
+        }
 
         let start = Instant::now();
-        let jpeg_data = get_jpeg_data(jpeg_buf, &jpeg_info).await?;
-        println!("Time to get_jpeg_data: {:?}", start.elapsed());
-        jpeg_data
+        let jpeg_data = get_jpeg_data(&jpeg_buf, orientation).await?;
+        time(&mut timings, "get_jpeg_data", start);
+        (jpeg_data, options.format.extension().into())
     } else {
-        raw_buf.to_vec()
+        // An input without an extension is copied without one, rather than given a made up one.
+        let extension = entry_path.extension().unwrap_or_default().to_string_lossy();
+        let data = match options.dry_run {
+            true => Vec::new(),
+            false => raw_buf.to_vec(),
+        };
+        (data, Cow::Owned(extension.into_owned()))
     };
 
-    Ok(jpeg_data)
+    Ok(Extracted {
+        data: jpeg_data,
+        extension,
+        mpf_images,
+        dimensions,
+        preview,
+        container,
+        metadata,
+        timings,
+    })
 }
@@ -27,7 +27,9 @@ pub async fn open_raw(path: &Path) -> Result<File> {
 
 pub fn prefetch_jpeg(raw_buf: &Mmap, jpeg: &EmbeddedJpegInfo) -> Result<()> {
     ensure!(
-        jpeg.offset + jpeg.length <= raw_buf.len(),
+        jpeg.offset
+            .checked_add(jpeg.length)
+            .is_some_and(|end| end <= raw_buf.len()),
         "JPEG data is out of bounds"
     );
 
@@ -0,0 +1,132 @@
+//! Machine readable reports of a run, as JSON Lines with one object per RAW.
+//!
+//! Every line has `input` and `status`, which is one of:
+//!
+//! - `ok`: the preview was written to `output`.
+//! - `renamed`: like `ok`, but `wanted` was already taken.
+//! - `skipped`: `output` already existed, and was kept.
+//! - `no-preview`: no preview was found, so the RAW was copied as-is to `output`. If that was
+//!   taken, `wanted` is there too, as for `renamed`.
+//! - `unchanged`: unchanged since the last incremental run.
+//! - `error`: with `error_kind` (`io` or `invalid`) and the `error` message.
+//!
+//! In a dry run, every line for a processed RAW has `dry_run` set to true, and nothing was actually
+//! written: `output` is only where it would have been.
+//!
+//! When a preview was found, `container`, `preview_kind`, `preview_offset`, `preview_length`,
+//! `orientation`, `width` and `height` describe it, as far as they're known. `durations_ms` has
+//! how long each stage took.
+
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::output::WriteOutcome;
+use crate::parser::{FileReport, PreviewKind};
+
+/// Writes a report line by line, as each RAW is done.
+pub struct ReportWriter<W: Write> {
+    out: W,
+}
+
+impl ReportWriter<BufWriter<File>> {
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+fn error_kind(error: &anyhow::Error) -> &'static str {
+    if error
+        .chain()
+        .any(|cause| cause.downcast_ref::<std::io::Error>().is_some())
+    {
+        "io"
+    } else {
+        "invalid"
+    }
+}
+
+impl<W: Write> ReportWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    fn write_line(&mut self, line: Map<String, Value>) -> Result<()> {
+        serde_json::to_writer(&mut self.out, &line)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Report a RAW which was processed.
+    pub fn processed(&mut self, input: &Path, report: &FileReport) -> Result<()> {
+        let mut line = Map::new();
+        line.insert("input".into(), json!(input.to_string_lossy()));
+        let (status, output) = match &report.outcome {
+            WriteOutcome::Skipped(output) => ("skipped", output),
+            WriteOutcome::Written(output) if report.preview.is_none() => ("no-preview", output),
+            WriteOutcome::Written(output) => ("ok", output),
+            WriteOutcome::Renamed { wanted, written } => {
+                line.insert("wanted".into(), json!(wanted.to_string_lossy()));
+                match report.preview {
+                    Some(_) => ("renamed", written),
+                    None => ("no-preview", written),
+                }
+            }
+        };
+        line.insert("status".into(), json!(status));
+        line.insert("output".into(), json!(output.to_string_lossy()));
+        if report.dry_run {
+            line.insert("dry_run".into(), json!(true));
+        }
+
+        if let Some(container) = report.container {
+            line.insert("container".into(), json!(container));
+        }
+        if let Some(preview) = &report.preview {
+            let kind = match preview.kind() {
+                PreviewKind::Jpeg => "jpeg",
+                PreviewKind::JpegXl => "jxl",
+                PreviewKind::Heif => "heif",
+            };
+            line.insert("preview_kind".into(), json!(kind));
+            line.insert("preview_offset".into(), json!(preview.offset()));
+            line.insert("preview_length".into(), json!(preview.length()));
+            line.insert("orientation".into(), json!(preview.orientation()));
+        }
+        if let Some((width, height)) = report.dimensions {
+            line.insert("width".into(), json!(width));
+            line.insert("height".into(), json!(height));
+        }
+        let durations: Map<_, _> = report
+            .timings
+            .iter()
+            .map(|(stage, duration)| (stage.to_string(), json!(duration.as_secs_f64() * 1000.0)))
+            .collect();
+        line.insert("durations_ms".into(), Value::Object(durations));
+        self.write_line(line)
+    }
+
+    /// Report a RAW which was left alone because it hasn't changed since the last incremental run.
+    pub fn unchanged(&mut self, input: &Path) -> Result<()> {
+        let mut line = Map::new();
+        line.insert("input".into(), json!(input.to_string_lossy()));
+        line.insert("status".into(), json!("unchanged"));
+        self.write_line(line)
+    }
+
+    /// Report a RAW which couldn't be processed.
+    pub fn error(&mut self, input: &Path, error: &anyhow::Error) -> Result<()> {
+        let mut line = Map::new();
+        line.insert("input".into(), json!(input.to_string_lossy()));
+        line.insert("status".into(), json!("error"));
+        line.insert("error_kind".into(), json!(error_kind(error)));
+        line.insert("error".into(), json!(format!("{:#}", error)));
+        self.write_line(line)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
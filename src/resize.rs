@@ -0,0 +1,203 @@
+//! Downscaling of extracted previews, for galleries and the like which only need a thumbnail.
+//!
+//! Decoding a full size preview only to throw most of it away is wasteful, so we lean on the
+//! decoder's DCT scaling to get within a factor of two of the target size for free, and only then
+//! do a proper area averaging resize for the rest.
+
+use anyhow::{bail, ensure, Result};
+use jpeg_decoder::{Decoder, PixelFormat};
+use jpeg_encoder::{ColorType, Encoder};
+
+use crate::encode;
+
+/// How to downscale an extracted preview.
+#[derive(Clone, Debug)]
+pub struct ResizeOptions {
+    /// The maximum length of the long edge, in pixels.
+    pub max_size: u32,
+    /// JPEG quality to re-encode at, from 1 to 100.
+    pub quality: u8,
+}
+
+impl Default for ResizeOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 1024,
+            quality: 85,
+        }
+    }
+}
+
+/// Decoded 8 bit pixels, either grayscale or RGB.
+pub(crate) struct Pixels {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub data: Vec<u8>,
+}
+
+/// Scale (width, height) down so that the long edge is at most `max_size`, keeping the aspect
+/// ratio. Returns `None` if it already fits.
+fn fit(width: usize, height: usize, max_size: usize) -> Option<(usize, usize)> {
+    let long = width.max(height);
+    if long <= max_size {
+        return None;
+    }
+    let scale = |v: usize| ((v * max_size + long / 2) / long).max(1);
+    Some((scale(width), scale(height)))
+}
+
+/// Decode a JPEG, using DCT scaling to get as close to `target` as possible without going under.
+pub(crate) fn decode(jpeg: &[u8], target: Option<(usize, usize)>) -> Result<Pixels> {
+    let mut decoder = Decoder::new(jpeg);
+    if let Some((w, h)) = target {
+        decoder.scale(w.try_into()?, h.try_into()?)?;
+    }
+    let data = decoder.decode()?;
+    let Some(info) = decoder.info() else {
+        bail!("JPEG has no frame");
+    };
+    let channels = match info.pixel_format {
+        PixelFormat::L8 => 1,
+        PixelFormat::RGB24 => 3,
+        other => bail!("Unsupported JPEG pixel format: {:?}", other),
+    };
+    Ok(Pixels {
+        width: info.width.into(),
+        height: info.height.into(),
+        channels,
+        data,
+    })
+}
+
+/// Read a JPEG's dimensions from its frame header, without decoding it.
+pub(crate) fn dimensions(jpeg: &[u8]) -> Option<(u32, u32)> {
+    let mut decoder = Decoder::new(jpeg);
+    decoder.read_info().ok()?;
+    let info = decoder.info()?;
+    Some((info.width.into(), info.height.into()))
+}
+
+/// Get, for each output position along one axis, the source positions it covers and how much
+/// each contributes.
+fn area_weights(src: usize, dst: usize) -> Vec<Vec<(usize, f32)>> {
+    let ratio = src as f32 / dst as f32;
+    (0..dst)
+        .map(|i| {
+            let start = i as f32 * ratio;
+            let end = (start + ratio).min(src as f32);
+            let mut weights = Vec::new();
+            let mut pos = start;
+            while pos < end - f32::EPSILON {
+                let idx = (pos.floor() as usize).min(src - 1);
+                let next = ((idx + 1) as f32).min(end);
+                weights.push((idx, (next - pos) / ratio));
+                pos = next;
+            }
+            weights
+        })
+        .collect()
+}
+
+/// Resize with a box filter. We only ever downscale, where this gives good results cheaply.
+pub(crate) fn resize(src: &Pixels, width: usize, height: usize) -> Pixels {
+    let ch = src.channels;
+    let xw = area_weights(src.width, width);
+    let yw = area_weights(src.height, height);
+
+    // Horizontal pass, then vertical.
+    let mut tmp = vec![0f32; width * src.height * ch];
+    for y in 0..src.height {
+        let row = &src.data[y * src.width * ch..][..src.width * ch];
+        for (x, weights) in xw.iter().enumerate() {
+            for &(sx, w) in weights {
+                for c in 0..ch {
+                    tmp[(y * width + x) * ch + c] += f32::from(row[sx * ch + c]) * w;
+                }
+            }
+        }
+    }
+
+    let mut data = vec![0u8; width * height * ch];
+    for (y, weights) in yw.iter().enumerate() {
+        for x in 0..width {
+            for c in 0..ch {
+                let v: f32 = weights
+                    .iter()
+                    .map(|&(sy, w)| tmp[(sy * width + x) * ch + c] * w)
+                    .sum();
+                data[(y * width + x) * ch + c] = v.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+
+    Pixels {
+        width,
+        height,
+        channels: ch,
+        data,
+    }
+}
+
+/// Decode and scale a JPEG down so that its long edge fits in `max_size`. Returns `None` if it
+/// already fits.
+pub(crate) fn decode_fitted(jpeg: &[u8], max_size: u32) -> Result<Option<Pixels>> {
+    ensure!(max_size > 0, "Maximum size must be positive");
+    let mut decoder = Decoder::new(jpeg);
+    decoder.read_info()?;
+    let Some(info) = decoder.info() else {
+        bail!("JPEG has no frame");
+    };
+    let Some((w, h)) = fit(info.width.into(), info.height.into(), max_size.try_into()?) else {
+        return Ok(None);
+    };
+    let pixels = decode(jpeg, Some((w, h)))?;
+    if (w, h) == (pixels.width, pixels.height) {
+        return Ok(Some(pixels));
+    }
+    Ok(Some(resize(&pixels, w, h)))
+}
+
+/// Encode pixels as a baseline JPEG.
+pub(crate) fn encode_jpeg(pixels: &Pixels, quality: u8) -> Result<Vec<u8>> {
+    let color = match pixels.channels {
+        1 => ColorType::Luma,
+        _ => ColorType::Rgb,
+    };
+    let mut out = Vec::new();
+    Encoder::new(&mut out, quality).encode(
+        &pixels.data,
+        pixels.width.try_into()?,
+        pixels.height.try_into()?,
+        color,
+    )?;
+    Ok(out)
+}
+
+/// Downscale a JPEG so that its long edge is no longer than `options.max_size`.
+///
+/// Returns `None` if the JPEG already fits, in which case it should just be used as is rather than
+/// needlessly losing quality to a re-encode.
+pub fn downscale(jpeg: &[u8], options: &ResizeOptions) -> Result<Option<Vec<u8>>> {
+    downscale_oriented(jpeg, options, 1)
+}
+
+/// Like [`downscale`], but applying `orientation` to the pixels before they're re-encoded, so that
+/// the result displays correctly without it.
+pub(crate) fn downscale_oriented(
+    jpeg: &[u8],
+    options: &ResizeOptions,
+    orientation: u16,
+) -> Result<Option<Vec<u8>>> {
+    ensure!(
+        (1..=100).contains(&options.quality),
+        "JPEG quality must be between 1 and 100"
+    );
+    let Some(pixels) = decode_fitted(jpeg, options.max_size)? else {
+        return Ok(None);
+    };
+    Ok(Some(encode_jpeg(
+        &encode::orient(pixels, orientation),
+        options.quality,
+    )?))
+}
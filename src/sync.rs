@@ -0,0 +1,294 @@
+//! Incremental runs, which only extract RAWs that are new or have changed since the last one.
+//!
+//! What was extracted last time is kept in [`STATE_FILE`] in the output directory, keyed by each
+//! RAW's relative path: where it was read from, its size and modification time, and optionally a
+//! fingerprint of its contents, along with the outputs written for it and the options they were
+//! written with. A RAW read from the same place whose size and modification time match, and whose
+//! outputs are all still there and were written with the same options, is left alone. With fingerprints, a RAW which was only touched, like by
+//! copying the archive somewhere without preserving times, is recognised as unchanged too, at the
+//! cost of reading it.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::inputs;
+use crate::output::{self, WriteOutcome};
+use crate::parser::ExtractOptions;
+
+/// The name of the state file in the output directory.
+pub const STATE_FILE: &str = ".jpgfromraw-state.json";
+
+const STATE_VERSION: u32 = 3;
+
+/// What's known about one RAW from when it was last extracted.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// The RAW's absolute path, since a different RAW can have the same relative path in another
+    /// run, like one with the same name in a different input directory.
+    pub source: String,
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub mtime_ns: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// The outputs written for it, relative to the output directory.
+    pub outputs: Vec<String>,
+    /// A fingerprint of the options which shaped the outputs, from [`options_fingerprint`].
+    pub options: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    files: BTreeMap<String, Record>,
+}
+
+/// The state of an output directory, keyed by each RAW's path relative to the input directory.
+#[derive(Debug, Default)]
+pub struct SyncState {
+    files: BTreeMap<String, Record>,
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Continue a 64-bit FNV-1a hash with `bytes`.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// A 64-bit FNV-1a hash of the file's contents.
+async fn fingerprint(path: &Path) -> Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let mut buf = vec![0; 1 << 20];
+        let mut hash = FNV_OFFSET_BASIS;
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hash = fnv1a(hash, &buf[..read]);
+        }
+        Ok(format!("{:016x}", hash))
+    })
+    .await?
+}
+
+/// A fingerprint of everything in `options` which changes what's written, so that RAWs are
+/// extracted again when those change, rather than keeping outputs from different options.
+pub fn options_fingerprint(options: &ExtractOptions) -> String {
+    let relevant = format!(
+        "{:?}",
+        (
+            options.find_type,
+            options.apply_orientation,
+            &options.resize,
+            options.format,
+            options.allow_jxl,
+            options.extract_mpf_images,
+            &options.name_template,
+            options.timestamps,
+        )
+    );
+    format!("{:016x}", fnv1a(FNV_OFFSET_BASIS, relevant.as_bytes()))
+}
+
+/// How `input` is recorded in [`Record::source`].
+fn source(input: &Path) -> Result<String> {
+    Ok(inputs::absolute(input)?.to_string_lossy().into_owned())
+}
+
+/// The size and modification time of `path`.
+async fn stat(path: &Path) -> Result<(u64, u64)> {
+    let metadata = tokio::fs::metadata(path).await?;
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok((metadata.len(), mtime.as_nanos().try_into()?))
+}
+
+impl Record {
+    /// Make a record for `input`, which has just been extracted to `outputs` with `options`.
+    pub async fn new(
+        input: &Path,
+        out_dir: &Path,
+        outputs: &[&Path],
+        options: &ExtractOptions,
+        with_fingerprint: bool,
+    ) -> Result<Self> {
+        let source = source(input)?;
+        let (size, mtime_ns) = stat(input).await?;
+        let fingerprint = match with_fingerprint {
+            true => Some(fingerprint(input).await?),
+            false => None,
+        };
+        let outputs = outputs
+            .iter()
+            .map(|output| {
+                let relative = output.strip_prefix(out_dir).unwrap_or(output);
+                relative.to_string_lossy().into_owned()
+            })
+            .collect();
+        Ok(Self {
+            source,
+            size,
+            mtime_ns,
+            fingerprint,
+            outputs,
+            options: options_fingerprint(options),
+        })
+    }
+
+    /// Make a record for `input` from what extracting it did. A file which was kept rather than
+    /// written, because it was already there, isn't counted as an output, since it isn't ours to
+    /// delete later.
+    pub async fn from_outcome(
+        input: &Path,
+        out_dir: &Path,
+        outcome: &WriteOutcome,
+        options: &ExtractOptions,
+        with_fingerprint: bool,
+    ) -> Result<Self> {
+        let outputs: Vec<_> = outcome.written().into_iter().collect();
+        Self::new(input, out_dir, &outputs, options, with_fingerprint).await
+    }
+
+    /// Whether this record was made for `input`, rather than for another RAW which had the same
+    /// relative path.
+    pub fn is_for(&self, input: &Path) -> bool {
+        source(input).is_ok_and(|source| source == self.source)
+    }
+
+    /// Whether `input` is unchanged since this record was made, and its outputs are still there
+    /// and would be written the same with `options`.
+    /// If so, the record to keep for it is returned, which may have a new modification time if it
+    /// was only recognised by its fingerprint.
+    pub async fn check(
+        &self,
+        input: &Path,
+        out_dir: &Path,
+        options: &ExtractOptions,
+        with_fingerprint: bool,
+    ) -> Result<Option<Self>> {
+        if !self.is_for(input) || self.options != options_fingerprint(options) {
+            return Ok(None);
+        }
+        for output in &self.outputs {
+            if tokio::fs::metadata(out_dir.join(output)).await.is_err() {
+                return Ok(None);
+            }
+        }
+
+        let (size, mtime_ns) = stat(input).await?;
+        if size != self.size {
+            return Ok(None);
+        }
+        if mtime_ns == self.mtime_ns {
+            return Ok(Some(self.clone()));
+        }
+        match (&self.fingerprint, with_fingerprint) {
+            (Some(previous), true) if *previous == fingerprint(input).await? => Ok(Some(Self {
+                mtime_ns,
+                ..self.clone()
+            })),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl SyncState {
+    /// Load the state from `out_dir`, which is empty if there isn't any yet.
+    pub async fn load(out_dir: &Path) -> Result<Self> {
+        let path = out_dir.join(STATE_FILE);
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let state: StateFile = serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid state file {}", path.display()))?;
+        // Anything else is from a different version, and it's always safe to start over.
+        if state.version != STATE_VERSION {
+            return Ok(Self::default());
+        }
+        Ok(Self { files: state.files })
+    }
+
+    /// Save the state to `out_dir`.
+    pub async fn save(&self, out_dir: &Path) -> Result<()> {
+        let state = StateFile {
+            version: STATE_VERSION,
+            files: self.files.clone(),
+        };
+        let data = serde_json::to_vec(&state)?;
+        output::write_atomic(&out_dir.join(STATE_FILE), &data, false).await
+    }
+
+    pub fn get(&self, relative_path: &Path) -> Option<&Record> {
+        self.files.get(&*relative_path.to_string_lossy())
+    }
+
+    pub fn insert(&mut self, relative_path: &Path, record: Record) {
+        self.files
+            .insert(relative_path.to_string_lossy().into_owned(), record);
+    }
+
+    /// Forget about a RAW, so that it's extracted next time.
+    pub fn remove(&mut self, relative_path: &Path) {
+        self.files.remove(&*relative_path.to_string_lossy());
+    }
+
+    /// Delete the outputs of every RAW which isn't in `inputs` any more, along with any MPF
+    /// images written next to them, and forget about those RAWs. Outputs which a remaining RAW
+    /// was written to since are kept. Returns the deleted files.
+    pub async fn remove_orphans(
+        &mut self,
+        out_dir: &Path,
+        inputs: &HashSet<PathBuf>,
+    ) -> Result<Vec<PathBuf>> {
+        let inputs: HashSet<_> = inputs
+            .iter()
+            .map(|input| input.to_string_lossy().into_owned())
+            .collect();
+        let orphans: Vec<_> = self
+            .files
+            .keys()
+            .filter(|input| !inputs.contains(*input))
+            .cloned()
+            .collect();
+        let kept: HashSet<_> = self
+            .files
+            .iter()
+            .filter(|(input, _)| inputs.contains(*input))
+            .flat_map(|(_, record)| &record.outputs)
+            .cloned()
+            .collect();
+
+        let mut deleted = Vec::new();
+        for orphan in orphans {
+            let record = self.files.remove(&orphan).expect("key from map");
+            for output in &record.outputs {
+                if kept.contains(output) {
+                    continue;
+                }
+                let output = out_dir.join(output);
+                let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+                let mpf_images =
+                    (1..).map(|n| output.with_file_name(format!("{}-mpf{}.jpg", stem, n)));
+                for path in std::iter::once(output.clone()).chain(mpf_images) {
+                    match tokio::fs::remove_file(&path).await {
+                        Ok(()) => deleted.push(path),
+                        Err(e) if e.kind() == ErrorKind::NotFound => break,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+        }
+        Ok(deleted)
+    }
+}
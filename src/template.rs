@@ -0,0 +1,279 @@
+//! Output path templates, like `{date:%Y/%m/%d}/{model}_{stem}_{seq}.jpg`.
+//!
+//! Placeholders are in braces, and `{{` and `}}` are literal braces:
+//!
+//! - `{stem}`: the RAW's file name without its extension.
+//! - `{dir}`: the RAW's directory, relative to the input directory, or empty for a RAW given
+//!   directly.
+//! - `{ext}`: the RAW's extension.
+//! - `{date}`, or `{date:FORMAT}` with a strftime format: when the photo was taken, from
+//!   DateTimeOriginal, or the RAW's modification time if it doesn't have one. The default format
+//!   is `%Y-%m-%d`.
+//! - `{make}`, `{model}`, `{lens}`: the camera and lens.
+//! - `{width}`, `{height}`: the embedded preview's dimensions, before any resizing.
+//! - `{seq}`, or `{seq:WIDTH}` to zero pad it: a counter, starting at 1, which is incremented
+//!   until the path doesn't collide with an existing file, other than one holding exactly the same
+//!   preview from an earlier run.
+//!
+//! `/` in the template separates directories. An image extension at the end of the template, like
+//! `.jpg`, is replaced with the one for the format actually written, so it's fine even with
+//! `--format png`. Anything else is kept as part of the name. Values which aren't known for a file
+//! are written as `unknown`.
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::format::{Item, StrftimeItems};
+use chrono::NaiveDateTime;
+use std::fmt::Write as _;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::parser::metadata::RawMetadata;
+
+const UNKNOWN: &str = "unknown";
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Extensions which a template can end with to no effect, since one for the format actually
+/// written is always added.
+const OUTPUT_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp", "jxl", "heic"];
+
+/// The template for [`NameTemplate::by_date`].
+const BY_DATE: &str = "{date:%Y}/{date:%Y-%m-%d}/{stem}";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Field {
+    Stem,
+    Dir,
+    Ext,
+    Date(String),
+    Make,
+    Model,
+    Lens,
+    Width,
+    Height,
+    Seq(usize),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// A parsed output path template.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+/// Everything a template can refer to for one RAW.
+#[derive(Clone, Copy, Debug)]
+pub struct TemplateValues<'a> {
+    /// The RAW's path, relative to the input directory.
+    pub relative_path: &'a Path,
+    pub metadata: &'a RawMetadata,
+    /// When the photo was taken.
+    pub date: Option<NaiveDateTime>,
+    /// The embedded preview's width and height.
+    pub dimensions: Option<(u32, u32)>,
+}
+
+fn parse_field(spec: &str) -> Result<Field> {
+    let (name, arg) = match spec.split_once(':') {
+        Some((name, arg)) => (name, Some(arg)),
+        None => (spec, None),
+    };
+    let field = match (name, arg) {
+        ("stem", None) => Field::Stem,
+        ("dir", None) => Field::Dir,
+        ("ext", None) => Field::Ext,
+        ("make", None) => Field::Make,
+        ("model", None) => Field::Model,
+        ("lens", None) => Field::Lens,
+        ("width", None) => Field::Width,
+        ("height", None) => Field::Height,
+        ("date", format) => {
+            let format = format.unwrap_or(DEFAULT_DATE_FORMAT);
+            ensure!(
+                !StrftimeItems::new(format).any(|item| matches!(item, Item::Error)),
+                "Invalid date format: {}",
+                format
+            );
+            Field::Date(format.to_string())
+        }
+        ("seq", width) => Field::Seq(match width {
+            Some(width) => width
+                .parse()
+                .with_context(|| format!("Invalid seq width: {}", width))?,
+            None => 0,
+        }),
+        _ => bail!("Unknown placeholder: {{{}}}", spec),
+    };
+    Ok(field)
+}
+
+/// Replace the characters which aren't allowed in file names everywhere, including `/`.
+fn replace_unsafe(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Make a value safe to use as (part of) a single path component.
+fn sanitise(value: &str) -> String {
+    let value = replace_unsafe(value);
+    match value.trim() {
+        "" => UNKNOWN.to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => bail!("Unterminated placeholder in name template"),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(parse_field(&spec)?));
+                }
+                '}' => bail!("Unmatched '}}' in name template"),
+                c => literal.push(c),
+            }
+        }
+
+        // Drop an output extension, since it's always the one for the format written. Anything
+        // else after a '.', like the 2 in `_v1.2`, is part of the name.
+        if let Some(dot) = literal.rfind('.') {
+            let extension = &literal[dot + 1..];
+            if OUTPUT_EXTENSIONS
+                .iter()
+                .any(|known| extension.eq_ignore_ascii_case(known))
+            {
+                literal.truncate(dot);
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        ensure!(!parts.is_empty(), "Empty name template");
+        Ok(Self { parts })
+    }
+
+    /// Sort previews into `YYYY/YYYY-MM-DD/` folders by when they were taken, keeping the RAW's
+    /// file name.
+    pub fn by_date() -> Self {
+        Self::parse(BY_DATE).expect("built in template is valid")
+    }
+
+    /// Whether the template has a `{seq}` placeholder, so can be rendered again to avoid
+    /// collisions.
+    pub fn has_seq(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Field(Field::Seq(_))))
+    }
+
+    /// Render the template into a path relative to the output directory, with `extension`, or
+    /// none if that's empty.
+    pub fn render(&self, values: &TemplateValues, seq: u32, extension: &str) -> Result<PathBuf> {
+        let path = values.relative_path;
+        let os_str = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().into_owned());
+        let text = |value: Option<&str>| sanitise(value.unwrap_or_default());
+
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Field(field) => match field {
+                    Field::Stem => rendered.push_str(&text(os_str(path.file_stem()).as_deref())),
+                    Field::Ext => rendered.push_str(&text(os_str(path.extension()).as_deref())),
+                    Field::Dir => {
+                        if let Some(parent) = path.parent() {
+                            rendered.push_str(&parent.to_string_lossy());
+                        }
+                    }
+                    Field::Date(format) => match values.date {
+                        // A format like %H:%M would otherwise put a ':' in the name, but any '/'
+                        // in it is meant to separate directories.
+                        Some(date) => {
+                            let date = date.format(format).to_string();
+                            let components: Vec<_> = date.split('/').map(replace_unsafe).collect();
+                            rendered.push_str(&components.join("/"));
+                        }
+                        None => rendered.push_str(UNKNOWN),
+                    },
+                    Field::Make => rendered.push_str(&text(values.metadata.make.as_deref())),
+                    Field::Model => rendered.push_str(&text(values.metadata.model.as_deref())),
+                    Field::Lens => {
+                        rendered.push_str(&text(values.metadata.lens_model.as_deref()));
+                    }
+                    Field::Width | Field::Height => match values.dimensions {
+                        Some((width, height)) => {
+                            let value = if *field == Field::Width {
+                                width
+                            } else {
+                                height
+                            };
+                            write!(rendered, "{}", value)?;
+                        }
+                        None => rendered.push_str(UNKNOWN),
+                    },
+                    Field::Seq(width) => write!(rendered, "{:0width$}", seq, width = *width)?,
+                },
+            }
+        }
+
+        // Only ever produce a path inside the output directory, whatever the values were.
+        let mut out = PathBuf::new();
+        for component in Path::new(&rendered).components() {
+            match component {
+                Component::Normal(name) => out.push(name),
+                Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+                Component::ParentDir => bail!("Name template produced '..': {}", rendered),
+            }
+        }
+        let file_name = out
+            .file_name()
+            .context("Name template produced an empty file name")?
+            .to_string_lossy()
+            .into_owned();
+        // A RAW without an extension which is copied as-is doesn't get one.
+        if !extension.is_empty() {
+            out.set_file_name(format!("{}.{}", file_name, extension));
+        }
+        Ok(out)
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
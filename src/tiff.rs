@@ -0,0 +1,581 @@
+//! A small zero-copy reader for TIFF structures, as used by nearly every RAW format.
+//!
+//! Everything borrows from the underlying buffer, which is usually a memory map of the whole RAW,
+//! and only the bytes actually asked for are ever touched. That matters: reading a tag shouldn't
+//! fault in pages of sensor data around it.
+//!
+//! All offsets are relative to the start of the buffer given to [`IfdReader::new`], which is where
+//! the TIFF header lives. MakerNotes which use some other base can be read by creating an
+//! [`IfdReader`] over a different slice with [`IfdReader::with_byte_order`].
+//!
+//! BigTIFF, with its 64-bit counts and offsets, is read through the same types, so callers only
+//! need to use [`Entry::usize`] or [`Entry::u64s`] for offsets rather than the 32-bit accessors.
+
+use anyhow::{bail, ensure, Context, Result};
+use byteorder::{BigEndian, ByteOrder as _, LittleEndian};
+use serde::Serialize;
+
+/// Stop following an IFD chain after this many IFDs, in case of loops we failed to spot.
+const MAX_IFDS: usize = 256;
+
+const IFD_ENTRY_SIZE: usize = 12;
+const BIGTIFF_IFD_ENTRY_SIZE: usize = 20;
+
+/// The byte order of a TIFF structure.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+impl ByteOrder {
+    /// Parse a byte order marker, "II" or "MM", as used in TIFF headers and some MakerNotes.
+    pub fn from_marker(marker: &[u8]) -> Option<Self> {
+        match marker {
+            b"II" => Some(Self::LittleEndian),
+            b"MM" => Some(Self::BigEndian),
+            _ => None,
+        }
+    }
+
+    pub fn read_u16(self, buf: &[u8]) -> u16 {
+        match self {
+            Self::LittleEndian => LittleEndian::read_u16(buf),
+            Self::BigEndian => BigEndian::read_u16(buf),
+        }
+    }
+
+    pub fn read_u32(self, buf: &[u8]) -> u32 {
+        match self {
+            Self::LittleEndian => LittleEndian::read_u32(buf),
+            Self::BigEndian => BigEndian::read_u32(buf),
+        }
+    }
+
+    pub fn read_u64(self, buf: &[u8]) -> u64 {
+        match self {
+            Self::LittleEndian => LittleEndian::read_u64(buf),
+            Self::BigEndian => BigEndian::read_u64(buf),
+        }
+    }
+}
+
+/// The type of an IFD entry's value.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FieldType {
+    Byte,
+    Ascii,
+    Short,
+    Long,
+    Rational,
+    SByte,
+    Undefined,
+    SShort,
+    SLong,
+    SRational,
+    Float,
+    Double,
+    Ifd,
+    Long8,
+    SLong8,
+    Ifd8,
+    Unknown(u16),
+}
+
+impl FieldType {
+    pub fn from_u16(typ: u16) -> Self {
+        match typ {
+            1 => Self::Byte,
+            2 => Self::Ascii,
+            3 => Self::Short,
+            4 => Self::Long,
+            5 => Self::Rational,
+            6 => Self::SByte,
+            7 => Self::Undefined,
+            8 => Self::SShort,
+            9 => Self::SLong,
+            10 => Self::SRational,
+            11 => Self::Float,
+            12 => Self::Double,
+            13 => Self::Ifd,
+            16 => Self::Long8,
+            17 => Self::SLong8,
+            18 => Self::Ifd8,
+            other => Self::Unknown(other),
+        }
+    }
+
+    /// The size of one value of this type in bytes, or `None` for types we don't know.
+    pub const fn size(self) -> Option<usize> {
+        match self {
+            Self::Byte | Self::Ascii | Self::SByte | Self::Undefined => Some(1),
+            Self::Short | Self::SShort => Some(2),
+            Self::Long | Self::SLong | Self::Float | Self::Ifd => Some(4),
+            Self::Rational | Self::SRational | Self::Double => Some(8),
+            Self::Long8 | Self::SLong8 | Self::Ifd8 => Some(8),
+            Self::Unknown(_) => None,
+        }
+    }
+}
+
+/// An unsigned TIFF RATIONAL.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Rational {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+impl Rational {
+    /// The value as a float, or `None` if the denominator is zero.
+    pub fn to_f64(self) -> Option<f64> {
+        (self.denominator != 0).then(|| f64::from(self.numerator) / f64::from(self.denominator))
+    }
+}
+
+/// A signed TIFF SRATIONAL.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SRational {
+    pub numerator: i32,
+    pub denominator: i32,
+}
+
+impl SRational {
+    /// The value as a float, or `None` if the denominator is zero.
+    pub fn to_f64(self) -> Option<f64> {
+        (self.denominator != 0).then(|| f64::from(self.numerator) / f64::from(self.denominator))
+    }
+}
+
+/// A decoded IFD entry value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value<'a> {
+    Byte(&'a [u8]),
+    /// ASCII data, with the trailing NUL (and anything after it) removed.
+    Ascii(&'a [u8]),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<Rational>),
+    SByte(Vec<i8>),
+    Undefined(&'a [u8]),
+    SShort(Vec<i16>),
+    SLong(Vec<i32>),
+    SRational(Vec<SRational>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+    Ifd(Vec<u32>),
+    Long8(Vec<u64>),
+    SLong8(Vec<i64>),
+    Ifd8(Vec<u64>),
+}
+
+/// The magic numbers we accept after the byte order marker. Alongside the standard 42, some
+/// vendors use their own in otherwise ordinary TIFF structures: 0x55 for Panasonic RW2, and "RO"
+/// and "RS" for Olympus ORF.
+const TIFF_MAGICS: [u16; 4] = [42, 0x55, 0x4f52, 0x5352];
+
+/// BigTIFF's magic number, for files whose offsets don't fit in 32 bits.
+const BIGTIFF_MAGIC: u16 = 43;
+
+/// A reader for the IFDs of a TIFF structure in a buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct IfdReader<'a> {
+    buf: &'a [u8],
+    order: ByteOrder,
+    big: bool,
+}
+
+impl<'a> IfdReader<'a> {
+    /// Parse the TIFF header at the start of `buf`.
+    pub fn new(buf: &'a [u8]) -> Result<Self> {
+        ensure!(buf.len() >= 8, "Not enough data for TIFF header");
+        let order = ByteOrder::from_marker(&buf[0..2]).context("Not a valid TIFF file")?;
+        let magic = order.read_u16(&buf[2..4]);
+        if magic == BIGTIFF_MAGIC {
+            // The size of offsets, which is always 8, then a reserved zero.
+            ensure!(buf.len() >= 16, "Not enough data for BigTIFF header");
+            ensure!(
+                order.read_u16(&buf[4..6]) == 8 && order.read_u16(&buf[6..8]) == 0,
+                "Unsupported BigTIFF offset size"
+            );
+            return Ok(Self {
+                buf,
+                order,
+                big: true,
+            });
+        }
+        ensure!(TIFF_MAGICS.contains(&magic), "Not a valid TIFF file");
+        Ok(Self {
+            buf,
+            order,
+            big: false,
+        })
+    }
+
+    /// Read a TIFF structure without a header, like the bare IFDs in many MakerNotes. Offsets are
+    /// relative to the start of `buf`.
+    pub fn with_byte_order(buf: &'a [u8], order: ByteOrder) -> Self {
+        Self {
+            buf,
+            order,
+            big: false,
+        }
+    }
+
+    pub fn buf(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    pub fn byte_order(&self) -> ByteOrder {
+        self.order
+    }
+
+    /// Whether this is a BigTIFF structure, with 64-bit offsets and counts.
+    pub fn is_big(&self) -> bool {
+        self.big
+    }
+
+    /// The size of offsets, and of the value field in IFD entries.
+    fn offset_size(&self) -> usize {
+        if self.big {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// The size of the entry count at the start of an IFD.
+    fn count_size(&self) -> usize {
+        if self.big {
+            8
+        } else {
+            2
+        }
+    }
+
+    fn entry_size(&self) -> usize {
+        if self.big {
+            BIGTIFF_IFD_ENTRY_SIZE
+        } else {
+            IFD_ENTRY_SIZE
+        }
+    }
+
+    /// Read an offset or count, which is 64 bits in BigTIFF and 32 bits otherwise.
+    fn read_offset(&self, bytes: &[u8]) -> u64 {
+        if self.big {
+            self.order.read_u64(bytes)
+        } else {
+            self.order.read_u32(bytes).into()
+        }
+    }
+
+    /// The offset of IFD0, from the header.
+    pub fn first_ifd_offset(&self) -> Result<usize> {
+        let start = if self.big { 8 } else { 4 };
+        let bytes = self
+            .buf
+            .get(start..start + self.offset_size())
+            .context("Not enough data for TIFF header")?;
+        Ok(self.read_offset(bytes).try_into()?)
+    }
+
+    /// Get the IFD at `offset`.
+    pub fn ifd(&self, offset: usize) -> Result<Ifd<'a>> {
+        let count_end = offset
+            .checked_add(self.count_size())
+            .context("Invalid IFD offset")?;
+        let count_bytes = self
+            .buf
+            .get(offset..count_end)
+            .context("Invalid IFD offset")?;
+        let count = if self.big {
+            self.order.read_u64(count_bytes)
+        } else {
+            self.order.read_u16(count_bytes).into()
+        };
+        let entries = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(self.entry_size()))
+            .and_then(|len| self.buf.get(count_end..)?.get(..len))
+            .context("Invalid number of IFD entries")?;
+        Ok(Ifd {
+            tiff: *self,
+            offset,
+            entries,
+        })
+    }
+
+    /// Iterate over the IFD chain starting at IFD0.
+    pub fn ifds(&self) -> IfdChain<'a> {
+        IfdChain {
+            tiff: *self,
+            next: Some(self.first_ifd_offset()),
+            seen: Vec::new(),
+        }
+    }
+
+    /// Iterate over the IFD chain starting at `offset`.
+    pub fn ifds_from(&self, offset: usize) -> IfdChain<'a> {
+        IfdChain {
+            tiff: *self,
+            next: Some(Ok(offset)),
+            seen: Vec::new(),
+        }
+    }
+}
+
+/// An iterator over a chain of IFDs, following each IFD's next IFD offset until it's zero.
+pub struct IfdChain<'a> {
+    tiff: IfdReader<'a>,
+    next: Option<Result<usize>>,
+    seen: Vec<usize>,
+}
+
+impl<'a> Iterator for IfdChain<'a> {
+    type Item = Result<Ifd<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = match self.next.take()? {
+            Ok(0) => return None,
+            Ok(offset) => offset,
+            Err(e) => return Some(Err(e)),
+        };
+        if self.seen.contains(&offset) || self.seen.len() >= MAX_IFDS {
+            return Some(Err(anyhow::anyhow!("IFD chain loops at {}", offset)));
+        }
+        self.seen.push(offset);
+        let ifd = match self.tiff.ifd(offset) {
+            Ok(ifd) => ifd,
+            Err(e) => return Some(Err(e)),
+        };
+        self.next = Some(ifd.next_ifd_offset());
+        Some(Ok(ifd))
+    }
+}
+
+/// An Image File Directory.
+#[derive(Clone, Copy, Debug)]
+pub struct Ifd<'a> {
+    tiff: IfdReader<'a>,
+    offset: usize,
+    entries: &'a [u8],
+}
+
+impl<'a> Ifd<'a> {
+    /// Where this IFD starts, relative to the TIFF buffer.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() / self.tiff.entry_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = Entry<'a>> + 'a {
+        let tiff = self.tiff;
+        self.entries
+            .chunks_exact(tiff.entry_size())
+            .map(move |raw| Entry { tiff, raw })
+    }
+
+    /// Find the entry for `tag`, if there is one.
+    pub fn entry(&self, tag: u16) -> Option<Entry<'a>> {
+        self.entries().find(|entry| entry.tag() == tag)
+    }
+
+    /// The offset of the next IFD in the chain, or zero if this is the last one.
+    pub fn next_ifd_offset(&self) -> Result<usize> {
+        let start = self.offset + self.tiff.count_size() + self.entries.len();
+        let bytes = self
+            .tiff
+            .buf
+            .get(start..start + self.tiff.offset_size())
+            .context("Invalid next IFD offset")?;
+        Ok(self.tiff.read_offset(bytes).try_into()?)
+    }
+}
+
+/// A single IFD entry.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry<'a> {
+    tiff: IfdReader<'a>,
+    raw: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    pub fn tag(&self) -> u16 {
+        self.tiff.order.read_u16(&self.raw[0..2])
+    }
+
+    pub fn field_type(&self) -> FieldType {
+        FieldType::from_u16(self.tiff.order.read_u16(&self.raw[2..4]))
+    }
+
+    /// The number of values, not bytes. A BigTIFF count which doesn't fit in a `usize` saturates,
+    /// so that its data is out of bounds.
+    pub fn count(&self) -> usize {
+        let size = self.tiff.offset_size();
+        let count = self.tiff.read_offset(&self.raw[4..4 + size]);
+        count.try_into().unwrap_or(usize::MAX)
+    }
+
+    /// The value field, which holds the value itself if it fits, or else an offset to it.
+    fn value_field(&self) -> &'a [u8] {
+        let start = 4 + self.tiff.offset_size();
+        &self.raw[start..start + self.tiff.offset_size()]
+    }
+
+    /// The raw value field, interpreted as an offset. Only meaningful when the value doesn't fit
+    /// inline, although some vendors store offsets to data in entries of other types, like
+    /// UNDEFINED entries for embedded previews. Like [`Entry::count`], this saturates.
+    pub fn value_offset(&self) -> usize {
+        let offset = self.tiff.read_offset(self.value_field());
+        offset.try_into().unwrap_or(usize::MAX)
+    }
+
+    /// The raw bytes of the value, either inline in the entry or at the offset it points to.
+    pub fn data(&self) -> Result<&'a [u8]> {
+        let size = self
+            .field_type()
+            .size()
+            .with_context(|| format!("Unknown type for tag {:#x}", self.tag()))?;
+        let len = self
+            .count()
+            .checked_mul(size)
+            .context("IFD entry count overflows")?;
+        let inline = self.value_field();
+        if len <= inline.len() {
+            return Ok(&inline[..len]);
+        }
+        let offset = self.value_offset();
+        offset
+            .checked_add(len)
+            .and_then(|end| self.tiff.buf.get(offset..end))
+            .with_context(|| format!("Data for tag {:#x} is out of bounds", self.tag()))
+    }
+
+    /// Decode the value according to its type.
+    pub fn value(&self) -> Result<Value<'a>> {
+        let data = self.data()?;
+        let order = self.tiff.order;
+        let u16s = || data.chunks_exact(2).map(|c| order.read_u16(c));
+        let u32s = || data.chunks_exact(4).map(|c| order.read_u32(c));
+        let u64s = || data.chunks_exact(8).map(|c| order.read_u64(c));
+        Ok(match self.field_type() {
+            FieldType::Byte => Value::Byte(data),
+            FieldType::Ascii => {
+                let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                Value::Ascii(&data[..end])
+            }
+            FieldType::Short => Value::Short(u16s().collect()),
+            FieldType::Long => Value::Long(u32s().collect()),
+            FieldType::Ifd => Value::Ifd(u32s().collect()),
+            FieldType::Rational => Value::Rational(
+                data.chunks_exact(8)
+                    .map(|c| Rational {
+                        numerator: order.read_u32(&c[..4]),
+                        denominator: order.read_u32(&c[4..]),
+                    })
+                    .collect(),
+            ),
+            FieldType::SByte => Value::SByte(data.iter().map(|&b| b as i8).collect()),
+            FieldType::Undefined => Value::Undefined(data),
+            FieldType::SShort => Value::SShort(u16s().map(|v| v as i16).collect()),
+            FieldType::SLong => Value::SLong(u32s().map(|v| v as i32).collect()),
+            FieldType::SRational => Value::SRational(
+                data.chunks_exact(8)
+                    .map(|c| SRational {
+                        numerator: order.read_u32(&c[..4]) as i32,
+                        denominator: order.read_u32(&c[4..]) as i32,
+                    })
+                    .collect(),
+            ),
+            FieldType::Float => Value::Float(u32s().map(f32::from_bits).collect()),
+            FieldType::Double => Value::Double(u64s().map(f64::from_bits).collect()),
+            FieldType::Long8 => Value::Long8(u64s().collect()),
+            FieldType::SLong8 => Value::SLong8(u64s().map(|v| v as i64).collect()),
+            FieldType::Ifd8 => Value::Ifd8(u64s().collect()),
+            FieldType::Unknown(typ) => bail!("Unknown type {} for tag {:#x}", typ, self.tag()),
+        })
+    }
+
+    /// All values as unsigned integers, for BYTE, SHORT, LONG and IFD entries.
+    pub fn u32s(&self) -> Option<Vec<u32>> {
+        match self.value().ok()? {
+            Value::Byte(v) => Some(v.iter().map(|&b| b.into()).collect()),
+            Value::Short(v) => Some(v.into_iter().map(u32::from).collect()),
+            Value::Long(v) | Value::Ifd(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// All values as unsigned integers, for the types [`Entry::u32s`] handles as well as BigTIFF's
+    /// LONG8 and IFD8.
+    pub fn u64s(&self) -> Option<Vec<u64>> {
+        match self.value().ok()? {
+            Value::Byte(v) => Some(v.iter().map(|&b| b.into()).collect()),
+            Value::Short(v) => Some(v.into_iter().map(u64::from).collect()),
+            Value::Long(v) | Value::Ifd(v) => Some(v.into_iter().map(u64::from).collect()),
+            Value::Long8(v) | Value::Ifd8(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The first value as an unsigned integer, for BYTE, SHORT, LONG and IFD entries, and their
+    /// 64-bit BigTIFF counterparts.
+    ///
+    /// This is the common case of a single value, so avoid going through [`Entry::value`].
+    pub fn u64(&self) -> Option<u64> {
+        if self.count() == 0 {
+            return None;
+        }
+        let order = self.tiff.order;
+        let field = self.value_field();
+        match self.field_type() {
+            FieldType::Byte => Some(field[0].into()),
+            FieldType::Short => Some(order.read_u16(&field[..2]).into()),
+            FieldType::Long | FieldType::Ifd => Some(order.read_u32(&field[..4]).into()),
+            // These only fit inline in BigTIFF.
+            FieldType::Long8 | FieldType::Ifd8 if self.tiff.big => Some(order.read_u64(field)),
+            _ => None,
+        }
+    }
+
+    /// The first value as an unsigned integer, if it fits in 32 bits.
+    pub fn u32(&self) -> Option<u32> {
+        self.u64()?.try_into().ok()
+    }
+
+    /// The first value as an unsigned integer, converted to `usize` for use as an offset or
+    /// length.
+    pub fn usize(&self) -> Option<usize> {
+        self.u64()?.try_into().ok()
+    }
+
+    /// The value as a string, for ASCII entries. Trailing whitespace, which some cameras pad with,
+    /// is removed, and empty strings are treated as missing.
+    pub fn ascii(&self) -> Option<String> {
+        let Value::Ascii(data) = self.value().ok()? else {
+            return None;
+        };
+        let value = String::from_utf8_lossy(data).trim().to_string();
+        (!value.is_empty()).then_some(value)
+    }
+
+    /// All values, for RATIONAL entries.
+    pub fn rationals(&self) -> Option<Vec<Rational>> {
+        match self.value().ok()? {
+            Value::Rational(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The first value, for RATIONAL entries.
+    pub fn rational(&self) -> Option<Rational> {
+        self.rationals()?.first().copied()
+    }
+}
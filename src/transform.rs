@@ -0,0 +1,891 @@
+//! Lossless JPEG transforms, in the style of jpegtran.
+//!
+//! Some consumers of our output ignore the EXIF Orientation tag entirely, so for them we physically
+//! rotate the preview instead. Doing that by decoding to pixels and re-encoding would be both slow
+//! and lossy, so instead we only entropy decode to DCT coefficients, move and flip those around,
+//! and entropy encode them again. The quantised coefficients themselves are never touched beyond
+//! sign changes, so no generation loss is introduced.
+//!
+//! Only sequential Huffman coded JPEGs are supported, which covers every camera preview we've
+//! seen. Like `jpegtran -trim`, partial MCUs on an edge which would need to move to the other side
+//! of the image are dropped, since they can't be transformed losslessly.
+
+use anyhow::{bail, ensure, Context, Result};
+
+/// Map from zigzag order (as stored in the file) to natural row-major order.
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOF0: u8 = 0xc0;
+const SOF1: u8 = 0xc1;
+const DHT: u8 = 0xc4;
+const SOS: u8 = 0xda;
+const DQT: u8 = 0xdb;
+const DRI: u8 = 0xdd;
+
+type Block = [i16; 64];
+
+/// The primitive operations every EXIF orientation can be built from.
+#[derive(Clone, Copy)]
+enum Op {
+    Transpose,
+    FlipHorizontal,
+    FlipVertical,
+}
+
+/// Get the operations which turn an image stored with the given EXIF orientation into one which
+/// displays correctly with orientation 1.
+fn orientation_ops(orientation: u16) -> Result<&'static [Op]> {
+    use Op::*;
+    Ok(match orientation {
+        1 => &[],
+        2 => &[FlipHorizontal],
+        3 => &[FlipHorizontal, FlipVertical],
+        4 => &[FlipVertical],
+        5 => &[Transpose],
+        6 => &[Transpose, FlipHorizontal],
+        7 => &[Transpose, FlipHorizontal, FlipVertical],
+        8 => &[Transpose, FlipVertical],
+        _ => bail!("Invalid orientation: {}", orientation),
+    })
+}
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    tq: u8,
+    /// Width of the block grid, padded out to whole MCUs.
+    bw: usize,
+    /// Height of the block grid, padded out to whole MCUs.
+    bh: usize,
+    /// Coefficients in natural order, not dequantised.
+    blocks: Vec<Block>,
+}
+
+struct Frame {
+    marker: u8,
+    width: usize,
+    height: usize,
+    components: Vec<Component>,
+}
+
+impl Frame {
+    fn max_h(&self) -> usize {
+        self.components.iter().map(|c| c.h).max().unwrap_or(1)
+    }
+
+    fn max_v(&self) -> usize {
+        self.components.iter().map(|c| c.v).max().unwrap_or(1)
+    }
+
+    fn mcus_x(&self) -> usize {
+        self.width.div_ceil(8 * self.max_h())
+    }
+
+    fn mcus_y(&self) -> usize {
+        self.height.div_ceil(8 * self.max_v())
+    }
+
+    /// Size the block grids to fit the current frame dimensions, dropping any blocks which no
+    /// longer fit and zero filling new ones.
+    fn resize_grids(&mut self) {
+        let (mcus_x, mcus_y) = (self.mcus_x(), self.mcus_y());
+        for c in &mut self.components {
+            let (bw, bh) = (mcus_x * c.h, mcus_y * c.v);
+            let mut blocks = vec![[0; 64]; bw * bh];
+            for y in 0..bh.min(c.bh) {
+                for x in 0..bw.min(c.bw) {
+                    blocks[y * bw + x] = c.blocks[y * c.bw + x];
+                }
+            }
+            c.bw = bw;
+            c.bh = bh;
+            c.blocks = blocks;
+        }
+    }
+
+    fn apply(&mut self, op: Op) -> Result<()> {
+        match op {
+            Op::Transpose => {
+                std::mem::swap(&mut self.width, &mut self.height);
+                for c in &mut self.components {
+                    let mut blocks = vec![[0; 64]; c.bw * c.bh];
+                    for y in 0..c.bh {
+                        for x in 0..c.bw {
+                            let src = &c.blocks[y * c.bw + x];
+                            let dst = &mut blocks[x * c.bh + y];
+                            for v in 0..8 {
+                                for u in 0..8 {
+                                    dst[u * 8 + v] = src[v * 8 + u];
+                                }
+                            }
+                        }
+                    }
+                    std::mem::swap(&mut c.bw, &mut c.bh);
+                    std::mem::swap(&mut c.h, &mut c.v);
+                    c.blocks = blocks;
+                }
+            }
+            Op::FlipHorizontal => {
+                self.width -= self.width % (8 * self.max_h());
+                ensure!(self.width > 0, "Image is too narrow to flip losslessly");
+                self.resize_grids();
+                for c in &mut self.components {
+                    for row in c.blocks.chunks_exact_mut(c.bw) {
+                        row.reverse();
+                        for block in row {
+                            for (i, coef) in block.iter_mut().enumerate() {
+                                if i % 2 == 1 {
+                                    *coef = coef.wrapping_neg();
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Op::FlipVertical => {
+                self.height -= self.height % (8 * self.max_v());
+                ensure!(self.height > 0, "Image is too short to flip losslessly");
+                self.resize_grids();
+                for c in &mut self.components {
+                    let mut blocks: Vec<Block> = c
+                        .blocks
+                        .chunks_exact(c.bw)
+                        .rev()
+                        .flatten()
+                        .copied()
+                        .collect();
+                    for block in &mut blocks {
+                        for (i, coef) in block.iter_mut().enumerate() {
+                            if (i / 8) % 2 == 1 {
+                                *coef = coef.wrapping_neg();
+                            }
+                        }
+                    }
+                    c.blocks = blocks;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct HuffmanTable {
+    /// Number of codes of each length, 1 to 16.
+    bits: [u8; 16],
+    values: Vec<u8>,
+}
+
+/// A Huffman table prepared for decoding, per the procedure in ITU T.81 F.2.2.3.
+struct HuffmanDecoder {
+    maxcode: [i32; 17],
+    valptr: [i32; 17],
+    mincode: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl HuffmanDecoder {
+    fn new(table: &HuffmanTable) -> Self {
+        let mut maxcode = [-1; 17];
+        let mut valptr = [0; 17];
+        let mut mincode = [0; 17];
+        let mut code = 0;
+        let mut k = 0;
+        for len in 1..=16 {
+            let n = i32::from(table.bits[len - 1]);
+            if n > 0 {
+                valptr[len] = k;
+                mincode[len] = code;
+                code += n;
+                k += n;
+                maxcode[len] = code - 1;
+            }
+            code <<= 1;
+        }
+        Self {
+            maxcode,
+            valptr,
+            mincode,
+            values: table.values.clone(),
+        }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8> {
+        let mut code = 0;
+        for len in 1..=16 {
+            code = (code << 1) | reader.bit() as i32;
+            if code <= self.maxcode[len] {
+                let idx = self.valptr[len] + code - self.mincode[len];
+                return self
+                    .values
+                    .get(idx as usize)
+                    .copied()
+                    .context("Invalid Huffman code");
+            }
+        }
+        bail!("Invalid Huffman code")
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u32,
+    nbits: u32,
+    /// Set once we hit a marker. We feed zeroes from then on, as libjpeg does.
+    hit_marker: bool,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            acc: 0,
+            nbits: 0,
+            hit_marker: false,
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.nbits <= 24 {
+            let mut byte = 0;
+            if !self.hit_marker && self.pos < self.data.len() {
+                byte = self.data[self.pos];
+                if byte == 0xff {
+                    match self.data.get(self.pos + 1) {
+                        Some(0x00) => self.pos += 2,
+                        _ => {
+                            self.hit_marker = true;
+                            byte = 0;
+                        }
+                    }
+                } else {
+                    self.pos += 1;
+                }
+            }
+            self.acc |= u32::from(byte) << (24 - self.nbits);
+            self.nbits += 8;
+        }
+    }
+
+    fn bit(&mut self) -> u32 {
+        self.bits(1)
+    }
+
+    fn bits(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        self.fill();
+        let val = self.acc >> (32 - n);
+        self.acc <<= n;
+        self.nbits -= n;
+        val
+    }
+
+    /// Read a magnitude category's extra bits and sign extend them, per ITU T.81 F.2.2.1.
+    fn receive_extend(&mut self, s: u8) -> i16 {
+        let s = u32::from(s);
+        let v = self.bits(s) as i32;
+        let v = if s > 0 && v < (1 << (s - 1)) {
+            v - (1 << s) + 1
+        } else {
+            v
+        };
+        v as i16
+    }
+
+    /// Skip to the byte after the next RSTn marker, for restart intervals.
+    fn restart(&mut self) -> Result<()> {
+        self.acc = 0;
+        self.nbits = 0;
+        self.hit_marker = false;
+        while self.pos + 1 < self.data.len() {
+            if self.data[self.pos] == 0xff && (0xd0..=0xd7).contains(&self.data[self.pos + 1]) {
+                self.pos += 2;
+                return Ok(());
+            }
+            self.pos += 1;
+        }
+        bail!("Missing restart marker")
+    }
+}
+
+/// Read the JPEG segment starting at `pos`. Returns the marker, the segment payload (without the
+/// length bytes), and the position just after the segment.
+fn read_segment(jpeg: &[u8], pos: usize) -> Result<(u8, &[u8], usize)> {
+    ensure!(
+        jpeg.get(pos) == Some(&0xff),
+        "Expected JPEG marker at {}",
+        pos
+    );
+    let mut pos = pos + 1;
+    // Markers may be preceded by any number of fill bytes.
+    while jpeg.get(pos) == Some(&0xff) {
+        pos += 1;
+    }
+    let marker = *jpeg.get(pos).context("Truncated JPEG")?;
+    if marker == EOI {
+        return Ok((marker, &[], pos + 1));
+    }
+    let len_bytes = jpeg.get(pos + 1..pos + 3).context("Truncated JPEG")?;
+    let len = usize::from(u16::from_be_bytes([len_bytes[0], len_bytes[1]]));
+    ensure!(len >= 2, "Invalid JPEG segment length");
+    let payload = jpeg
+        .get(pos + 3..pos + 1 + len)
+        .context("Truncated JPEG segment")?;
+    Ok((marker, payload, pos + 1 + len))
+}
+
+fn parse_dht(payload: &[u8], tables: &mut [[Option<HuffmanTable>; 4]; 2]) -> Result<()> {
+    let mut rest = payload;
+    while !rest.is_empty() {
+        ensure!(rest.len() >= 17, "Truncated DHT");
+        let class = usize::from(rest[0] >> 4);
+        let id = usize::from(rest[0] & 0xf);
+        ensure!(class < 2 && id < 4, "Invalid DHT table");
+        let mut bits = [0; 16];
+        bits.copy_from_slice(&rest[1..17]);
+        let count: usize = bits.iter().map(|&b| usize::from(b)).sum();
+        let values = rest.get(17..17 + count).context("Truncated DHT")?.to_vec();
+        tables[class][id] = Some(HuffmanTable { bits, values });
+        rest = &rest[17 + count..];
+    }
+    Ok(())
+}
+
+/// Transpose any quantisation tables in a DQT payload, since coefficient (u, v) becomes (v, u).
+fn transpose_dqt(payload: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(payload.len());
+    let mut rest = payload;
+    while !rest.is_empty() {
+        let precision = rest[0] >> 4;
+        let elem_size = if precision == 0 { 1 } else { 2 };
+        let table = rest.get(1..1 + 64 * elem_size).context("Truncated DQT")?;
+        out.push(rest[0]);
+        let mut transposed = vec![0; table.len()];
+        for (zz, &natural) in ZIGZAG.iter().enumerate() {
+            let (v, u) = (natural / 8, natural % 8);
+            let dst_zz = ZIGZAG
+                .iter()
+                .position(|&n| n == u * 8 + v)
+                .expect("in range");
+            transposed[dst_zz * elem_size..][..elem_size]
+                .copy_from_slice(&table[zz * elem_size..][..elem_size]);
+        }
+        out.extend_from_slice(&transposed);
+        rest = &rest[1 + 64 * elem_size..];
+    }
+    Ok(out)
+}
+
+fn parse_sof(marker: u8, payload: &[u8]) -> Result<Frame> {
+    ensure!(payload.len() >= 6, "Truncated SOF");
+    ensure!(payload[0] == 8, "Only 8 bit JPEGs are supported");
+    let height = usize::from(u16::from_be_bytes([payload[1], payload[2]]));
+    let width = usize::from(u16::from_be_bytes([payload[3], payload[4]]));
+    ensure!(width > 0 && height > 0, "Invalid JPEG dimensions");
+    let count = usize::from(payload[5]);
+    ensure!((1..=4).contains(&count), "Invalid JPEG component count");
+    let specs = payload.get(6..6 + count * 3).context("Truncated SOF")?;
+    let mut components = Vec::with_capacity(count);
+    for spec in specs.chunks_exact(3) {
+        let (mut h, mut v) = (usize::from(spec[1] >> 4), usize::from(spec[1] & 0xf));
+        ensure!(
+            (1..=4).contains(&h) && (1..=4).contains(&v),
+            "Invalid sampling factors"
+        );
+        if count == 1 {
+            // Sampling factors are meaningless for a single component.
+            (h, v) = (1, 1);
+        }
+        components.push(Component {
+            id: spec[0],
+            h,
+            v,
+            tq: spec[2],
+            bw: 0,
+            bh: 0,
+            blocks: Vec::new(),
+        });
+    }
+    let mut frame = Frame {
+        marker,
+        width,
+        height,
+        components,
+    };
+    frame.resize_grids();
+    Ok(frame)
+}
+
+/// Decode a single 8x8 block of coefficients, per ITU T.81 F.2.2.
+fn decode_block(
+    reader: &mut BitReader,
+    pred: &mut i16,
+    dc: &HuffmanDecoder,
+    ac: &HuffmanDecoder,
+) -> Result<Block> {
+    let mut block = [0; 64];
+    let s = dc.decode(reader)?;
+    ensure!(s <= 11, "Invalid DC magnitude");
+    *pred = pred.wrapping_add(reader.receive_extend(s));
+    block[0] = *pred;
+    let mut k = 1;
+    while k < 64 {
+        let rs = ac.decode(reader)?;
+        let (r, s) = (usize::from(rs >> 4), rs & 0xf);
+        if s == 0 {
+            if r != 15 {
+                break;
+            }
+            k += 16;
+            continue;
+        }
+        k += r;
+        ensure!(k < 64, "AC coefficient out of range");
+        block[ZIGZAG[k]] = reader.receive_extend(s);
+        k += 1;
+    }
+    Ok(block)
+}
+
+/// Decode one scan's entropy coded data into the frame's coefficient grids, returning the number
+/// of bytes of entropy coded data consumed.
+fn decode_scan(
+    frame: &mut Frame,
+    sos: &[u8],
+    data: &[u8],
+    tables: &[[Option<HuffmanTable>; 4]; 2],
+    restart_interval: usize,
+) -> Result<usize> {
+    let count = usize::from(*sos.first().context("Truncated SOS")?);
+    let specs = sos.get(1..1 + count * 2).context("Truncated SOS")?;
+    let tail = sos
+        .get(1 + count * 2..4 + count * 2)
+        .context("Truncated SOS")?;
+    ensure!(
+        tail[0] == 0 && tail[1] == 63 && tail[2] == 0,
+        "Only sequential JPEGs are supported"
+    );
+
+    let mut scan = Vec::with_capacity(count);
+    for spec in specs.chunks_exact(2) {
+        let idx = frame
+            .components
+            .iter()
+            .position(|c| c.id == spec[0])
+            .context("SOS references unknown component")?;
+        let dc = tables[0][usize::from(spec[1] >> 4)]
+            .as_ref()
+            .context("Missing DC table")?;
+        let ac = tables[1][usize::from(spec[1] & 0xf)]
+            .as_ref()
+            .context("Missing AC table")?;
+        scan.push((idx, HuffmanDecoder::new(dc), HuffmanDecoder::new(ac)));
+    }
+
+    let (max_h, max_v) = (frame.max_h(), frame.max_v());
+    let mut reader = BitReader::new(data);
+    let mut preds = vec![0i16; scan.len()];
+    let mut mcu_count = 0;
+    let mut next_mcu = |reader: &mut BitReader, preds: &mut [i16]| -> Result<()> {
+        if restart_interval > 0 && mcu_count > 0 && mcu_count % restart_interval == 0 {
+            reader.restart()?;
+            preds.fill(0);
+        }
+        mcu_count += 1;
+        Ok(())
+    };
+
+    if let [(idx, dc, ac)] = scan.as_slice() {
+        // Non-interleaved scans only cover the component's own dimensions, not whole MCUs.
+        let c = &mut frame.components[*idx];
+        let cw = (frame.width * c.h).div_ceil(max_h).div_ceil(8);
+        let ch = (frame.height * c.v).div_ceil(max_v).div_ceil(8);
+        for y in 0..ch {
+            for x in 0..cw {
+                next_mcu(&mut reader, &mut preds)?;
+                c.blocks[y * c.bw + x] = decode_block(&mut reader, &mut preds[0], dc, ac)?;
+            }
+        }
+    } else {
+        let (mcus_x, mcus_y) = (frame.mcus_x(), frame.mcus_y());
+        for mcu_y in 0..mcus_y {
+            for mcu_x in 0..mcus_x {
+                next_mcu(&mut reader, &mut preds)?;
+                for ((idx, dc, ac), pred) in scan.iter().zip(preds.iter_mut()) {
+                    let c = &mut frame.components[*idx];
+                    for by in 0..c.v {
+                        for bx in 0..c.h {
+                            let (x, y) = (mcu_x * c.h + bx, mcu_y * c.v + by);
+                            c.blocks[y * c.bw + x] = decode_block(&mut reader, pred, dc, ac)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Find where the entropy coded data ends, which is the first marker other than RSTn.
+    let mut end = 0;
+    while end + 1 < data.len() {
+        if data[end] == 0xff && data[end + 1] != 0 && !(0xd0..=0xd7).contains(&data[end + 1]) {
+            break;
+        }
+        end += 1;
+    }
+    Ok(end)
+}
+
+/// Generate code lengths for an optimal Huffman table from symbol frequencies, limited to 16
+/// bits. This is the procedure from ITU T.81 K.2, as also used by libjpeg.
+fn optimal_table(freqs: &[u64; 256]) -> HuffmanTable {
+    let mut freq = [0u64; 257];
+    freq[..256].copy_from_slice(freqs);
+    // Reserve one code point so that no real symbol gets a code of all ones.
+    freq[256] = 1;
+    let mut codesize = [0usize; 257];
+    let mut others = [-1i32; 257];
+
+    loop {
+        let mut c1 = None;
+        let mut c2 = None;
+        let mut v1 = u64::MAX;
+        let mut v2 = u64::MAX;
+        for (i, &f) in freq.iter().enumerate() {
+            if f == 0 {
+                continue;
+            }
+            if f <= v1 {
+                v2 = v1;
+                c2 = c1;
+                v1 = f;
+                c1 = Some(i);
+            } else if f <= v2 {
+                v2 = f;
+                c2 = Some(i);
+            }
+        }
+        let (Some(mut c1), Some(mut c2)) = (c1, c2) else {
+            break;
+        };
+        freq[c1] += freq[c2];
+        freq[c2] = 0;
+        codesize[c1] += 1;
+        while others[c1] >= 0 {
+            c1 = others[c1] as usize;
+            codesize[c1] += 1;
+        }
+        others[c1] = c2 as i32;
+        codesize[c2] += 1;
+        while others[c2] >= 0 {
+            c2 = others[c2] as usize;
+            codesize[c2] += 1;
+        }
+    }
+
+    let mut bits = [0usize; 33];
+    for &size in &codesize {
+        if size > 0 {
+            bits[size.min(32)] += 1;
+        }
+    }
+    for i in (17..=32).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+    let mut i = 16;
+    while bits[i] == 0 {
+        i -= 1;
+    }
+    bits[i] -= 1;
+
+    let mut values = Vec::new();
+    for size in 1..=32 {
+        for (sym, &s) in codesize.iter().enumerate().take(256) {
+            if s == size {
+                values.push(sym as u8);
+            }
+        }
+    }
+    let mut out_bits = [0u8; 16];
+    for (len, out) in out_bits.iter_mut().enumerate() {
+        *out = bits[len + 1] as u8;
+    }
+    HuffmanTable {
+        bits: out_bits,
+        values,
+    }
+}
+
+/// A Huffman table prepared for encoding: code and length for each symbol.
+struct HuffmanEncoder {
+    codes: [(u16, u8); 256],
+}
+
+impl HuffmanEncoder {
+    fn new(table: &HuffmanTable) -> Self {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0u16;
+        let mut k = 0;
+        for len in 1..=16 {
+            for _ in 0..table.bits[len - 1] {
+                codes[usize::from(table.values[k])] = (code, len as u8);
+                code += 1;
+                k += 1;
+            }
+            code <<= 1;
+        }
+        Self { codes }
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, bits: u32, n: u32) {
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (bits & ((1 << n) - 1));
+        self.nbits += n;
+        while self.nbits >= 8 {
+            let byte = (self.acc >> (self.nbits - 8)) as u8;
+            self.out.push(byte);
+            if byte == 0xff {
+                self.out.push(0);
+            }
+            self.nbits -= 8;
+        }
+    }
+
+    fn flush(&mut self) {
+        // Pad with ones, as required by ITU T.81 F.1.2.3.
+        let pad = (8 - self.nbits % 8) % 8;
+        self.write((1 << pad) - 1, pad);
+    }
+}
+
+/// Get the magnitude category and extra bits for a coefficient value.
+fn categorise(v: i16) -> (u8, u32) {
+    let mag = v.unsigned_abs();
+    let size = (16 - mag.leading_zeros()) as u8;
+    let bits = if v < 0 {
+        (i32::from(v) - 1) as u32
+    } else {
+        v as u32
+    };
+    (size, bits & ((1 << size) - 1))
+}
+
+/// Walk every block of the frame in interleaved MCU order, calling `f` with the component's slot
+/// and each (symbol, extra bits, extra bit count) triple in coding order.
+fn for_each_symbol(frame: &Frame, mut f: impl FnMut(usize, bool, u8, u32, u8)) {
+    let single = frame.components.len() == 1;
+    let mut preds = vec![0i16; frame.components.len()];
+    let mut emit_block = |slot: usize, block: &Block| {
+        let diff = block[0].wrapping_sub(preds[slot]);
+        preds[slot] = block[0];
+        let (size, bits) = categorise(diff);
+        f(slot, true, size, bits, size);
+        let mut run = 0;
+        for &natural in &ZIGZAG[1..] {
+            let coef = block[natural];
+            if coef == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                f(slot, false, 0xf0, 0, 0);
+                run -= 16;
+            }
+            let (size, bits) = categorise(coef);
+            f(slot, false, (run << 4) | size, bits, size);
+            run = 0;
+        }
+        if run > 0 {
+            f(slot, false, 0x00, 0, 0);
+        }
+    };
+
+    if single {
+        let c = &frame.components[0];
+        let cw = frame.width.div_ceil(8);
+        let ch = frame.height.div_ceil(8);
+        for y in 0..ch {
+            for x in 0..cw {
+                emit_block(0, &c.blocks[y * c.bw + x]);
+            }
+        }
+        return;
+    }
+
+    for mcu_y in 0..frame.mcus_y() {
+        for mcu_x in 0..frame.mcus_x() {
+            for (slot, c) in frame.components.iter().enumerate() {
+                for by in 0..c.v {
+                    for bx in 0..c.h {
+                        let (x, y) = (mcu_x * c.h + bx, mcu_y * c.v + by);
+                        emit_block(slot, &c.blocks[y * c.bw + x]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Entropy encode the frame with optimal Huffman tables, appending SOF, DHT, SOS and the scan
+/// data to `out`.
+fn encode_frame(frame: &Frame, out: &mut Vec<u8>) {
+    // Luma gets tables 0, everything else shares tables 1, which is what baseline encoders do.
+    let table_id = |slot: usize| usize::from(slot > 0);
+    let mut freqs = [[[0u64; 256]; 2]; 2];
+    for_each_symbol(frame, |slot, is_dc, sym, _, _| {
+        freqs[usize::from(!is_dc)][table_id(slot)][usize::from(sym)] += 1;
+    });
+
+    let nr_tables = if frame.components.len() > 1 { 2 } else { 1 };
+    let mut tables = Vec::new();
+    for (class, class_freqs) in freqs.iter().enumerate() {
+        for (id, table_freqs) in class_freqs.iter().enumerate().take(nr_tables) {
+            tables.push((class, id, optimal_table(table_freqs)));
+        }
+    }
+
+    // SOF
+    let mut sof = vec![8];
+    sof.extend_from_slice(&(frame.height as u16).to_be_bytes());
+    sof.extend_from_slice(&(frame.width as u16).to_be_bytes());
+    sof.push(frame.components.len() as u8);
+    for c in &frame.components {
+        sof.extend_from_slice(&[c.id, ((c.h as u8) << 4) | c.v as u8, c.tq]);
+    }
+    write_segment(out, frame.marker, &sof);
+
+    // DHT
+    let mut dht = Vec::new();
+    for (class, id, table) in &tables {
+        dht.push(((*class as u8) << 4) | *id as u8);
+        dht.extend_from_slice(&table.bits);
+        dht.extend_from_slice(&table.values);
+    }
+    write_segment(out, DHT, &dht);
+
+    // SOS
+    let mut sos = vec![frame.components.len() as u8];
+    for (slot, c) in frame.components.iter().enumerate() {
+        let id = table_id(slot) as u8;
+        sos.extend_from_slice(&[c.id, (id << 4) | id]);
+    }
+    sos.extend_from_slice(&[0, 63, 0]);
+    write_segment(out, SOS, &sos);
+
+    let encoders: Vec<Vec<HuffmanEncoder>> = (0..2)
+        .map(|class| {
+            tables
+                .iter()
+                .filter(|(c, _, _)| *c == class)
+                .map(|(_, _, t)| HuffmanEncoder::new(t))
+                .collect()
+        })
+        .collect();
+
+    let mut writer = BitWriter {
+        out: std::mem::take(out),
+        acc: 0,
+        nbits: 0,
+    };
+    for_each_symbol(frame, |slot, is_dc, sym, bits, nbits| {
+        let (code, len) = encoders[usize::from(!is_dc)][table_id(slot)].codes[usize::from(sym)];
+        writer.write(code.into(), len.into());
+        writer.write(bits, nbits.into());
+    });
+    writer.flush();
+    *out = writer.out;
+    out.extend_from_slice(&[0xff, EOI]);
+}
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// Losslessly transform a JPEG so that it displays correctly without an EXIF orientation.
+///
+/// `jpeg` must be a complete JPEG starting with SOI. Segments other than those describing the
+/// frame and its entropy coding (APPn, COM and so on) are carried over untouched, so the caller is
+/// responsible for making sure nothing in there still claims a non-default orientation.
+pub fn apply_orientation(jpeg: &[u8], orientation: u16) -> Result<Vec<u8>> {
+    let ops = orientation_ops(orientation)?;
+    ensure!(jpeg.starts_with(&[0xff, SOI]), "Not a JPEG");
+    if ops.is_empty() {
+        return Ok(jpeg.to_vec());
+    }
+    let transposes = ops.iter().any(|op| matches!(op, Op::Transpose));
+
+    let mut header = vec![0xff, SOI];
+    let mut tables: [[Option<HuffmanTable>; 4]; 2] = Default::default();
+    let mut frame = None;
+    let mut restart_interval = 0;
+    let mut pos = 2;
+
+    loop {
+        let (marker, payload, next) = read_segment(jpeg, pos)?;
+        match marker {
+            SOF0 | SOF1 => frame = Some(parse_sof(marker, payload)?),
+            0xc2 | 0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                bail!("Only sequential Huffman coded JPEGs are supported");
+            }
+            DHT => parse_dht(payload, &mut tables)?,
+            DRI => {
+                ensure!(payload.len() >= 2, "Truncated DRI");
+                restart_interval = usize::from(u16::from_be_bytes([payload[0], payload[1]]));
+            }
+            DQT if transposes => write_segment(&mut header, DQT, &transpose_dqt(payload)?),
+            SOS => {
+                let frame = frame.as_mut().context("SOS before SOF")?;
+                let used = decode_scan(frame, payload, &jpeg[next..], &tables, restart_interval)?;
+                pos = next + used;
+                // Sequential JPEGs may have one scan per component, keep going until EOI.
+                if jpeg.get(pos..pos + 2) == Some(&[0xff, EOI]) || pos + 2 > jpeg.len() {
+                    break;
+                }
+                continue;
+            }
+            EOI => break,
+            _ => write_segment(&mut header, marker, payload),
+        }
+        pos = next;
+    }
+
+    let mut frame = frame.context("No SOF found")?;
+    for &op in ops {
+        frame.apply(op)?;
+    }
+
+    let mut out = header;
+    encode_frame(&frame, &mut out);
+    Ok(out)
+}
@@ -0,0 +1,25 @@
+//! Diagnostics about each file processed, like where its preview was found and how long each
+//! stage took. They're off by default, and always go to stderr, so that they never get mixed up
+//! with output meant for other programs.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Turn diagnostics on or off, for the whole process.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub(crate) fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Print a diagnostic to stderr, if they're turned on with [`set_verbose`].
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::verbose::is_verbose() {
+            eprintln!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,161 @@
+//! Checking an output directory after a run.
+//!
+//! Every JPEG in it is checked to be complete, from SOI to EOI, with a header which decodes. Given
+//! the input directory too, every RAW in it is checked to have its outputs there, and for them to
+//! match it, by extracting the preview again with the same options and comparing the two. The
+//! outputs checked are the ones recorded for the RAW if the output directory has incremental
+//! state, or else wherever the same options would have written it, including any `-N` or `{seq}`
+//! names it could have been given instead of its first choice.
+
+use anyhow::{ensure, Context, Result};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::parser::{expected_output, ExtractOptions};
+use crate::resize;
+use crate::sync::SyncState;
+
+/// Something wrong with an output directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Problem {
+    /// An output which isn't a valid JPEG.
+    Invalid { path: PathBuf, reason: String },
+    /// A RAW which doesn't have its output.
+    Missing { source: PathBuf, expected: PathBuf },
+    /// An output which isn't what extracting its RAW gives now, like if either was replaced.
+    Mismatch { source: PathBuf, output: PathBuf },
+    /// A RAW which its preview couldn't be extracted from, to compare its outputs with.
+    Extract { source: PathBuf, error: String },
+}
+
+/// The result of [`verify`].
+#[derive(Clone, Debug, Default)]
+pub struct Verification {
+    /// How many JPEGs were checked.
+    pub checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Check that `data` is a complete JPEG, returning its dimensions.
+pub fn check_jpeg(data: &[u8]) -> Result<(u32, u32)> {
+    ensure!(data.starts_with(&[0xff, 0xd8]), "No SOI marker");
+    ensure!(data.ends_with(&[0xff, 0xd9]), "No EOI marker, truncated");
+    resize::dimensions(data).context("Header doesn't decode")
+}
+
+/// Every file under `dir` whose extension, lowercased, is in `extensions`.
+async fn walk(dir: &Path, extensions: &HashSet<OsString>) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dir_queue = vec![dir.to_path_buf()];
+    while let Some(current_dir) = dir_queue.pop() {
+        let mut read_dir = tokio::fs::read_dir(&current_dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                dir_queue.push(path);
+            } else if path
+                .extension()
+                .is_some_and(|ext| extensions.contains(&ext.to_ascii_lowercase()))
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Check the JPEGs in `out_dir`, and with `in_dir`, that every RAW in it with one of
+/// `raw_extensions` (lowercase) has its outputs, and that they match what extracting it with
+/// `options` gives. Those should be the options the outputs were written with, or anything which
+/// changes the preview, like rotating it, will make them all mismatch.
+pub async fn verify(
+    out_dir: &Path,
+    in_dir: Option<&Path>,
+    raw_extensions: &HashSet<OsString>,
+    options: &ExtractOptions,
+) -> Result<Verification> {
+    let mut verification = Verification::default();
+    let jpeg_extensions = ["jpg", "jpeg"].map(OsString::from).into();
+    for path in walk(out_dir, &jpeg_extensions).await? {
+        verification.checked += 1;
+        let data = tokio::fs::read(&path).await?;
+        if let Err(e) = check_jpeg(&data) {
+            let reason = e.to_string();
+            verification
+                .problems
+                .push(Problem::Invalid { path, reason });
+        }
+    }
+
+    let Some(in_dir) = in_dir else {
+        return Ok(verification);
+    };
+    let state = SyncState::load(out_dir).await?;
+    for source in walk(in_dir, raw_extensions).await? {
+        let relative_path = source.strip_prefix(in_dir)?;
+        let expected = match expected_output(&source, out_dir, relative_path, options).await {
+            Ok(expected) => expected,
+            Err(e) => {
+                let error = format!("{:#}", e);
+                verification
+                    .problems
+                    .push(Problem::Extract { source, error });
+                continue;
+            }
+        };
+
+        let record = state
+            .get(relative_path)
+            .filter(|record| record.is_for(&source));
+        if let Some(record) = record {
+            for output in &record.outputs {
+                let output = out_dir.join(output);
+                match tokio::fs::read(&output).await {
+                    Ok(data) if data == expected.data => {}
+                    Ok(_) => verification.problems.push(Problem::Mismatch {
+                        source: source.clone(),
+                        output,
+                    }),
+                    Err(_) => verification.problems.push(Problem::Missing {
+                        source: source.clone(),
+                        expected: output,
+                    }),
+                }
+            }
+            continue;
+        }
+
+        // Without a record, look through every name it could have been given, as far as they
+        // exist, for one holding its preview.
+        let wanted = expected.path(0)?;
+        let mut found = false;
+        for n in 0.. {
+            let path = expected.path(n)?;
+            match tokio::fs::read(&path).await {
+                Ok(data) if data == expected.data => {
+                    found = true;
+                    break;
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        if found {
+            continue;
+        }
+        let problem = match tokio::fs::metadata(&wanted).await {
+            Ok(_) => Problem::Mismatch {
+                source,
+                output: wanted,
+            },
+            Err(_) => Problem::Missing {
+                source,
+                expected: wanted,
+            },
+        };
+        verification.problems.push(problem);
+    }
+    Ok(verification)
+}
@@ -0,0 +1,108 @@
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Where [`tiff`] puts the payload, so tests can point IFD entries at it.
+pub const PAYLOAD_OFFSET: u32 = 0x400;
+
+/// A minimal JPEG: SOI and EOI, with nothing in between.
+pub const TINY_JPEG: &[u8] = &[0xff, 0xd8, 0xff, 0xd9];
+
+/// One IFD entry as (tag, type, count, value or offset).
+pub type Entry = (u16, u16, u32, u32);
+
+/// Build a little-endian TIFF with the given IFD chain, followed by `payload` at
+/// [`PAYLOAD_OFFSET`].
+pub fn tiff(ifds: &[Vec<Entry>], payload: &[u8]) -> Vec<u8> {
+    let mut buf = b"II*\0".to_vec();
+    buf.extend_from_slice(&8u32.to_le_bytes());
+    for (i, ifd) in ifds.iter().enumerate() {
+        buf.extend_from_slice(&(ifd.len() as u16).to_le_bytes());
+        for &(tag, typ, count, value) in ifd {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&typ.to_le_bytes());
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        let next = if i + 1 == ifds.len() {
+            0
+        } else {
+            buf.len() as u32 + 4
+        };
+        buf.extend_from_slice(&next.to_le_bytes());
+    }
+    assert!(buf.len() <= PAYLOAD_OFFSET as usize, "IFDs overlap payload");
+    buf.resize(PAYLOAD_OFFSET as usize, 0);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// One BigTIFF IFD entry as (tag, type, count, value or offset).
+pub type BigEntry = (u16, u16, u64, u64);
+
+/// Like [`tiff`], but BigTIFF.
+pub fn bigtiff(ifds: &[Vec<BigEntry>], payload: &[u8]) -> Vec<u8> {
+    let mut buf = b"II+\0\x08\0\0\0".to_vec();
+    buf.extend_from_slice(&16u64.to_le_bytes());
+    for (i, ifd) in ifds.iter().enumerate() {
+        buf.extend_from_slice(&(ifd.len() as u64).to_le_bytes());
+        for &(tag, typ, count, value) in ifd {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&typ.to_le_bytes());
+            buf.extend_from_slice(&count.to_le_bytes());
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        let next = if i + 1 == ifds.len() {
+            0
+        } else {
+            buf.len() as u64 + 8
+        };
+        buf.extend_from_slice(&next.to_le_bytes());
+    }
+    assert!(buf.len() <= PAYLOAD_OFFSET as usize, "IFDs overlap payload");
+    buf.resize(PAYLOAD_OFFSET as usize, 0);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Serialise a little-endian IFD with no next IFD.
+pub fn ifd_bytes(entries: &[Entry]) -> Vec<u8> {
+    let mut buf = (entries.len() as u16).to_le_bytes().to_vec();
+    for &(tag, typ, count, value) in entries {
+        buf.extend_from_slice(&tag.to_le_bytes());
+        buf.extend_from_slice(&typ.to_le_bytes());
+        buf.extend_from_slice(&count.to_le_bytes());
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf
+}
+
+/// A directory of its own for one test, removed along with everything in it when dropped.
+pub fn temp_dir() -> TempDir {
+    tempfile::Builder::new()
+        .prefix("jpgfromraw-tests-")
+        .tempdir()
+        .unwrap()
+}
+
+/// Write `data` to `name` in `dir`.
+pub fn write_temp(dir: impl AsRef<Path>, name: &str, data: &[u8]) -> PathBuf {
+    let path = dir.as_ref().join(name);
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+/// Get the orientation we wrote into the APP1 header of an extracted JPEG.
+pub fn written_orientation(jpeg: &[u8]) -> u16 {
+    u16::from_le_bytes([jpeg[30], jpeg[31]])
+}
+
+/// Get the offset [`tiff`] will put the IFD at `index` at.
+pub fn ifd_offset(ifds: &[Vec<Entry>], index: usize) -> u32 {
+    8 + ifds[..index]
+        .iter()
+        .map(|ifd| 2 + 12 * ifd.len() as u32 + 4)
+        .sum::<u32>()
+}
@@ -0,0 +1,186 @@
+mod common;
+
+use anyhow::Result;
+use common::{temp_dir, tiff, write_temp, PAYLOAD_OFFSET, TINY_JPEG};
+use jpgfromraw::parser::process_file_with_options;
+use jpgfromraw::{bmff, read_metadata};
+use jpgfromraw::{find_embedded_jpeg, ExtractOptions, OverwritePolicy, PreviewKind, WriteOutcome};
+
+const ASCII: u16 = 2;
+
+const CANON_UUID: [u8; 16] = [
+    0x85, 0xc0, 0xb6, 0x87, 0x82, 0x0f, 0x11, 0xe0, 0x81, 0x11, 0xf4, 0xce, 0x46, 0x2b, 0x6a, 0x48,
+];
+
+fn bx(box_type: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+    let mut out = ((contents.len() + 8) as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(box_type);
+    out.extend_from_slice(contents);
+    out
+}
+
+fn uuid_box(uuid: &[u8; 16], contents: &[u8]) -> Vec<u8> {
+    let mut with_uuid = uuid.to_vec();
+    with_uuid.extend_from_slice(contents);
+    let mut out = ((with_uuid.len() + 8) as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(b"uuid");
+    out.extend_from_slice(&with_uuid);
+    out
+}
+
+/// A CR3 with a JPEG THMB and a full size image in a track, whose sample entry has `codec`
+/// (a `JPEG` or `hvcC` box) as a child.
+fn cr3(codec: &[u8], image: &[u8]) -> Vec<u8> {
+    let cmt1 = bx(b"CMT1", &tiff(&[vec![(0x112, 3, 1, 6)]], &[]));
+    cr3_with_metadata(&[cmt1], codec, image)
+}
+
+/// Like [`cr3`], with `cmt` as the metadata boxes.
+fn cr3_with_metadata(cmt: &[Vec<u8>], codec: &[u8], image: &[u8]) -> Vec<u8> {
+    let ftyp = bx(b"ftyp", b"crx \0\0\0\x01crx isom");
+
+    let mut thmb = vec![0; 4];
+    thmb.extend_from_slice(&160u16.to_be_bytes());
+    thmb.extend_from_slice(&120u16.to_be_bytes());
+    thmb.extend_from_slice(&(TINY_JPEG.len() as u32).to_be_bytes());
+    thmb.extend_from_slice(&[0; 4]);
+    thmb.extend_from_slice(TINY_JPEG);
+    let canon = uuid_box(&CANON_UUID, &[cmt.concat(), bx(b"THMB", &thmb)].concat());
+
+    let mut craw = vec![0; 78];
+    craw[24..26].copy_from_slice(&6000u16.to_be_bytes());
+    craw[26..28].copy_from_slice(&4000u16.to_be_bytes());
+    craw.extend_from_slice(codec);
+    let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+    stsd.extend(bx(b"CRAW", &craw));
+    let mut stsz = vec![0; 4];
+    stsz.extend_from_slice(&(image.len() as u32).to_be_bytes());
+    stsz.extend_from_slice(&1u32.to_be_bytes());
+
+    // The chunk offset depends on the size of moov, which doesn't depend on the offset.
+    let moov = |offset: u64| {
+        let mut co64 = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        co64.extend_from_slice(&offset.to_be_bytes());
+        let stbl = [bx(b"stsd", &stsd), bx(b"stsz", &stsz), bx(b"co64", &co64)].concat();
+        let trak = bx(b"trak", &bx(b"mdia", &bx(b"minf", &bx(b"stbl", &stbl))));
+        bx(b"moov", &[canon.clone(), trak].concat())
+    };
+    let offset = ftyp.len() + moov(0).len() + 8;
+    [ftyp, moov(offset as u64), bx(b"mdat", image)].concat()
+}
+
+#[tokio::test]
+async fn test_cr3_jpeg_track() -> Result<()> {
+    let mut image = vec![0xff, 0xd8];
+    image.resize(0x100, 0);
+    let temp = temp_dir();
+    let path = write_temp(&temp, "jpeg.cr3", &cr3(&bx(b"JPEG", &[]), &image));
+
+    let preview = find_embedded_jpeg(&path, &ExtractOptions::default()).await?;
+    assert_eq!(preview.kind(), PreviewKind::Jpeg);
+    assert_eq!(preview.length(), image.len());
+    assert_eq!(preview.dimensions(), Some((6000, 4000)));
+    assert_eq!(preview.orientation(), Some(6));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cr3_heif_track_is_wrapped_as_heic() -> Result<()> {
+    let hvcc = [1, 2, 3, 4];
+    let image = [0, 0, 0, 4, 0x26, 0x01, 0xaf, 0x00];
+    let temp = temp_dir();
+    let path = write_temp(&temp, "hdr.cr3", &cr3(&bx(b"hvcC", &hvcc), &image));
+
+    let preview = find_embedded_jpeg(&path, &ExtractOptions::default()).await?;
+    assert_eq!(preview.kind(), PreviewKind::Heif);
+    assert_eq!(preview.dimensions(), Some((6000, 4000)));
+
+    let out_dir = path.with_file_name("hdr_out");
+    let options = ExtractOptions::default();
+    process_file_with_options(&path, &out_dir, "hdr.cr3".as_ref(), &options).await?;
+    let heic = std::fs::read(out_dir.join("hdr.heic"))?;
+
+    let ftyp = bmff::boxes(&heic).find(b"ftyp").unwrap();
+    assert!(ftyp.data().starts_with(b"heic"));
+    let meta = bmff::boxes(&heic).find(b"meta").unwrap();
+    let ipco = meta
+        .children(4)
+        .find(b"iprp")
+        .unwrap()
+        .children(0)
+        .find(b"ipco")
+        .unwrap();
+    assert_eq!(ipco.children(0).find(b"hvcC").unwrap().data(), hvcc);
+    assert_eq!(ipco.children(0).find(b"irot").unwrap().data(), [3]);
+
+    // The item's single extent must be exactly the bitstream in mdat.
+    let iloc = meta.children(4).find(b"iloc").unwrap().data();
+    let offset = u32::from_be_bytes(iloc[14..18].try_into()?) as usize;
+    let length = u32::from_be_bytes(iloc[18..22].try_into()?) as usize;
+    assert_eq!(&heic[offset..offset + length], image);
+    let mdat = bmff::boxes(&heic).find(b"mdat").unwrap();
+    assert_eq!(mdat.data_offset(), offset);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cr3_heif_is_not_skipped_for_an_existing_jpeg() -> Result<()> {
+    let image = [0, 0, 0, 4, 0x26, 0x01, 0xaf, 0x00];
+    let temp = temp_dir();
+    let path = write_temp(
+        &temp,
+        "hdr_never.cr3",
+        &cr3(&bx(b"hvcC", &[1, 2, 3, 4]), &image),
+    );
+    let out_dir = path.with_file_name("hdr_never_out");
+    std::fs::create_dir_all(&out_dir)?;
+    std::fs::write(out_dir.join("hdr_never.jpg"), b"unrelated")?;
+
+    let options = ExtractOptions {
+        overwrite: OverwritePolicy::Never,
+        ..Default::default()
+    };
+    let outcome =
+        process_file_with_options(&path, &out_dir, "hdr_never.cr3".as_ref(), &options).await?;
+    assert_eq!(
+        outcome,
+        WriteOutcome::Written(out_dir.join("hdr_never.heic"))
+    );
+    assert_eq!(std::fs::read(out_dir.join("hdr_never.jpg"))?, b"unrelated");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cr3_metadata() -> Result<()> {
+    let cmt1 = bx(
+        b"CMT1",
+        &tiff(
+            &[vec![
+                (0x10f, ASCII, 6, PAYLOAD_OFFSET),
+                (0x110, ASCII, 13, PAYLOAD_OFFSET + 8),
+            ]],
+            b"Canon\0\0\0Canon EOS R5\0",
+        ),
+    );
+    let cmt2 = bx(
+        b"CMT2",
+        &tiff(
+            &[vec![(0x9003, ASCII, 20, PAYLOAD_OFFSET)]],
+            b"2024:12:24 18:30:00\0",
+        ),
+    );
+    let mut image = vec![0xff, 0xd8];
+    image.resize(0x100, 0);
+    let raw = cr3_with_metadata(&[cmt1, cmt2], &bx(b"JPEG", &[]), &image);
+    let temp = temp_dir();
+    let path = write_temp(&temp, "metadata.cr3", &raw);
+
+    let metadata = read_metadata(&path).await?;
+    assert_eq!(metadata.make.as_deref(), Some("Canon"));
+    assert_eq!(metadata.model.as_deref(), Some("Canon EOS R5"));
+    assert_eq!(
+        metadata.date_time_original.as_deref(),
+        Some("2024:12:24 18:30:00")
+    );
+    Ok(())
+}
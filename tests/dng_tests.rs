@@ -0,0 +1,133 @@
+mod common;
+
+use anyhow::Result;
+use common::{bigtiff, ifd_bytes, temp_dir, tiff, write_temp, Entry, PAYLOAD_OFFSET};
+use jpgfromraw::parser::process_file_with_options;
+use jpgfromraw::{find_embedded_jpeg, ExtractOptions, PreviewColorSpace, PreviewKind};
+
+const BYTE: u16 = 1;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const LONG8: u16 = 16;
+
+const COMPRESSION_JPEG: u32 = 7;
+const COMPRESSION_JPEG_XL: u32 = 52546;
+
+/// One SubIFD's image: (NewSubfileType, Compression, data, extra entries).
+type Image<'a> = (u32, u32, &'a [u8], Vec<Entry>);
+
+/// Build a DNG whose IFD0 has the given images as SubIFDs.
+fn dng(images: &[Image]) -> Vec<u8> {
+    // The SubIFD offsets go first, then the IFDs, then the image data.
+    let data_len: usize = images.iter().map(|(_, _, data, _)| data.len()).sum();
+    let ifds_start = 4 * images.len() as u32;
+    let mut data_offset = 0x200;
+    let mut offsets = Vec::new();
+    let mut ifds = Vec::new();
+    let mut data = Vec::new();
+    for (subfile_type, compression, image, extra) in images {
+        offsets.extend_from_slice(&(PAYLOAD_OFFSET + ifds_start + ifds.len() as u32).to_le_bytes());
+        let mut entries = vec![
+            (0xfe, LONG, 1, *subfile_type),
+            (0x103, SHORT, 1, *compression),
+            (0x106, SHORT, 1, 6),
+            (0x111, LONG, 1, PAYLOAD_OFFSET + data_offset),
+            (0x117, LONG, 1, image.len() as u32),
+        ];
+        entries.extend_from_slice(extra);
+        ifds.extend(ifd_bytes(&entries));
+        data.extend_from_slice(image);
+        data_offset += image.len() as u32;
+    }
+    let mut payload = offsets;
+    payload.extend(ifds);
+    assert!(payload.len() <= 0x200);
+    payload.resize(0x200, 0);
+    payload.extend(data);
+    assert_eq!(payload.len(), 0x200 + data_len);
+
+    tiff(
+        &[vec![
+            (0xfe, LONG, 1, 1),
+            (0x14a, LONG, images.len() as u32, PAYLOAD_OFFSET),
+            (0xc612, BYTE, 4, 0x0701),
+        ]],
+        &payload,
+    )
+}
+
+fn image(header: &[u8], len: usize) -> Vec<u8> {
+    let mut image = header.to_vec();
+    image.resize(len, 0);
+    image
+}
+
+#[tokio::test]
+async fn test_dng_skips_main_image_and_reports_color_space() -> Result<()> {
+    // A lossy DNG: the main image is a bigger JPEG than the preview, but must not be chosen.
+    let preview = image(&[0xff, 0xd8], 0x20);
+    let main_image = image(&[0xff, 0xd8], 0x100);
+    let raw = dng(&[
+        (1, COMPRESSION_JPEG, &preview, vec![(0xc71a, LONG, 1, 2)]),
+        (0, COMPRESSION_JPEG, &main_image, vec![]),
+    ]);
+
+    let temp = temp_dir();
+    let path = write_temp(&temp, "lossy.dng", &raw);
+    let jpeg = find_embedded_jpeg(&path, &ExtractOptions::default()).await?;
+    assert_eq!(jpeg.length(), preview.len());
+    assert_eq!(jpeg.kind(), PreviewKind::Jpeg);
+    assert_eq!(jpeg.color_space(), Some(PreviewColorSpace::Srgb));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dng_jxl_preview_is_opt_in() -> Result<()> {
+    let jpeg_preview = image(&[0xff, 0xd8], 0x20);
+    let jxl_preview = image(&[0xff, 0x0a], 0x40);
+    let raw = dng(&[
+        (1, COMPRESSION_JPEG, &jpeg_preview, vec![]),
+        (1, COMPRESSION_JPEG_XL, &jxl_preview, vec![]),
+    ]);
+    let temp = temp_dir();
+    let path = write_temp(&temp, "jxl.dng", &raw);
+
+    let jpeg = find_embedded_jpeg(&path, &ExtractOptions::default()).await?;
+    assert_eq!(jpeg.kind(), PreviewKind::Jpeg);
+
+    let options = ExtractOptions {
+        allow_jxl: true,
+        ..Default::default()
+    };
+    let jxl = find_embedded_jpeg(&path, &options).await?;
+    assert_eq!(jxl.kind(), PreviewKind::JpegXl);
+    assert_eq!(jxl.length(), jxl_preview.len());
+
+    let out_dir = path.with_file_name("jxl_out");
+    process_file_with_options(&path, &out_dir, "jxl.dng".as_ref(), &options).await?;
+    assert_eq!(std::fs::read(out_dir.join("jxl.jxl"))?, jxl_preview);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bigtiff_dng_preview() -> Result<()> {
+    let preview = image(&[0xff, 0xd8], 0x20);
+    let raw = bigtiff(
+        &[
+            vec![(0xfe, LONG, 1, 0), (0xc612, BYTE, 4, 0x0701)],
+            vec![
+                (0xfe, LONG, 1, 1),
+                (0x103, SHORT, 1, COMPRESSION_JPEG.into()),
+                (0x111, LONG8, 1, PAYLOAD_OFFSET.into()),
+                (0x117, LONG8, 1, preview.len() as u64),
+            ],
+        ],
+        &preview,
+    );
+    let temp = temp_dir();
+    let path = write_temp(&temp, "big.dng", &raw);
+    let jpeg = find_embedded_jpeg(&path, &ExtractOptions::default()).await?;
+    assert_eq!(jpeg.offset(), PAYLOAD_OFFSET as usize);
+    assert_eq!(jpeg.length(), preview.len());
+    Ok(())
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use anyhow::Result;
+use common::{temp_dir, tiff, write_temp, PAYLOAD_OFFSET, TINY_JPEG};
+use jpgfromraw::{
+    find_all_embedded_jpegs, process_file_with_report, ExtractOptions, PreviewKind, WriteOutcome,
+};
+
+const LONG: u16 = 4;
+
+#[tokio::test]
+async fn test_dry_run_writes_nothing() -> Result<()> {
+    let raw = tiff(
+        &[vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET),
+            (0x202, LONG, 1, TINY_JPEG.len() as u32),
+        ]],
+        TINY_JPEG,
+    );
+    let temp = temp_dir();
+    let path = write_temp(&temp, "dry_run.dng", &raw);
+    let out_dir = path.with_file_name("dry_run_out");
+
+    for template in [None, Some("{stem}_{seq}".parse()?)] {
+        let options = ExtractOptions {
+            dry_run: true,
+            name_template: template,
+            ..Default::default()
+        };
+        let report =
+            process_file_with_report(&path, &out_dir, "dry_run.dng".as_ref(), &options).await?;
+        assert!(matches!(report.outcome, WriteOutcome::Written(_)));
+        assert_eq!(report.preview.unwrap().length(), TINY_JPEG.len());
+    }
+    assert!(!out_dir.exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_all_embedded_jpegs() -> Result<()> {
+    let mut payload = TINY_JPEG.to_vec();
+    payload.resize(0x10, 0);
+    payload.extend_from_slice(TINY_JPEG);
+    payload.extend_from_slice(TINY_JPEG);
+    let ifds = vec![
+        vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET),
+            (0x202, LONG, 1, TINY_JPEG.len() as u32),
+        ],
+        vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET + 0x10),
+            (0x202, LONG, 1, 2 * TINY_JPEG.len() as u32),
+        ],
+        // Past the end of the file, so left out.
+        vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET + 0x1000),
+            (0x202, LONG, 1, 4),
+        ],
+    ];
+    let temp = temp_dir();
+    let path = write_temp(&temp, "all_previews.dng", &tiff(&ifds, &payload));
+
+    let previews = find_all_embedded_jpegs(&path).await?;
+    let found: Vec<_> = previews
+        .iter()
+        .map(|preview| (preview.offset(), preview.length(), preview.kind()))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (PAYLOAD_OFFSET as usize, 4, PreviewKind::Jpeg),
+            (PAYLOAD_OFFSET as usize + 0x10, 8, PreviewKind::Jpeg),
+        ]
+    );
+    Ok(())
+}
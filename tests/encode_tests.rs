@@ -0,0 +1,53 @@
+use anyhow::Result;
+use jpeg_encoder::{ColorType, Encoder};
+use jpgfromraw::encode::{encode_preview, OutputFormat};
+
+fn encode(width: u16, height: u16) -> Result<Vec<u8>> {
+    let pixels = vec![100; usize::from(width) * usize::from(height) * 3];
+    let mut out = Vec::new();
+    Encoder::new(&mut out, 90).encode(&pixels, width, height, ColorType::Rgb)?;
+    Ok(out)
+}
+
+#[test]
+fn test_encode_png_applies_orientation_and_size() -> Result<()> {
+    let png_data = encode_preview(&encode(64, 32)?, 6, OutputFormat::Png, Some(32))?;
+    let decoder = png::Decoder::new(&png_data[..]);
+    let reader = decoder.read_info()?;
+    let info = reader.info();
+    assert_eq!((info.width, info.height), (16, 32));
+    Ok(())
+}
+
+#[test]
+fn test_encode_webp() -> Result<()> {
+    let jpeg = encode(64, 32)?;
+    for format in [
+        OutputFormat::Webp { quality: 80 },
+        OutputFormat::WebpLossless,
+    ] {
+        let webp = encode_preview(&jpeg, 1, format, None)?;
+        assert_eq!(&webp[..4], b"RIFF");
+        assert_eq!(&webp[8..12], b"WEBP");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_encode_webp_too_big_is_an_error() -> Result<()> {
+    // WebP can't be more than 16383 pixels on either side.
+    let jpeg = encode(16384, 8)?;
+    for format in [
+        OutputFormat::Webp { quality: 80 },
+        OutputFormat::WebpLossless,
+    ] {
+        assert!(encode_preview(&jpeg, 1, format, None).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_encode_jpeg_is_rejected() -> Result<()> {
+    assert!(encode_preview(&encode(8, 8)?, 1, OutputFormat::Jpeg, None).is_err());
+    Ok(())
+}
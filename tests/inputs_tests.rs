@@ -0,0 +1,105 @@
+mod common;
+
+use anyhow::Result;
+use common::temp_dir;
+use jpgfromraw::inputs::{collect, parse_file_list, Input};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+#[test]
+fn test_parse_file_list() -> Result<()> {
+    assert_eq!(
+        parse_file_list(b"a.cr2\r\nb c.nef\n\nsub/d.dng\n")?,
+        [PathBuf::from("a.cr2"), "b c.nef".into(), "sub/d.dng".into()]
+    );
+    // NUL separated, so newlines are part of the names.
+    assert_eq!(
+        parse_file_list(b"a.cr2\0b\nc.nef\0")?,
+        [PathBuf::from("a.cr2"), "b\nc.nef".into()]
+    );
+    assert!(parse_file_list(b"")?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_collect_files_and_directories() -> Result<()> {
+    let temp = temp_dir();
+    let dir = temp.path();
+    std::fs::create_dir_all(dir.join("in/sub"))?;
+    std::fs::write(dir.join("in/sub/a.dng"), b"raw")?;
+    std::fs::write(dir.join("in/notes.txt"), b"not a raw")?;
+    std::fs::write(dir.join("b.raw.tmp"), b"raw")?;
+
+    let extensions: HashSet<_> = ["dng"].map(OsString::from).into();
+    let paths = [
+        dir.join("in"),
+        dir.join("b.raw.tmp"),
+        dir.join("in/sub/a.dng"),
+        dir.join("missing.dng"),
+    ];
+    let inputs = collect(&paths, &extensions).await?;
+    assert_eq!(
+        inputs,
+        [
+            Input {
+                path: dir.join("in/sub/a.dng"),
+                relative_path: "in/sub/a.dng".into(),
+            },
+            // Given directly, so taken whatever its extension.
+            Input {
+                path: dir.join("b.raw.tmp"),
+                relative_path: "b.raw.tmp".into(),
+            },
+            // Left to fail when it's processed.
+            Input {
+                path: dir.join("missing.dng"),
+                relative_path: "missing.dng".into(),
+            },
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_collect_keeps_same_names_apart() -> Result<()> {
+    let temp = temp_dir();
+    let dir = temp.path();
+    for card in ["card1", "card2"] {
+        std::fs::create_dir_all(dir.join(card).join("DCIM"))?;
+        std::fs::write(dir.join(card).join("DCIM/IMG_1.dng"), b"raw")?;
+    }
+
+    let extensions: HashSet<_> = ["dng"].map(OsString::from).into();
+    let inputs = collect(&[dir.join("card1"), dir.join("card2")], &extensions).await?;
+    let relative_paths: Vec<_> = inputs.iter().map(|input| &input.relative_path).collect();
+    assert_eq!(
+        relative_paths,
+        [
+            Path::new("card1/DCIM/IMG_1.dng"),
+            Path::new("card2/DCIM/IMG_1.dng")
+        ]
+    );
+
+    let files = [
+        dir.join("card1/DCIM/IMG_1.dng"),
+        dir.join("card2/DCIM/../DCIM/IMG_1.dng"),
+        dir.join("card2/./DCIM/IMG_1.dng"),
+    ];
+    let inputs = collect(&files, &extensions).await?;
+    let relative_paths: Vec<_> = inputs.iter().map(|input| &input.relative_path).collect();
+    assert_eq!(
+        relative_paths,
+        [
+            Path::new("card1/DCIM/IMG_1.dng"),
+            Path::new("card2/DCIM/IMG_1.dng")
+        ]
+    );
+
+    // On their own, they're relative to themselves as before.
+    let inputs = collect(&[dir.join("card1")], &extensions).await?;
+    assert_eq!(inputs[0].relative_path, Path::new("DCIM/IMG_1.dng"));
+    let inputs = collect(&files[..1], &extensions).await?;
+    assert_eq!(inputs[0].relative_path, Path::new("IMG_1.dng"));
+    Ok(())
+}
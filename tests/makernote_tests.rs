@@ -0,0 +1,268 @@
+mod common;
+
+use anyhow::Result;
+use common::{ifd_bytes, temp_dir, tiff, write_temp, PAYLOAD_OFFSET, TINY_JPEG};
+use jpgfromraw::parser::{find_all_embedded_jpegs, process_file_bytes, FindJpegType};
+
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const UNDEFINED: u16 = 7;
+
+// Where things go in the payload.
+const EXIF: u32 = 0x10;
+const MAKER_NOTE: u32 = 0x40;
+const PREVIEW: u32 = 0xc0;
+const THUMBNAIL: u32 = 0x140;
+
+/// A RAW with a tiny thumbnail in IFD0, and `maker_note` in the EXIF IFD. `preview` is put at
+/// [`PREVIEW`] for the MakerNote to point at.
+fn raw_with_maker_note(make: &str, maker_note: &[u8], preview: &[u8]) -> Vec<u8> {
+    let mut payload = vec![0; THUMBNAIL as usize];
+    payload[..make.len()].copy_from_slice(make.as_bytes());
+
+    let exif = ifd_bytes(&[(
+        0x927c,
+        UNDEFINED,
+        maker_note.len() as u32,
+        PAYLOAD_OFFSET + MAKER_NOTE,
+    )]);
+    payload[EXIF as usize..][..exif.len()].copy_from_slice(&exif);
+    payload[MAKER_NOTE as usize..][..maker_note.len()].copy_from_slice(maker_note);
+    payload[PREVIEW as usize..][..preview.len()].copy_from_slice(preview);
+    payload.extend_from_slice(TINY_JPEG);
+
+    tiff(
+        &[vec![
+            (0x10f, ASCII, make.len() as u32 + 1, PAYLOAD_OFFSET),
+            (0x201, LONG, 1, PAYLOAD_OFFSET + THUMBNAIL),
+            (0x202, LONG, 1, TINY_JPEG.len() as u32),
+            (0x8769, LONG, 1, PAYLOAD_OFFSET + EXIF),
+        ]],
+        &payload,
+    )
+}
+
+/// A JPEG-ish preview bigger than [`TINY_JPEG`].
+fn preview() -> Vec<u8> {
+    let mut preview = vec![0xff, 0xd8, 0xff, 0xe0];
+    preview.resize(62, 0);
+    preview.extend_from_slice(&[0xff, 0xd9]);
+    preview
+}
+
+async fn assert_largest_and_smallest(name: &str, raw: &[u8], preview: &[u8]) -> Result<()> {
+    let temp = temp_dir();
+    let path = write_temp(&temp, name, raw);
+    let largest = process_file_bytes(&path, FindJpegType::Largest).await?;
+    assert!(largest.ends_with(&preview[2..]));
+    let smallest = process_file_bytes(&path, FindJpegType::Smallest).await?;
+    assert!(smallest.ends_with(&TINY_JPEG[2..]));
+    Ok(())
+}
+
+/// Check that the MakerNote's preview is found exactly where [`raw_with_maker_note`] put it, and
+/// is chosen over the thumbnail.
+async fn assert_maker_note_preview(name: &str, raw: &[u8], preview: &[u8]) -> Result<()> {
+    let temp = temp_dir();
+    let path = write_temp(&temp, name, raw);
+    let found = find_all_embedded_jpegs(&path).await?;
+    assert!(
+        found
+            .iter()
+            .any(|info| info.offset() == (PAYLOAD_OFFSET + PREVIEW) as usize
+                && info.length() == preview.len()),
+        "MakerNote preview not found in {:?}",
+        found
+            .iter()
+            .map(|info| (info.offset(), info.length()))
+            .collect::<Vec<_>>()
+    );
+    assert_largest_and_smallest(name, raw, preview).await
+}
+
+#[tokio::test]
+async fn test_nikon_maker_note_preview() -> Result<()> {
+    // A complete TIFF structure starts 10 bytes in, after the version, and PreviewIFD's offsets
+    // are relative to it.
+    const BASE: u32 = MAKER_NOTE + 10;
+    let preview = preview();
+    let mut maker_note = b"Nikon\0\x02\x10\0\0II*\0\x08\0\0\0".to_vec();
+    let main_ifd = ifd_bytes(&[(0x11, LONG, 1, 0)]);
+    let preview_ifd_offset = 8 + main_ifd.len() as u32;
+    maker_note.extend(ifd_bytes(&[(0x11, LONG, 1, preview_ifd_offset)]));
+    maker_note.extend(ifd_bytes(&[
+        (0x201, LONG, 1, PREVIEW - BASE),
+        (0x202, LONG, 1, preview.len() as u32),
+    ]));
+    let raw = raw_with_maker_note("NIKON CORPORATION", &maker_note, &preview);
+    assert_maker_note_preview("nikon_maker_note.nef", &raw, &preview).await
+}
+
+#[tokio::test]
+async fn test_samsung_maker_note_preview() -> Result<()> {
+    // A bare IFD, with offsets relative to the main TIFF header.
+    let preview = preview();
+    let main_ifd = ifd_bytes(&[(0x35, LONG, 1, 0)]);
+    let mut maker_note = ifd_bytes(&[(
+        0x35,
+        LONG,
+        1,
+        PAYLOAD_OFFSET + MAKER_NOTE + main_ifd.len() as u32,
+    )]);
+    maker_note.extend(ifd_bytes(&[
+        (0x201, LONG, 1, PAYLOAD_OFFSET + PREVIEW),
+        (0x202, LONG, 1, preview.len() as u32),
+    ]));
+    let raw = raw_with_maker_note("SAMSUNG", &maker_note, &preview);
+    assert_maker_note_preview("samsung_maker_note.srw", &raw, &preview).await
+}
+
+#[tokio::test]
+async fn test_olympus_camera_settings_preview() -> Result<()> {
+    // "OLYMPUS\0" MakerNotes have offsets relative to the start of the MakerNote, and the IFD
+    // after a 12 byte header.
+    let preview = preview();
+    let main_ifd = ifd_bytes(&[(0x2020, LONG, 1, 0)]);
+    let camera_settings = ifd_bytes(&[
+        (0x100, SHORT, 1, 1),
+        (0x101, LONG, 1, PREVIEW - MAKER_NOTE),
+        (0x102, LONG, 1, preview.len() as u32),
+    ]);
+    let mut maker_note = b"OLYMPUS\0II\x03\0".to_vec();
+    let camera_settings_offset = maker_note.len() + main_ifd.len();
+    // CameraSettings as an UNDEFINED blob holding the IFD, as older bodies write it.
+    maker_note.extend(ifd_bytes(&[(
+        0x2020,
+        UNDEFINED,
+        camera_settings.len() as u32,
+        camera_settings_offset as u32,
+    )]));
+    maker_note.extend(camera_settings);
+    let raw = raw_with_maker_note("OLYMPUS IMAGING CORP.", &maker_note, &preview);
+    assert_maker_note_preview("olympus_maker_note.orf", &raw, &preview).await
+}
+
+#[tokio::test]
+async fn test_olympus_old_maker_note_preview() -> Result<()> {
+    // "OLYMP\0" MakerNotes have offsets relative to the main TIFF header, and PreviewImageStart
+    // and Length in the main IFD.
+    let preview = preview();
+    let mut maker_note = b"OLYMP\0\x01\0".to_vec();
+    maker_note.extend(ifd_bytes(&[
+        (0x88, LONG, 1, PAYLOAD_OFFSET + PREVIEW),
+        (0x89, LONG, 1, preview.len() as u32),
+    ]));
+    let raw = raw_with_maker_note("OLYMPUS OPTICAL CO.,LTD", &maker_note, &preview);
+    assert_maker_note_preview("olympus_old_maker_note.orf", &raw, &preview).await
+}
+
+#[tokio::test]
+async fn test_pentax_maker_note_preview() -> Result<()> {
+    // "PENTAX \0" MakerNotes have offsets relative to the start of the MakerNote.
+    let preview = preview();
+    let mut maker_note = b"PENTAX \0II".to_vec();
+    maker_note.extend(ifd_bytes(&[
+        (0x3, LONG, 1, preview.len() as u32),
+        (0x4, LONG, 1, PREVIEW - MAKER_NOTE),
+    ]));
+    let raw = raw_with_maker_note("PENTAX", &maker_note, &preview);
+    assert_maker_note_preview("pentax_maker_note.pef", &raw, &preview).await
+}
+
+#[tokio::test]
+async fn test_sony_maker_note_preview() -> Result<()> {
+    // Sony MakerNotes have offsets relative to the TIFF header.
+    let preview = preview();
+    let mut maker_note = b"SONY DSC \0\0\0".to_vec();
+    maker_note.extend(ifd_bytes(&[(
+        0x2001,
+        UNDEFINED,
+        preview.len() as u32,
+        PAYLOAD_OFFSET + PREVIEW,
+    )]));
+    let raw = raw_with_maker_note("SONY", &maker_note, &preview);
+    assert_maker_note_preview("sony_maker_note.arw", &raw, &preview).await
+}
+
+#[tokio::test]
+async fn test_sub_ifd_preview_skips_full_resolution_image() -> Result<()> {
+    const SUB_IFDS: u32 = 0x10;
+    const RAW: u32 = 0x200;
+
+    let preview = preview();
+    let mut sensor_data = vec![0xff, 0xd8];
+    sensor_data.resize(0x100, 0);
+
+    let raw_ifd = ifd_bytes(&[
+        (0xfe, LONG, 1, 0),
+        (0x201, LONG, 1, PAYLOAD_OFFSET + RAW),
+        (0x202, LONG, 1, sensor_data.len() as u32),
+    ]);
+    let preview_ifd = ifd_bytes(&[
+        (0xfe, LONG, 1, 1),
+        (0x201, LONG, 1, PAYLOAD_OFFSET + PREVIEW),
+        (0x202, LONG, 1, preview.len() as u32),
+    ]);
+    let offsets = [
+        PAYLOAD_OFFSET + SUB_IFDS,
+        PAYLOAD_OFFSET + SUB_IFDS + raw_ifd.len() as u32,
+    ];
+
+    let mut payload = vec![0; RAW as usize];
+    payload[..8].copy_from_slice(&[offsets[0].to_le_bytes(), offsets[1].to_le_bytes()].concat());
+    payload[SUB_IFDS as usize..][..raw_ifd.len()].copy_from_slice(&raw_ifd);
+    payload[SUB_IFDS as usize + raw_ifd.len()..][..preview_ifd.len()].copy_from_slice(&preview_ifd);
+    payload[PREVIEW as usize..][..preview.len()].copy_from_slice(&preview);
+    payload.extend_from_slice(&sensor_data);
+
+    let raw = tiff(&[vec![(0x14a, LONG, 2, PAYLOAD_OFFSET)]], &payload);
+    let temp = temp_dir();
+    let path = write_temp(&temp, "sub_ifds.arw", &raw);
+    let largest = process_file_bytes(&path, FindJpegType::Largest).await?;
+    assert!(largest.ends_with(&preview[2..]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bogus_candidates_are_never_chosen() -> Result<()> {
+    const SUB_IFDS: u32 = 0x10;
+    const NOT_JPEG: u32 = 0x100;
+
+    let preview = preview();
+    // One SubIFD runs off the end of the file, and the other points at something else entirely.
+    let past_end = ifd_bytes(&[
+        (0xfe, LONG, 1, 1),
+        (0x201, LONG, 1, PAYLOAD_OFFSET + PREVIEW),
+        (0x202, LONG, 1, 0x10_0000),
+    ]);
+    let not_jpeg = ifd_bytes(&[
+        (0xfe, LONG, 1, 1),
+        (0x201, LONG, 1, PAYLOAD_OFFSET + NOT_JPEG),
+        (0x202, LONG, 1, 0x100),
+    ]);
+    let offsets = [
+        PAYLOAD_OFFSET + SUB_IFDS,
+        PAYLOAD_OFFSET + SUB_IFDS + past_end.len() as u32,
+    ];
+
+    let mut payload = vec![0; NOT_JPEG as usize + 0x100];
+    payload[..8].copy_from_slice(&[offsets[0].to_le_bytes(), offsets[1].to_le_bytes()].concat());
+    payload[SUB_IFDS as usize..][..past_end.len()].copy_from_slice(&past_end);
+    payload[SUB_IFDS as usize + past_end.len()..][..not_jpeg.len()].copy_from_slice(&not_jpeg);
+    payload[PREVIEW as usize..][..preview.len()].copy_from_slice(&preview);
+
+    let raw = tiff(
+        &[vec![
+            (0x14a, LONG, 2, PAYLOAD_OFFSET),
+            (0x201, LONG, 1, PAYLOAD_OFFSET + PREVIEW),
+            (0x202, LONG, 1, preview.len() as u32),
+        ]],
+        &payload,
+    );
+    let temp = temp_dir();
+    let path = write_temp(&temp, "bogus_sub_ifds.arw", &raw);
+    let largest = process_file_bytes(&path, FindJpegType::Largest).await?;
+    assert!(largest.ends_with(&preview[2..]));
+    Ok(())
+}
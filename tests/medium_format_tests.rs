@@ -0,0 +1,107 @@
+mod common;
+
+use anyhow::Result;
+use common::{ifd_bytes, temp_dir, tiff, write_temp, PAYLOAD_OFFSET, TINY_JPEG};
+use jpgfromraw::parser::{process_file_bytes, FindJpegType};
+use jpgfromraw::{find_embedded_jpeg, ExtractOptions};
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+/// A JPEG-ish preview bigger than [`TINY_JPEG`].
+fn preview() -> Vec<u8> {
+    let mut preview = vec![0xff, 0xd8, 0xff, 0xe0];
+    preview.resize(62, 0);
+    preview.extend_from_slice(&[0xff, 0xd9]);
+    preview
+}
+
+// A TIFF header pointing at IFD0, then the Phase One header at 8.
+const BASE: usize = 8;
+const DIR: usize = 0x18;
+const IFD0: usize = 0x40;
+const THUMBNAIL: usize = 0x60;
+const PREVIEW: usize = 0x80;
+
+/// An IIQ with [`preview`] in the Phase One directory, the camera orientation `rotation` in tag
+/// 0x100, and [`TINY_JPEG`] in IFD0.
+fn iiq(rotation: u32) -> Vec<u8> {
+    let preview = preview();
+    let mut raw = vec![0; PREVIEW];
+    raw[..8].copy_from_slice(&[b"II*\0".as_slice(), &(IFD0 as u32).to_le_bytes()].concat());
+    raw[BASE..BASE + 8].copy_from_slice(b"IIII\x01waR");
+    raw[BASE + 8..BASE + 12].copy_from_slice(&((DIR - BASE) as u32).to_le_bytes());
+
+    let entries: [[u32; 4]; 2] = [
+        [0x100, 1, 4, rotation],
+        [0x200, 7, preview.len() as u32, (PREVIEW - BASE) as u32],
+    ];
+    let mut dir = (entries.len() as u32).to_le_bytes().to_vec();
+    dir.extend_from_slice(&[0; 4]);
+    dir.extend(entries.iter().flatten().flat_map(|v| v.to_le_bytes()));
+    raw[DIR..DIR + dir.len()].copy_from_slice(&dir);
+
+    let ifd0 = ifd_bytes(&[
+        (0x201, LONG, 1, THUMBNAIL as u32),
+        (0x202, LONG, 1, TINY_JPEG.len() as u32),
+    ]);
+    raw[IFD0..IFD0 + ifd0.len()].copy_from_slice(&ifd0);
+    raw[THUMBNAIL..THUMBNAIL + TINY_JPEG.len()].copy_from_slice(TINY_JPEG);
+    raw.extend_from_slice(&preview);
+    raw
+}
+
+#[tokio::test]
+async fn test_iiq_preview_in_phase_one_directory() -> Result<()> {
+    let temp = temp_dir();
+    let path = write_temp(&temp, "phase_one.iiq", &iiq(0));
+    let info = find_embedded_jpeg(&path, &ExtractOptions::default()).await?;
+    assert_eq!(info.offset(), PREVIEW);
+    assert_eq!(info.length(), preview().len());
+    assert_eq!(info.orientation(), Some(1));
+
+    let smallest = process_file_bytes(&path, FindJpegType::Smallest).await?;
+    assert!(smallest.ends_with(&TINY_JPEG[2..]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_iiq_rotated() -> Result<()> {
+    // Clockwise quarter turns, as dcraw reads them, and the EXIF orientation for each. The upper
+    // bits aren't part of the rotation.
+    for (rotation, orientation) in [(1, 6), (2, 8), (3, 3), (0x11, 6)] {
+        let temp = temp_dir();
+        let path = write_temp(
+            &temp,
+            &format!("phase_one_rotated_{}.iiq", rotation),
+            &iiq(rotation),
+        );
+        let info = find_embedded_jpeg(&path, &ExtractOptions::default()).await?;
+        assert_eq!(
+            info.orientation(),
+            Some(orientation),
+            "rotation {}",
+            rotation
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_3fr_jpeg_strip_preview() -> Result<()> {
+    let preview = preview();
+    let raw = tiff(
+        &[vec![
+            (0x103, SHORT, 1, 7),
+            (0x106, SHORT, 1, 6),
+            (0x111, LONG, 1, PAYLOAD_OFFSET),
+            (0x117, LONG, 1, preview.len() as u32),
+        ]],
+        &preview,
+    );
+    let temp = temp_dir();
+    let path = write_temp(&temp, "hasselblad.3fr", &raw);
+    let jpeg = process_file_bytes(&path, FindJpegType::Largest).await?;
+    assert!(jpeg.ends_with(&preview[2..]));
+    Ok(())
+}
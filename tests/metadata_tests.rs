@@ -0,0 +1,93 @@
+mod common;
+
+use anyhow::Result;
+use common::{ifd_offset, temp_dir, tiff, write_temp, PAYLOAD_OFFSET};
+use jpgfromraw::parser::metadata::{read_metadata, GpsPosition, Rational};
+
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+
+#[tokio::test]
+async fn test_read_metadata() -> Result<()> {
+    let mut payload = Vec::new();
+    let mut add = |data: &[u8]| {
+        let offset = PAYLOAD_OFFSET + payload.len() as u32;
+        payload.extend_from_slice(data);
+        offset
+    };
+    let make = add(b"Canon\0");
+    let model = add(b"Canon EOS R5\0");
+    let date = add(b"2024:12:24 18:30:00\0");
+    let offset_time = add(b"+01:00\0");
+    let exposure = add(&[1, 0, 0, 0, 250, 0, 0, 0]);
+    let f_number = add(&[28, 0, 0, 0, 10, 0, 0, 0]);
+    let lens = add(b"RF24-70mm F2.8 L IS USM\0");
+    let latitude = add(&[
+        50, 0, 0, 0, 1, 0, 0, 0, 30, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0,
+    ]);
+    let longitude = add(&[
+        14, 0, 0, 0, 1, 0, 0, 0, 15, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0,
+    ]);
+
+    let mut ifds = vec![
+        vec![
+            (0x10f, ASCII, 6, make),
+            (0x110, ASCII, 13, model),
+            (0x8769, LONG, 1, 0),
+            (0x8825, LONG, 1, 0),
+        ],
+        vec![
+            (0x829a, RATIONAL, 1, exposure),
+            (0x829d, RATIONAL, 1, f_number),
+            (0x8827, SHORT, 1, 400),
+            (0x9003, ASCII, 20, date),
+            (0x9011, ASCII, 7, offset_time),
+            (0xa434, ASCII, 24, lens),
+        ],
+        vec![
+            (0x1, ASCII, 2, u32::from(b'N')),
+            (0x2, RATIONAL, 3, latitude),
+            (0x3, ASCII, 2, u32::from(b'W')),
+            (0x4, RATIONAL, 3, longitude),
+        ],
+    ];
+    ifds[0][2].3 = ifd_offset(&ifds, 1);
+    ifds[0][3].3 = ifd_offset(&ifds, 2);
+
+    let temp = temp_dir();
+    let path = write_temp(&temp, "metadata.tif", &tiff(&ifds, &payload));
+    let metadata = read_metadata(&path).await?;
+
+    assert_eq!(metadata.make.as_deref(), Some("Canon"));
+    assert_eq!(metadata.model.as_deref(), Some("Canon EOS R5"));
+    assert_eq!(
+        metadata.lens_model.as_deref(),
+        Some("RF24-70mm F2.8 L IS USM")
+    );
+    assert_eq!(
+        metadata.date_time_original.as_deref(),
+        Some("2024:12:24 18:30:00")
+    );
+    assert_eq!(metadata.offset_time_original.as_deref(), Some("+01:00"));
+    assert_eq!(
+        metadata.exposure_time,
+        Some(Rational {
+            numerator: 1,
+            denominator: 250
+        })
+    );
+    assert_eq!(metadata.f_number, Some(2.8));
+    assert_eq!(metadata.iso, Some(400));
+    assert_eq!(metadata.focal_length, None);
+    assert_eq!(
+        metadata.gps,
+        Some(GpsPosition {
+            latitude: 50.5,
+            longitude: -14.25,
+            altitude: None,
+        })
+    );
+    Ok(())
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use anyhow::Result;
+use common::{temp_dir, tiff, write_temp, PAYLOAD_OFFSET, TINY_JPEG};
+use jpgfromraw::mpf;
+use jpgfromraw::parser::{process_file_with_options, ExtractOptions};
+
+/// Where the MP header is in [`primary_with_mpf`]: after SOI, the APP2 marker and length, and
+/// "MPF\0".
+const MP_HEADER: usize = 10;
+
+/// A JPEG with an MPF segment listing itself and [`TINY_JPEG`] straight after it.
+fn primary_with_mpf() -> Vec<u8> {
+    let mut mp = b"MM\0*".to_vec();
+    mp.extend_from_slice(&8u32.to_be_bytes());
+    mp.extend_from_slice(&2u16.to_be_bytes());
+    for (tag, typ, count, value) in [(0xb001u16, 4u16, 1u32, 2u32), (0xb002, 7, 32, 38)] {
+        mp.extend_from_slice(&tag.to_be_bytes());
+        mp.extend_from_slice(&typ.to_be_bytes());
+        mp.extend_from_slice(&count.to_be_bytes());
+        mp.extend_from_slice(&value.to_be_bytes());
+    }
+    mp.extend_from_slice(&0u32.to_be_bytes());
+    let entries_at = mp.len();
+    mp.resize(entries_at + 32, 0);
+
+    let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe2];
+    jpeg.extend_from_slice(&((mp.len() + 6) as u16).to_be_bytes());
+    jpeg.extend_from_slice(b"MPF\0");
+    jpeg.extend_from_slice(&mp);
+    jpeg.extend_from_slice(&[0xff, 0xd9]);
+
+    let len = jpeg.len() as u32;
+    let entries = &mut jpeg[MP_HEADER + entries_at..];
+    entries[0..4].copy_from_slice(&0x030000u32.to_be_bytes());
+    entries[4..8].copy_from_slice(&len.to_be_bytes());
+    entries[16..20].copy_from_slice(&0x010001u32.to_be_bytes());
+    entries[20..24].copy_from_slice(&(TINY_JPEG.len() as u32).to_be_bytes());
+    entries[24..28].copy_from_slice(&(len - MP_HEADER as u32).to_be_bytes());
+    jpeg
+}
+
+#[test]
+fn test_fix_up_corrects_first_image_size() {
+    let primary = primary_with_mpf();
+    let mut jpeg = [primary.as_slice(), TINY_JPEG].concat();
+    mpf::fix_up(&mut jpeg, 32);
+    let entries = mpf::parse(&jpeg).unwrap().entries().to_vec();
+    assert_eq!(entries[0].size as usize, primary.len() + 32);
+    assert_eq!(entries[1].size as usize, TINY_JPEG.len());
+}
+
+#[tokio::test]
+async fn test_mpf_stripped_and_secondary_extracted() -> Result<()> {
+    let primary = primary_with_mpf();
+    // The RAW's tags only cover the primary image, like cameras do.
+    let raw = tiff(
+        &[vec![
+            (0x201, 4, 1, PAYLOAD_OFFSET),
+            (0x202, 4, 1, primary.len() as u32),
+        ]],
+        &[primary.as_slice(), TINY_JPEG].concat(),
+    );
+    let temp = temp_dir();
+    let path = write_temp(&temp, "mpf.tif", &raw);
+    let out_dir = path.with_file_name("mpf_out");
+    let options = ExtractOptions {
+        extract_mpf_images: true,
+        ..Default::default()
+    };
+    process_file_with_options(&path, &out_dir, "mpf.tif".as_ref(), &options).await?;
+
+    let jpeg = std::fs::read(out_dir.join("mpf.jpg"))?;
+    assert!(mpf::parse(&jpeg).is_none());
+    assert!(jpeg.ends_with(&[0xff, 0xd9]));
+    assert_eq!(std::fs::read(out_dir.join("mpf-mpf1.jpg"))?, TINY_JPEG);
+    Ok(())
+}
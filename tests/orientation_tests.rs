@@ -0,0 +1,248 @@
+mod common;
+
+use anyhow::Result;
+use common::{
+    ifd_bytes, temp_dir, tiff, write_temp, written_orientation, PAYLOAD_OFFSET, TINY_JPEG,
+};
+use jpgfromraw::parser::{
+    process_file_bytes, process_file_bytes_with_options, process_file_with_report, ExtractOptions,
+    FindJpegType,
+};
+use jpgfromraw::resize::ResizeOptions;
+
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const UNDEFINED: u16 = 7;
+
+fn jpeg_entries(orientation: Option<u16>) -> Vec<(u16, u16, u32, u32)> {
+    let mut entries = Vec::new();
+    if let Some(orientation) = orientation {
+        entries.push((0x112, SHORT, 1, orientation.into()));
+    }
+    entries.push((0x201, LONG, 1, PAYLOAD_OFFSET));
+    entries.push((0x202, LONG, 1, TINY_JPEG.len() as u32));
+    entries
+}
+
+#[tokio::test]
+async fn test_orientation_from_preview_ifd() -> Result<()> {
+    let raw = tiff(
+        &[vec![(0x112, SHORT, 1, 6)], jpeg_entries(Some(8))],
+        TINY_JPEG,
+    );
+    let temp = temp_dir();
+    let path = write_temp(&temp, "preview_ifd.tif", &raw);
+    let jpeg = process_file_bytes(&path, FindJpegType::Largest).await?;
+    assert_eq!(written_orientation(&jpeg), 8);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_orientation_falls_back_to_ifd0() -> Result<()> {
+    let raw = tiff(&[vec![(0x112, SHORT, 1, 6)], jpeg_entries(None)], TINY_JPEG);
+    let temp = temp_dir();
+    let path = write_temp(&temp, "ifd0_only.tif", &raw);
+    let jpeg = process_file_bytes(&path, FindJpegType::Largest).await?;
+    assert_eq!(written_orientation(&jpeg), 6);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_orientation_defaults_to_normal() -> Result<()> {
+    let raw = tiff(&[jpeg_entries(None)], TINY_JPEG);
+    let temp = temp_dir();
+    let path = write_temp(&temp, "no_orientation.tif", &raw);
+    let jpeg = process_file_bytes(&path, FindJpegType::Largest).await?;
+    assert_eq!(written_orientation(&jpeg), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_apply_orientation_rotates_and_resets_tag() -> Result<()> {
+    let mut preview = Vec::new();
+    jpeg_encoder::Encoder::new(&mut preview, 90).encode(
+        &[128; 32 * 16],
+        32,
+        16,
+        jpeg_encoder::ColorType::Luma,
+    )?;
+    let mut entries = vec![(0x112, SHORT, 1, 6)];
+    entries.push((0x201, LONG, 1, PAYLOAD_OFFSET));
+    entries.push((0x202, LONG, 1, preview.len() as u32));
+    let temp = temp_dir();
+    let path = write_temp(&temp, "rotate.tif", &tiff(&[entries], &preview));
+
+    let options = ExtractOptions {
+        apply_orientation: true,
+        ..Default::default()
+    };
+    let jpeg = process_file_bytes_with_options(&path, &options).await?;
+    assert_eq!(written_orientation(&jpeg), 1);
+
+    let mut decoder = jpeg_decoder::Decoder::new(&jpeg[..]);
+    decoder.decode()?;
+    let info = decoder.info().unwrap();
+    assert_eq!((info.width, info.height), (16, 32));
+    Ok(())
+}
+
+// Where things go in the payload.
+const EXIF: u32 = 0x10;
+const MAKER_NOTE: u32 = 0x40;
+const MAKER_NOTE_DATA: u32 = 0x100;
+const PREVIEW: u32 = 0x200;
+
+/// A RAW from `make` with `maker_note` in the EXIF IFD, and `data` after it for the MakerNote to
+/// point at. There's an IFD for each of `orientations`, with the preview in the last one, and the
+/// orientation given, if any.
+fn raw_with_maker_note(
+    make: &str,
+    orientations: &[Option<u16>],
+    maker_note: &[u8],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut payload = vec![0; PREVIEW as usize];
+    payload[..make.len()].copy_from_slice(make.as_bytes());
+    let exif = ifd_bytes(&[(
+        0x927c,
+        UNDEFINED,
+        maker_note.len() as u32,
+        PAYLOAD_OFFSET + MAKER_NOTE,
+    )]);
+    payload[EXIF as usize..][..exif.len()].copy_from_slice(&exif);
+    payload[MAKER_NOTE as usize..][..maker_note.len()].copy_from_slice(maker_note);
+    payload[MAKER_NOTE_DATA as usize..][..data.len()].copy_from_slice(data);
+    payload.extend_from_slice(TINY_JPEG);
+
+    let mut ifds: Vec<_> = orientations
+        .iter()
+        .map(|orientation| match orientation {
+            Some(orientation) => vec![(0x112, SHORT, 1, u32::from(*orientation))],
+            None => Vec::new(),
+        })
+        .collect();
+    ifds[0].insert(0, (0x10f, ASCII, make.len() as u32 + 1, PAYLOAD_OFFSET));
+    ifds[0].push((0x8769, LONG, 1, PAYLOAD_OFFSET + EXIF));
+    let last = ifds.last_mut().unwrap();
+    last.push((0x201, LONG, 1, PAYLOAD_OFFSET + PREVIEW));
+    last.push((0x202, LONG, 1, TINY_JPEG.len() as u32));
+    tiff(&ifds, &payload)
+}
+
+/// A Canon MakerNote with ShotInfo (0x4) recording `auto_rotate`, and the ShotInfo array to put
+/// after it.
+fn canon_maker_note(auto_rotate: u16) -> (Vec<u8>, Vec<u8>) {
+    let mut shot_info = [0u16; 28];
+    shot_info[27] = auto_rotate;
+    let maker_note = ifd_bytes(&[(
+        0x4,
+        SHORT,
+        shot_info.len() as u32,
+        PAYLOAD_OFFSET + MAKER_NOTE_DATA,
+    )]);
+    let data = shot_info.iter().flat_map(|v| v.to_le_bytes()).collect();
+    (maker_note, data)
+}
+
+async fn orientation_of(name: &str, raw: &[u8]) -> Result<u16> {
+    let temp = temp_dir();
+    let path = write_temp(&temp, name, raw);
+    let jpeg = process_file_bytes(&path, FindJpegType::Largest).await?;
+    Ok(written_orientation(&jpeg))
+}
+
+#[tokio::test]
+async fn test_orientation_from_canon_maker_note() -> Result<()> {
+    for (auto_rotate, expected) in [(0, 1), (1, 6), (2, 3), (3, 8)] {
+        let (maker_note, data) = canon_maker_note(auto_rotate);
+        let raw = raw_with_maker_note("Canon", &[None], &maker_note, &data);
+        assert_eq!(orientation_of("canon.cr2", &raw).await?, expected);
+    }
+    // Unknown values, like -1 when it's off, say nothing.
+    let (maker_note, data) = canon_maker_note(0xffff);
+    let raw = raw_with_maker_note("Canon", &[None], &maker_note, &data);
+    assert_eq!(orientation_of("canon_unknown.cr2", &raw).await?, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_orientation_from_pentax_maker_note() -> Result<()> {
+    for (level, expected) in [(1, 1), (2, 3), (3, 6), (4, 8)] {
+        // LevelInfo is UNDEFINED, and short enough to fit in the entry. The high nibble of its
+        // first byte is something else.
+        let mut maker_note = b"PENTAX \0II".to_vec();
+        maker_note.extend(ifd_bytes(&[(0x22b, UNDEFINED, 4, 0x10 | level)]));
+        let raw = raw_with_maker_note("PENTAX", &[None], &maker_note, &[]);
+        assert_eq!(orientation_of("pentax.pef", &raw).await?, expected);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ifd0_orientation_beats_maker_note() -> Result<()> {
+    let (maker_note, data) = canon_maker_note(1);
+    let raw = raw_with_maker_note("Canon", &[Some(8)], &maker_note, &data);
+    assert_eq!(orientation_of("canon_ifd0.cr2", &raw).await?, 8);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_preview_ifd_orientation_beats_ifd0_and_maker_note() -> Result<()> {
+    let (maker_note, data) = canon_maker_note(1);
+    let raw = raw_with_maker_note("Canon", &[Some(8), Some(3)], &maker_note, &data);
+    assert_eq!(orientation_of("canon_preview_ifd.cr2", &raw).await?, 3);
+
+    // Without one in the preview's IFD, IFD0's still wins.
+    let raw = raw_with_maker_note("Canon", &[Some(8), None], &maker_note, &data);
+    assert_eq!(orientation_of("canon_ifd1.cr2", &raw).await?, 8);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_apply_orientation_with_downscaling() -> Result<()> {
+    // Dark on the left, light on the right.
+    let pixels: Vec<u8> = (0..16)
+        .flat_map(|_| (0..32).map(|x| if x < 16 { 0 } else { 255 }))
+        .collect();
+    let mut preview = Vec::new();
+    jpeg_encoder::Encoder::new(&mut preview, 90).encode(
+        &pixels,
+        32,
+        16,
+        jpeg_encoder::ColorType::Luma,
+    )?;
+    let entries = vec![
+        (0x112, SHORT, 1, 6),
+        (0x201, LONG, 1, PAYLOAD_OFFSET),
+        (0x202, LONG, 1, preview.len() as u32),
+    ];
+    let temp = temp_dir();
+    let path = write_temp(&temp, "rotate_resize.tif", &tiff(&[entries], &preview));
+
+    let options = ExtractOptions {
+        apply_orientation: true,
+        resize: Some(ResizeOptions {
+            max_size: 16,
+            quality: 90,
+        }),
+        ..Default::default()
+    };
+    let out_dir = temp.path().join("out");
+    let report =
+        process_file_with_report(&path, &out_dir, "rotate_resize.tif".as_ref(), &options).await?;
+    // Rotated while it was downscaled, rather than losslessly at full size first.
+    let stages: Vec<_> = report.timings.iter().map(|(stage, _)| *stage).collect();
+    assert!(!stages.contains(&"apply_orientation"), "{:?}", stages);
+    let jpeg = std::fs::read(report.outcome.written().expect("written"))?;
+    assert_eq!(written_orientation(&jpeg), 1);
+
+    // Turned a quarter clockwise, so what was on the left is now at the top.
+    let mut decoder = jpeg_decoder::Decoder::new(&jpeg[..]);
+    let decoded = decoder.decode()?;
+    let info = decoder.info().unwrap();
+    assert_eq!((info.width, info.height), (8, 16));
+    assert!(decoded[8 + 4] < 64);
+    assert!(decoded[14 * 8 + 4] > 192);
+    Ok(())
+}
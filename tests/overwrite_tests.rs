@@ -0,0 +1,414 @@
+mod common;
+
+use anyhow::Result;
+use common::{temp_dir, tiff, write_temp, PAYLOAD_OFFSET, TINY_JPEG};
+use jpgfromraw::parser::{
+    process_file_in_turn, process_file_with_options, process_file_with_report,
+};
+use jpgfromraw::{output, ExtractOptions, OverwritePolicy, WriteOutcome};
+use std::path::Path;
+use tempfile::TempDir;
+
+const LONG: u16 = 4;
+
+fn raw() -> Vec<u8> {
+    tiff(
+        &[vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET),
+            (0x202, LONG, 1, TINY_JPEG.len() as u32),
+        ]],
+        TINY_JPEG,
+    )
+}
+
+/// Extract `name` into a fresh `out_dir` in `temp`, which already has `existing` at `name.jpg`.
+async fn extract_over_existing(
+    temp: &TempDir,
+    name: &str,
+    policy: OverwritePolicy,
+) -> Result<WriteOutcome> {
+    let path = write_temp(temp, name, &raw());
+    let out_dir = path.with_file_name(format!("{}_out", name));
+    std::fs::create_dir_all(&out_dir)?;
+    std::fs::write(
+        out_dir.join(Path::new(name).with_extension("jpg")),
+        b"existing",
+    )?;
+
+    let options = ExtractOptions {
+        overwrite: policy,
+        ..Default::default()
+    };
+    process_file_with_options(&path, &out_dir, name.as_ref(), &options).await
+}
+
+#[tokio::test]
+async fn test_overwrite_policies() -> Result<()> {
+    let temp = temp_dir();
+    let outcome = extract_over_existing(&temp, "always.dng", OverwritePolicy::Always).await?;
+    let written = outcome.written().expect("written");
+    assert!(std::fs::read(written)?.starts_with(&[0xff, 0xd8]));
+
+    let outcome = extract_over_existing(&temp, "never.dng", OverwritePolicy::Never).await?;
+    let WriteOutcome::Skipped(skipped) = outcome else {
+        panic!("not skipped: {:?}", outcome);
+    };
+    assert_eq!(std::fs::read(skipped)?, b"existing");
+
+    // The output was written after the RAW, so it's up to date.
+    let outcome = extract_over_existing(&temp, "if_newer.dng", OverwritePolicy::IfNewer).await?;
+    assert!(matches!(outcome, WriteOutcome::Skipped(_)));
+
+    let outcome = extract_over_existing(&temp, "rename.dng", OverwritePolicy::Rename).await?;
+    let WriteOutcome::Renamed { wanted, written } = outcome else {
+        panic!("not renamed: {:?}", outcome);
+    };
+    assert_eq!(std::fs::read(wanted)?, b"existing");
+    assert_eq!(written.file_name().unwrap(), "rename-1.jpg");
+    assert!(std::fs::read(written)?.starts_with(&[0xff, 0xd8]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_collisions_within_a_run_are_renamed() -> Result<()> {
+    let temp = temp_dir();
+    let cr2 = write_temp(&temp, "IMG_0002.CR2", &raw());
+    let dng = write_temp(&temp, "IMG_0002.DNG", &raw());
+    let out_dir = cr2.with_file_name("collision_out");
+
+    // Even overwriting is only about files from earlier runs.
+    let options = ExtractOptions::default();
+    let first =
+        process_file_with_options(&cr2, &out_dir, "IMG_0002.CR2".as_ref(), &options).await?;
+    let second =
+        process_file_with_options(&dng, &out_dir, "IMG_0002.DNG".as_ref(), &options).await?;
+
+    assert_eq!(first, WriteOutcome::Written(out_dir.join("IMG_0002.jpg")));
+    assert_eq!(
+        second,
+        WriteOutcome::Renamed {
+            wanted: out_dir.join("IMG_0002.jpg"),
+            written: out_dir.join("IMG_0002-1.jpg"),
+        }
+    );
+
+    // A new run starts with nothing claimed.
+    let again = process_file_with_options(
+        &dng,
+        &out_dir,
+        "IMG_0002.DNG".as_ref(),
+        &ExtractOptions::default(),
+    )
+    .await?;
+    assert_eq!(again, WriteOutcome::Written(out_dir.join("IMG_0002.jpg")));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_writes_leave_no_temp_files() -> Result<()> {
+    let temp = temp_dir();
+    let path = write_temp(&temp, "atomic.dng", &raw());
+    let out_dir = path.with_file_name("atomic_out");
+    std::fs::create_dir_all(&out_dir)?;
+    std::fs::write(out_dir.join("atomic.jpg"), b"truncated")?;
+
+    let options = ExtractOptions {
+        fsync: true,
+        ..Default::default()
+    };
+    process_file_with_options(&path, &out_dir, "atomic.dng".as_ref(), &options).await?;
+
+    let names = std::fs::read_dir(&out_dir)?
+        .map(|entry| Ok(entry?.file_name()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(names, ["atomic.jpg"]);
+    assert!(std::fs::read(out_dir.join("atomic.jpg"))?.starts_with(&[0xff, 0xd8]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reruns_reuse_names_in_input_order() -> Result<()> {
+    let temp = temp_dir();
+    let nef = write_temp(&temp, "IMG_0003.NEF", &raw());
+    let dng = write_temp(&temp, "IMG_0003.DNG", &raw());
+    let out_dir = nef.with_file_name("rerun_out");
+    let renamed = WriteOutcome::Renamed {
+        wanted: out_dir.join("IMG_0003.jpg"),
+        written: out_dir.join("IMG_0003-1.jpg"),
+    };
+
+    for policy in [
+        OverwritePolicy::Always,
+        OverwritePolicy::Always,
+        OverwritePolicy::Never,
+    ] {
+        let options = ExtractOptions {
+            overwrite: policy,
+            ..Default::default()
+        };
+        let nef_turn = options.claims.enqueue();
+        let dng_turn = options.claims.enqueue();
+        // The DNG is started first, but still waits for the NEF to take the plain name.
+        let (from_dng, from_nef) = tokio::join!(
+            process_file_in_turn(&dng, &out_dir, "IMG_0003.DNG".as_ref(), &options, dng_turn),
+            process_file_in_turn(&nef, &out_dir, "IMG_0003.NEF".as_ref(), &options, nef_turn),
+        );
+        let (from_dng, from_nef) = (
+            from_dng.map(|report| report.outcome),
+            from_nef.map(|report| report.outcome),
+        );
+        match policy {
+            OverwritePolicy::Never => {
+                assert_eq!(
+                    from_nef?,
+                    WriteOutcome::Skipped(out_dir.join("IMG_0003.jpg"))
+                );
+                assert_eq!(
+                    from_dng?,
+                    WriteOutcome::Skipped(out_dir.join("IMG_0003-1.jpg"))
+                );
+            }
+            _ => {
+                assert_eq!(
+                    from_nef?,
+                    WriteOutcome::Written(out_dir.join("IMG_0003.jpg"))
+                );
+                assert_eq!(from_dng?, renamed);
+            }
+        }
+    }
+
+    let mut names = std::fs::read_dir(&out_dir)?
+        .map(|entry| Ok(entry?.file_name()))
+        .collect::<Result<Vec<_>>>()?;
+    names.sort();
+    assert_eq!(names, ["IMG_0003-1.jpg", "IMG_0003.jpg"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reserved_names_are_kept_for_their_raw() -> Result<()> {
+    let temp = temp_dir();
+    let cr2 = write_temp(&temp, "IMG_0004.CR2", &raw());
+    let dng = write_temp(&temp, "IMG_0004.DNG", &raw());
+    let out_dir = cr2.with_file_name("reserved_out");
+
+    // The CR2 wrote IMG_0004.jpg last time and is unchanged, so it won't claim it itself.
+    let options = ExtractOptions::default();
+    options.claims.reserve(&cr2, [out_dir.join("IMG_0004.jpg")]);
+    let outcome =
+        process_file_with_options(&dng, &out_dir, "IMG_0004.DNG".as_ref(), &options).await?;
+    assert_eq!(
+        outcome,
+        WriteOutcome::Renamed {
+            wanted: out_dir.join("IMG_0004.jpg"),
+            written: out_dir.join("IMG_0004-1.jpg"),
+        }
+    );
+
+    // The RAW it's reserved for can still take it.
+    let outcome =
+        process_file_with_options(&cr2, &out_dir, "IMG_0004.CR2".as_ref(), &options).await?;
+    assert_eq!(outcome, WriteOutcome::Written(out_dir.join("IMG_0004.jpg")));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_raws_give_up_their_turn() -> Result<()> {
+    let temp = temp_dir();
+    let missing = temp.path().join("missing.dng");
+    let dng = write_temp(&temp, "IMG_0005.DNG", &raw());
+    let out_dir = dng.with_file_name("failed_turn_out");
+
+    let options = ExtractOptions::default();
+    let missing_turn = options.claims.enqueue();
+    let dng_turn = options.claims.enqueue();
+    let failed = process_file_in_turn(
+        &missing,
+        &out_dir,
+        "missing.dng".as_ref(),
+        &options,
+        missing_turn,
+    )
+    .await;
+    assert!(failed.is_err());
+    // This would wait forever if the failed RAW still held its turn.
+    let report =
+        process_file_in_turn(&dng, &out_dir, "IMG_0005.DNG".as_ref(), &options, dng_turn).await?;
+    assert_eq!(
+        report.outcome,
+        WriteOutcome::Written(out_dir.join("IMG_0005.jpg"))
+    );
+    Ok(())
+}
+
+/// Make `path` look like it was last touched two hours ago.
+fn age(path: &Path) -> Result<()> {
+    let mtime = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 60 * 60);
+    filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime))?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_abandoned_locks_are_broken() -> Result<()> {
+    let temp = temp_dir();
+    let dng = write_temp(&temp, "IMG_0006.DNG", &raw());
+    let out_dir = temp.path().join("out");
+    std::fs::create_dir_all(&out_dir)?;
+    let lock = out_dir.join(".IMG_0006_1.jpg.lock");
+    let extract = || async {
+        let options = ExtractOptions {
+            name_template: Some("{stem}_{seq}.jpg".parse()?),
+            ..Default::default()
+        };
+        process_file_with_options(&dng, &out_dir, "IMG_0006.DNG".as_ref(), &options).await
+    };
+
+    // Held by a process which is still going, like this one.
+    std::fs::write(&lock, std::process::id().to_string())?;
+    let outcome = extract().await?;
+    assert_eq!(
+        outcome,
+        WriteOutcome::Written(out_dir.join("IMG_0006_2.jpg"))
+    );
+    assert!(lock.is_file());
+    std::fs::remove_file(out_dir.join("IMG_0006_2.jpg"))?;
+
+    // Left so long ago that it can't still be in use.
+    age(&lock)?;
+    let outcome = extract().await?;
+    assert_eq!(
+        outcome,
+        WriteOutcome::Written(out_dir.join("IMG_0006_1.jpg"))
+    );
+    assert!(!lock.exists());
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_locks_from_dead_processes_are_broken() -> Result<()> {
+    let temp = temp_dir();
+    let dng = write_temp(&temp, "IMG_0007.DNG", &raw());
+    let out_dir = temp.path().join("out");
+    std::fs::create_dir_all(&out_dir)?;
+    // Higher than any pid Linux hands out.
+    std::fs::write(out_dir.join(".IMG_0007_1.jpg.lock"), u32::MAX.to_string())?;
+
+    let options = ExtractOptions {
+        name_template: Some("{stem}_{seq}.jpg".parse()?),
+        ..Default::default()
+    };
+    let outcome =
+        process_file_with_options(&dng, &out_dir, "IMG_0007.DNG".as_ref(), &options).await?;
+    assert_eq!(
+        outcome,
+        WriteOutcome::Written(out_dir.join("IMG_0007_1.jpg"))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_abandoned_files_are_removed() -> Result<()> {
+    let temp = temp_dir();
+    let out_dir = temp.path();
+    std::fs::create_dir_all(out_dir.join("sub"))?;
+    let pid = std::process::id();
+    let abandoned = [
+        out_dir.join(format!(".a.jpg.{}.0.tmp", pid)),
+        out_dir.join("sub/.b.jpg.lock"),
+    ];
+    let kept = [
+        // Still being written.
+        out_dir.join(format!(".c.jpg.{}.1.tmp", pid)),
+        // Not one of ours.
+        out_dir.join(".notes.lock"),
+        out_dir.join(".d.jpg.tmp"),
+    ];
+    for path in abandoned.iter().chain(&kept) {
+        std::fs::write(path, pid.to_string())?;
+    }
+    std::fs::write(out_dir.join(".notes.lock"), b"")?;
+    for path in abandoned.iter().chain(&kept[1..]) {
+        age(path)?;
+    }
+
+    let mut removed = output::remove_abandoned_files(out_dir).await?;
+    removed.sort();
+    assert_eq!(removed, abandoned);
+    assert!(kept.iter().all(|path| path.is_file()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_copies_without_a_preview_are_skipped_early() -> Result<()> {
+    let temp = temp_dir();
+    let nef = write_temp(&temp, "IMG_0008.NEF", b"not a RAW");
+    let out_dir = temp.path().join("out");
+    // A fresh set of options each time, as for separate runs.
+    let options = || ExtractOptions {
+        overwrite: OverwritePolicy::Never,
+        ..Default::default()
+    };
+
+    let report =
+        process_file_with_report(&nef, &out_dir, "IMG_0008.NEF".as_ref(), &options()).await?;
+    let copied = out_dir.join("IMG_0008.NEF");
+    assert_eq!(report.outcome, WriteOutcome::Written(copied.clone()));
+
+    // Without opening the RAW again.
+    let report =
+        process_file_with_report(&nef, &out_dir, "IMG_0008.NEF".as_ref(), &options()).await?;
+    assert_eq!(report.outcome, WriteOutcome::Skipped(copied));
+    assert_eq!(report.container, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_raws_are_not_mistaken_for_their_own_copies() -> Result<()> {
+    let temp = temp_dir();
+    let dng = write_temp(&temp, "IMG_0009.DNG", &raw());
+    let options = ExtractOptions {
+        overwrite: OverwritePolicy::Never,
+        ..Default::default()
+    };
+
+    let outcome =
+        process_file_with_options(&dng, temp.path(), "IMG_0009.DNG".as_ref(), &options).await?;
+    assert_eq!(
+        outcome,
+        WriteOutcome::Written(temp.path().join("IMG_0009.jpg"))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_inputs_without_an_extension_are_copied_without_one() -> Result<()> {
+    let temp = temp_dir();
+    let path = write_temp(&temp, "IMG_0010", b"not a RAW");
+    let out_dir = temp.path().join("out");
+
+    let outcome = process_file_with_options(
+        &path,
+        &out_dir,
+        "IMG_0010".as_ref(),
+        &ExtractOptions::default(),
+    )
+    .await?;
+    assert_eq!(outcome, WriteOutcome::Written(out_dir.join("IMG_0010")));
+
+    let options = ExtractOptions {
+        name_template: Some("copies/{stem}.jpg".parse()?),
+        ..Default::default()
+    };
+    let outcome = process_file_with_options(&path, &out_dir, "IMG_0010".as_ref(), &options).await?;
+    assert_eq!(
+        outcome,
+        WriteOutcome::Written(out_dir.join("copies/IMG_0010"))
+    );
+    assert_eq!(
+        std::fs::read(out_dir.join("copies/IMG_0010"))?,
+        b"not a RAW"
+    );
+    Ok(())
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use anyhow::Result;
+use common::{ifd_bytes, temp_dir, tiff, write_temp, Entry, PAYLOAD_OFFSET};
+use jpgfromraw::parser::{process_file_bytes, FindJpegType};
+
+const ASCII: u16 = 2;
+const LONG: u16 = 4;
+const UNDEFINED: u16 = 7;
+
+// Where things go in the payload.
+const MAKE: u32 = 0x0;
+const MODEL: u32 = 0x10;
+const EXIF: u32 = 0x20;
+const MAKER_NOTE: u32 = 0x40;
+const PREVIEW: u32 = 0x100;
+
+/// A RAW from `make` and `model`, with `maker_note` in the EXIF IFD and `preview` at [`PREVIEW`].
+fn raw(make: &str, model: &str, ifd0: &[Entry], maker_note: &[u8], preview: &[u8]) -> Vec<u8> {
+    let mut payload = vec![0; PREVIEW as usize];
+    payload[MAKE as usize..][..make.len()].copy_from_slice(make.as_bytes());
+    payload[MODEL as usize..][..model.len()].copy_from_slice(model.as_bytes());
+    let exif = ifd_bytes(&[(
+        0x927c,
+        UNDEFINED,
+        maker_note.len() as u32,
+        PAYLOAD_OFFSET + MAKER_NOTE,
+    )]);
+    payload[EXIF as usize..][..exif.len()].copy_from_slice(&exif);
+    payload[MAKER_NOTE as usize..][..maker_note.len()].copy_from_slice(maker_note);
+    payload.extend_from_slice(preview);
+
+    let mut entries = vec![
+        (0x10f, ASCII, make.len() as u32 + 1, PAYLOAD_OFFSET + MAKE),
+        (0x110, ASCII, model.len() as u32 + 1, PAYLOAD_OFFSET + MODEL),
+        (0x8769, LONG, 1, PAYLOAD_OFFSET + EXIF),
+    ];
+    entries.extend_from_slice(ifd0);
+    entries.sort_by_key(|e| e.0);
+    tiff(&[entries], &payload)
+}
+
+/// A JPEG-ish preview, followed by `padding` zeros.
+fn preview(padding: usize) -> Vec<u8> {
+    let mut preview = vec![0xff, 0xd8, 0xff, 0xe0];
+    preview.resize(62, 0x55);
+    preview.extend_from_slice(&[0xff, 0xd9]);
+    preview.resize(preview.len() + padding, 0);
+    preview
+}
+
+#[tokio::test]
+async fn test_pentax_padding_is_trimmed() -> Result<()> {
+    let padded = preview(32);
+    let mut maker_note = b"AOC\0II".to_vec();
+    maker_note.extend(ifd_bytes(&[
+        (0x3, LONG, 1, padded.len() as u32),
+        (0x4, LONG, 1, PAYLOAD_OFFSET + PREVIEW),
+    ]));
+    let raw = raw("PENTAX", "PENTAX K-3", &[], &maker_note, &padded);
+
+    let temp = temp_dir();
+    let path = write_temp(&temp, "padded.pef", &raw);
+    let jpeg = process_file_bytes(&path, FindJpegType::Largest).await?;
+    assert!(jpeg.ends_with(&[0x55, 0xff, 0xd9]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_samsung_nx10_maker_note_offsets_are_relative() -> Result<()> {
+    const PREVIEW_IFD: u32 = 0x20;
+
+    let preview = preview(0);
+    let mut maker_note = ifd_bytes(&[(0x35, LONG, 1, PREVIEW_IFD)]);
+    maker_note.resize(PREVIEW_IFD as usize, 0);
+    maker_note.extend(ifd_bytes(&[
+        (0x201, LONG, 1, PREVIEW - MAKER_NOTE),
+        (0x202, LONG, 1, preview.len() as u32),
+    ]));
+    let raw = raw("SAMSUNG", "NX10", &[], &maker_note, &preview);
+
+    let temp = temp_dir();
+    let path = write_temp(&temp, "nx10.srw", &raw);
+    let jpeg = process_file_bytes(&path, FindJpegType::Largest).await?;
+    assert!(jpeg.ends_with(&preview[2..]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_leica_short_length_is_extended_to_eoi() -> Result<()> {
+    let preview = preview(0);
+    let ifd0 = [
+        (0x201, LONG, 1, PAYLOAD_OFFSET + PREVIEW),
+        (0x202, LONG, 1, preview.len() as u32 - 4),
+    ];
+    let raw = raw("LEICA", "M (Typ 240)", &ifd0, &[], &preview);
+
+    let temp = temp_dir();
+    let path = write_temp(&temp, "short.rwl", &raw);
+    let jpeg = process_file_bytes(&path, FindJpegType::Largest).await?;
+    assert!(jpeg.ends_with(&[0x55, 0xff, 0xd9]));
+    Ok(())
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use anyhow::{anyhow, Result};
+use common::{temp_dir, tiff, write_temp, PAYLOAD_OFFSET, TINY_JPEG};
+use jpgfromraw::report::ReportWriter;
+use jpgfromraw::{process_file_with_report, ExtractOptions, OverwritePolicy};
+use serde_json::Value;
+use std::path::Path;
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+#[tokio::test]
+async fn test_report_lines() -> Result<()> {
+    let raw = tiff(
+        &[vec![
+            (0x112, SHORT, 1, 6),
+            (0x201, LONG, 1, PAYLOAD_OFFSET),
+            (0x202, LONG, 1, TINY_JPEG.len() as u32),
+        ]],
+        TINY_JPEG,
+    );
+    let temp = temp_dir();
+    let with_preview = write_temp(&temp, "report.dng", &raw);
+    let without_preview = write_temp(&temp, "report_none.dng", b"not a raw at all");
+    let out_dir = with_preview.with_file_name("report_out");
+    let options = ExtractOptions::default();
+
+    let mut out = Vec::new();
+    let mut report = ReportWriter::new(&mut out);
+    let file_report =
+        process_file_with_report(&with_preview, &out_dir, "report.dng".as_ref(), &options).await?;
+    report.processed(&with_preview, &file_report)?;
+    let file_report = process_file_with_report(
+        &without_preview,
+        &out_dir,
+        "report_none.dng".as_ref(),
+        &options,
+    )
+    .await?;
+    report.processed(&without_preview, &file_report)?;
+    report.unchanged(Path::new("old.dng"))?;
+    let io_error = anyhow::Error::new(std::io::Error::other("gone")).context("Reading RAW");
+    report.error(Path::new("gone.dng"), &io_error)?;
+    report.error(Path::new("bad.dng"), &anyhow!("Invalid TIFF header"))?;
+    report.flush()?;
+
+    let lines = std::str::from_utf8(&out)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<Value>, _>>()?;
+    assert_eq!(lines.len(), 5);
+
+    assert_eq!(lines[0]["status"], "ok");
+    assert_eq!(lines[0]["container"], "tiff");
+    assert_eq!(lines[0]["preview_kind"], "jpeg");
+    assert_eq!(lines[0]["preview_offset"], PAYLOAD_OFFSET);
+    assert_eq!(lines[0]["preview_length"], TINY_JPEG.len());
+    assert_eq!(lines[0]["orientation"], 6);
+    let output = lines[0]["output"].as_str().unwrap();
+    assert!(output.ends_with("report.jpg"));
+    assert!(lines[0]["durations_ms"]["find_largest_embedded_jpeg"].is_f64());
+    assert!(lines[0]["durations_ms"]["write"].is_f64());
+
+    assert_eq!(lines[1]["status"], "no-preview");
+    assert_eq!(lines[1]["container"], "unknown");
+    // Copied as-is, so it keeps its own extension rather than pretending to be a JPEG.
+    let output = lines[1]["output"].as_str().unwrap();
+    assert!(output.ends_with("report_none.dng"));
+    assert!(lines[1].get("preview_offset").is_none());
+
+    assert_eq!(lines[2]["status"], "unchanged");
+    assert_eq!(lines[3]["status"], "error");
+    assert_eq!(lines[3]["error_kind"], "io");
+    assert_eq!(lines[3]["error"], "Reading RAW: gone");
+    assert_eq!(lines[4]["error_kind"], "invalid");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_report_renamed_without_preview_and_dry_run() -> Result<()> {
+    let temp = temp_dir();
+    let input = write_temp(&temp, "report_renamed.dng", b"not a raw at all");
+    let out_dir = input.with_file_name("report_renamed_out");
+    std::fs::create_dir_all(&out_dir)?;
+    std::fs::write(out_dir.join("report_renamed.dng"), b"existing")?;
+
+    let mut out = Vec::new();
+    let mut report = ReportWriter::new(&mut out);
+    let options = ExtractOptions {
+        overwrite: OverwritePolicy::Rename,
+        ..Default::default()
+    };
+    let file_report =
+        process_file_with_report(&input, &out_dir, "report_renamed.dng".as_ref(), &options).await?;
+    report.processed(&input, &file_report)?;
+    let options = ExtractOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let file_report =
+        process_file_with_report(&input, &out_dir, "report_dry.dng".as_ref(), &options).await?;
+    report.processed(&input, &file_report)?;
+    report.flush()?;
+
+    let lines = std::str::from_utf8(&out)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<Value>, _>>()?;
+    assert_eq!(lines[0]["status"], "no-preview");
+    assert!(lines[0]["wanted"]
+        .as_str()
+        .unwrap()
+        .ends_with("report_renamed.dng"));
+    assert!(lines[0]["output"]
+        .as_str()
+        .unwrap()
+        .ends_with("report_renamed-1.dng"));
+    assert!(lines[0].get("dry_run").is_none());
+    assert_eq!(lines[1]["dry_run"], true);
+    assert!(!out_dir.join("report_dry.dng").exists());
+    Ok(())
+}
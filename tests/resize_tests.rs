@@ -0,0 +1,42 @@
+use anyhow::Result;
+use jpeg_encoder::{ColorType, Encoder};
+use jpgfromraw::resize::{downscale, ResizeOptions};
+
+fn encode(width: u16, height: u16) -> Result<Vec<u8>> {
+    let pixels = vec![200; usize::from(width) * usize::from(height) * 3];
+    let mut out = Vec::new();
+    Encoder::new(&mut out, 90).encode(&pixels, width, height, ColorType::Rgb)?;
+    Ok(out)
+}
+
+fn dimensions(jpeg: &[u8]) -> Result<(u16, u16)> {
+    let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+    decoder.read_info()?;
+    let info = decoder.info().unwrap();
+    Ok((info.width, info.height))
+}
+
+#[test]
+fn test_downscale_fits_long_edge() -> Result<()> {
+    let options = ResizeOptions {
+        max_size: 100,
+        quality: 80,
+    };
+    // 1/4 DCT scaling gets us to 150x75, and the rest is done by resampling.
+    let resized = downscale(&encode(600, 300)?, &options)?.expect("should be resized");
+    assert_eq!(dimensions(&resized)?, (100, 50));
+
+    let resized = downscale(&encode(300, 600)?, &options)?.expect("should be resized");
+    assert_eq!(dimensions(&resized)?, (50, 100));
+    Ok(())
+}
+
+#[test]
+fn test_downscale_leaves_small_images_alone() -> Result<()> {
+    let options = ResizeOptions {
+        max_size: 100,
+        quality: 80,
+    };
+    assert!(downscale(&encode(100, 40)?, &options)?.is_none());
+    Ok(())
+}
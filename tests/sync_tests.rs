@@ -0,0 +1,206 @@
+mod common;
+
+use anyhow::Result;
+use common::{temp_dir, tiff, write_temp, PAYLOAD_OFFSET, TINY_JPEG};
+use jpgfromraw::parser::process_file_with_options;
+use jpgfromraw::sync::{Record, SyncState, STATE_FILE};
+use jpgfromraw::{ExtractOptions, OutputFormat, OverwritePolicy};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+const LONG: u16 = 4;
+
+fn raw(padding: usize) -> Vec<u8> {
+    let mut payload = TINY_JPEG.to_vec();
+    payload.resize(payload.len() + padding, 0);
+    tiff(
+        &[vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET),
+            (0x202, LONG, 1, TINY_JPEG.len() as u32),
+        ]],
+        &payload,
+    )
+}
+
+async fn extract(input: &Path, out_dir: &Path, name: &str) -> Result<Record> {
+    let outcome =
+        process_file_with_options(input, out_dir, name.as_ref(), &ExtractOptions::default())
+            .await?;
+    Record::new(
+        input,
+        out_dir,
+        &[outcome.written().unwrap()],
+        &ExtractOptions::default(),
+        true,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_incremental_state() -> Result<()> {
+    let temp = temp_dir();
+    let kept = write_temp(&temp, "sync_kept.dng", &raw(0));
+    let changed = write_temp(&temp, "sync_changed.dng", &raw(0));
+    let gone = write_temp(&temp, "sync_gone.dng", &raw(0));
+    let out_dir = kept.with_file_name("sync_out");
+    std::fs::create_dir_all(&out_dir)?;
+
+    let mut state = SyncState::load(&out_dir).await?;
+    for (path, name) in [
+        (&kept, "sync_kept.dng"),
+        (&changed, "sync_changed.dng"),
+        (&gone, "sync_gone.dng"),
+    ] {
+        let record = extract(path, &out_dir, name).await?;
+        assert_eq!(record.outputs, [name.replace(".dng", ".jpg")]);
+        state.insert(name.as_ref(), record);
+    }
+    state.save(&out_dir).await?;
+    assert!(out_dir.join(STATE_FILE).is_file());
+
+    std::fs::write(&changed, raw(16))?;
+    std::fs::remove_file(&gone)?;
+
+    let mut state = SyncState::load(&out_dir).await?;
+    let options = ExtractOptions::default();
+    let previous = state.get("sync_kept.dng".as_ref()).unwrap();
+    assert!(previous
+        .check(&kept, &out_dir, &options, true)
+        .await?
+        .is_some());
+    let previous = state.get("sync_changed.dng".as_ref()).unwrap();
+    assert!(previous
+        .check(&changed, &out_dir, &options, true)
+        .await?
+        .is_none());
+
+    // An output going missing means extracting again, too.
+    std::fs::remove_file(out_dir.join("sync_kept.jpg"))?;
+    let previous = state.get("sync_kept.dng".as_ref()).unwrap();
+    assert!(previous
+        .check(&kept, &out_dir, &options, true)
+        .await?
+        .is_none());
+
+    let inputs: HashSet<PathBuf> = ["sync_kept.dng", "sync_changed.dng"]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+    let deleted = state.remove_orphans(&out_dir, &inputs).await?;
+    assert_eq!(deleted, [out_dir.join("sync_gone.jpg")]);
+    assert!(state.get("sync_gone.dng".as_ref()).is_none());
+    assert!(out_dir.join("sync_changed.jpg").is_file());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_skipped_outputs_are_not_deleted_as_orphans() -> Result<()> {
+    let temp = temp_dir();
+    let input = write_temp(&temp, "sync_skipped.dng", &raw(0));
+    let out_dir = input.with_file_name("sync_skipped_out");
+    std::fs::create_dir_all(&out_dir)?;
+    std::fs::write(out_dir.join("sync_skipped.jpg"), b"not ours")?;
+
+    let options = ExtractOptions {
+        overwrite: OverwritePolicy::Never,
+        ..Default::default()
+    };
+    let outcome =
+        process_file_with_options(&input, &out_dir, "sync_skipped.dng".as_ref(), &options).await?;
+    let record = Record::from_outcome(&input, &out_dir, &outcome, &options, false).await?;
+    assert!(record.outputs.is_empty());
+
+    let mut state = SyncState::default();
+    state.insert("sync_skipped.dng".as_ref(), record);
+    let deleted = state.remove_orphans(&out_dir, &HashSet::new()).await?;
+    assert!(deleted.is_empty());
+    assert_eq!(
+        std::fs::read(out_dir.join("sync_skipped.jpg"))?,
+        b"not ours"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_outputs_taken_over_are_not_deleted_as_orphans() -> Result<()> {
+    let temp = temp_dir();
+    let input = write_temp(&temp, "sync_taken.dng", &raw(0));
+    let out_dir = input.with_file_name("sync_taken_out");
+    std::fs::create_dir_all(&out_dir)?;
+
+    // sync_gone.cr2 was written to sync_taken.jpg, and has since been replaced by the DNG.
+    let record = extract(&input, &out_dir, "sync_taken.dng").await?;
+    let mut state = SyncState::default();
+    state.insert("sync_gone.cr2".as_ref(), record.clone());
+    state.insert("sync_taken.dng".as_ref(), record);
+
+    let inputs = HashSet::from([PathBuf::from("sync_taken.dng")]);
+    let deleted = state.remove_orphans(&out_dir, &inputs).await?;
+    assert!(deleted.is_empty());
+    assert!(out_dir.join("sync_taken.jpg").is_file());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_records_are_for_one_raw() -> Result<()> {
+    let temp = temp_dir();
+    let out_dir = temp.path().join("out");
+    std::fs::create_dir_all(&out_dir)?;
+    let mut raws = Vec::new();
+    for card in ["card1", "card2"] {
+        std::fs::create_dir_all(temp.path().join(card))?;
+        let raw = write_temp(temp.path().join(card), "IMG_1.dng", &raw(0));
+        filetime::set_file_mtime(&raw, filetime::FileTime::from_unix_time(1_700_000_000, 0))?;
+        raws.push(raw);
+    }
+
+    // The same relative path, size and modification time, but a different RAW.
+    let options = ExtractOptions::default();
+    let record = extract(&raws[0], &out_dir, "IMG_1.dng").await?;
+    assert!(record.is_for(&raws[0]));
+    assert!(record
+        .check(&raws[0], &out_dir, &options, false)
+        .await?
+        .is_some());
+    assert!(!record.is_for(&raws[1]));
+    assert!(record
+        .check(&raws[1], &out_dir, &options, true)
+        .await?
+        .is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_changed_options_mean_extracting_again() -> Result<()> {
+    let temp = temp_dir();
+    let input = write_temp(&temp, "sync_options.dng", &raw(0));
+    let out_dir = temp.path().join("out");
+    std::fs::create_dir_all(&out_dir)?;
+
+    let record = extract(&input, &out_dir, "sync_options.dng").await?;
+    let options = ExtractOptions::default();
+    assert!(record
+        .check(&input, &out_dir, &options, false)
+        .await?
+        .is_some());
+    // Like running again with --format png.
+    let options = ExtractOptions {
+        format: OutputFormat::Png,
+        ..Default::default()
+    };
+    assert!(record
+        .check(&input, &out_dir, &options, false)
+        .await?
+        .is_none());
+    // Options which don't change the outputs don't matter.
+    let options = ExtractOptions {
+        overwrite: OverwritePolicy::Never,
+        fsync: true,
+        ..Default::default()
+    };
+    assert!(record
+        .check(&input, &out_dir, &options, false)
+        .await?
+        .is_some());
+    Ok(())
+}
@@ -0,0 +1,235 @@
+mod common;
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use common::{ifd_bytes, temp_dir, tiff, write_temp, PAYLOAD_OFFSET};
+use jpeg_encoder::{ColorType, Encoder};
+use jpgfromraw::parser::metadata::RawMetadata;
+use jpgfromraw::parser::process_file_with_options;
+use jpgfromraw::template::{NameTemplate, TemplateValues};
+use jpgfromraw::{ExtractOptions, OverwritePolicy, WriteOutcome};
+use std::path::Path;
+
+const ASCII: u16 = 2;
+const LONG: u16 = 4;
+
+fn encode(width: u16, height: u16) -> Result<Vec<u8>> {
+    let pixels = vec![128; usize::from(width) * usize::from(height) * 3];
+    let mut out = Vec::new();
+    Encoder::new(&mut out, 90).encode(&pixels, width, height, ColorType::Rgb)?;
+    Ok(out)
+}
+
+#[test]
+fn test_render() -> Result<()> {
+    let metadata = RawMetadata {
+        make: Some("NIKON CORPORATION".into()),
+        model: Some("NIKON Z 8".into()),
+        lens_model: Some("NIKKOR Z 24-120mm f/4 S".into()),
+        date_time_original: Some("2024:06:01 09:15:30".into()),
+        ..Default::default()
+    };
+    let values = TemplateValues {
+        relative_path: Path::new("card1/DSC_0001.NEF"),
+        metadata: &metadata,
+        date: metadata.date(),
+        dimensions: Some((8256, 5504)),
+    };
+    let render = |template: &str, seq| -> Result<String> {
+        let path = NameTemplate::parse(template)?.render(&values, seq, "jpg")?;
+        Ok(path.to_string_lossy().into_owned())
+    };
+
+    assert_eq!(
+        render("{date:%Y/%m/%d}/{model}_{stem}_{seq}.jpg", 2)?,
+        "2024/06/01/NIKON Z 8_DSC_0001_2.jpg"
+    );
+    // Only a literal extension at the end is replaced.
+    assert_eq!(render("{dir}/{stem}.{ext}", 0)?, "card1/DSC_0001.NEF.jpg");
+    assert_eq!(
+        render("{lens}_{width}x{height}_{seq:3}", 7)?,
+        "NIKKOR Z 24-120mm f_4 S_8256x5504_007.jpg"
+    );
+    assert_eq!(render("{date}_{{literal}}", 0)?, "2024-06-01_{literal}.jpg");
+    // Only image extensions are dropped, rather than anything after a '.'.
+    assert_eq!(render("{stem}_v1.2", 0)?, "DSC_0001_v1.2.jpg");
+    assert_eq!(render("{stem}.JPEG", 0)?, "DSC_0001.jpg");
+    assert_eq!(render("{stem}.webp", 0)?, "DSC_0001.jpg");
+    // Dates can only add directories, not characters which aren't allowed in file names.
+    assert_eq!(
+        render("{date:%Y/%m-%d %H:%M}_{stem}", 0)?,
+        "2024/06-01 09_15_DSC_0001.jpg"
+    );
+    // Nothing can escape the output directory.
+    assert_eq!(
+        render("/{make}/{stem}", 0)?,
+        "NIKON CORPORATION/DSC_0001.jpg"
+    );
+    assert!(render("../{stem}", 0).is_err());
+
+    assert!(NameTemplate::parse("{nope}").is_err());
+    assert!(NameTemplate::parse("{stem").is_err());
+    assert!(NameTemplate::parse("{seq:x}").is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_name_template_with_seq_avoids_collisions() -> Result<()> {
+    const EXIF: u32 = 0x40;
+    const PREVIEW: u32 = 0x80;
+
+    let preview = encode(48, 32)?;
+    let mut payload = b"Canon\0\0\0Canon EOS R5\0\0\0\0".to_vec();
+    payload.extend_from_slice(b"2024:12:24 18:30:00\0");
+    payload.resize(EXIF as usize, 0);
+    payload.extend(ifd_bytes(&[(0x9003, ASCII, 20, PAYLOAD_OFFSET + 24)]));
+    payload.resize(PREVIEW as usize, 0);
+    payload.extend_from_slice(&preview);
+    let raw = tiff(
+        &[vec![
+            (0x10f, ASCII, 6, PAYLOAD_OFFSET),
+            (0x110, ASCII, 13, PAYLOAD_OFFSET + 8),
+            (0x201, LONG, 1, PAYLOAD_OFFSET + PREVIEW),
+            (0x202, LONG, 1, preview.len() as u32),
+            (0x8769, LONG, 1, PAYLOAD_OFFSET + EXIF),
+        ]],
+        &payload,
+    );
+    let temp = temp_dir();
+    let path = write_temp(&temp, "IMG_0001.CR2", &raw);
+    let out_dir = path.with_file_name("template_out");
+
+    let options = ExtractOptions {
+        name_template: Some("{date:%Y/%m}/{model}_{stem}_{width}x{height}_{seq}.jpg".parse()?),
+        ..Default::default()
+    };
+    for _ in 0..2 {
+        process_file_with_options(&path, &out_dir, "IMG_0001.CR2".as_ref(), &options).await?;
+    }
+
+    let dir = out_dir.join("2024/12");
+    let first = std::fs::read(dir.join("Canon EOS R5_IMG_0001_48x32_1.jpg"))?;
+    let second = std::fs::read(dir.join("Canon EOS R5_IMG_0001_48x32_2.jpg"))?;
+    assert_eq!(first, second);
+    assert!(first.starts_with(&[0xff, 0xd8]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_name_template_with_seq_reuses_its_own_output() -> Result<()> {
+    let preview = encode(16, 16)?;
+    let raw = tiff(
+        &[vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET),
+            (0x202, LONG, 1, preview.len() as u32),
+        ]],
+        &preview,
+    );
+    let temp = temp_dir();
+    let path = write_temp(&temp, "IMG_0004.CR2", &raw);
+    let out_dir = path.with_file_name("template_rerun_out");
+    std::fs::create_dir_all(&out_dir)?;
+    std::fs::write(out_dir.join("IMG_0004_1.jpg"), b"something else")?;
+
+    let expected = out_dir.join("IMG_0004_2.jpg");
+    for overwrite in [
+        OverwritePolicy::Always,
+        OverwritePolicy::Never,
+        OverwritePolicy::Always,
+    ] {
+        let options = ExtractOptions {
+            name_template: Some("{stem}_{seq}.jpg".parse()?),
+            overwrite,
+            ..Default::default()
+        };
+        let outcome =
+            process_file_with_options(&path, &out_dir, "IMG_0004.CR2".as_ref(), &options).await?;
+        match overwrite {
+            OverwritePolicy::Never => assert_eq!(outcome, WriteOutcome::Skipped(expected.clone())),
+            _ => assert_eq!(outcome, WriteOutcome::Written(expected.clone())),
+        }
+    }
+
+    // No more numbered copies, and no locks left behind.
+    let mut names = std::fs::read_dir(&out_dir)?
+        .map(|entry| Ok(entry?.file_name()))
+        .collect::<Result<Vec<_>>>()?;
+    names.sort();
+    assert_eq!(names, ["IMG_0004_1.jpg", "IMG_0004_2.jpg"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_name_template_with_seq_leaves_nothing_on_failure() -> Result<()> {
+    let preview = encode(16, 16)?;
+    let raw = tiff(
+        &[vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET),
+            (0x202, LONG, 1, preview.len() as u32),
+        ]],
+        &preview,
+    );
+    let temp = temp_dir();
+    let path = write_temp(&temp, "IMG_0005.CR2", &raw);
+    let out_dir = path.with_file_name("template_fail_out");
+    std::fs::create_dir_all(&out_dir)?;
+
+    // Long enough that the lock file's name fits, but the temporary file's doesn't, so writing
+    // fails after the name was reserved.
+    let template = format!("{{seq}}{}.jpg", "x".repeat(244));
+    let options = ExtractOptions {
+        name_template: Some(template.parse()?),
+        ..Default::default()
+    };
+    let result =
+        process_file_with_options(&path, &out_dir, "IMG_0005.CR2".as_ref(), &options).await;
+    assert!(result.is_err());
+    assert_eq!(std::fs::read_dir(&out_dir)?.count(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_by_date_falls_back_to_mtime() -> Result<()> {
+    let preview = encode(16, 16)?;
+    let dated = {
+        let mut payload = b"2023:01:02 03:04:05\0".to_vec();
+        payload.resize(0x40, 0);
+        payload.extend(ifd_bytes(&[(0x9003, ASCII, 20, PAYLOAD_OFFSET)]));
+        payload.resize(0x80, 0);
+        payload.extend_from_slice(&preview);
+        tiff(
+            &[vec![
+                (0x201, LONG, 1, PAYLOAD_OFFSET + 0x80),
+                (0x202, LONG, 1, preview.len() as u32),
+                (0x8769, LONG, 1, PAYLOAD_OFFSET + 0x40),
+            ]],
+            &payload,
+        )
+    };
+    let undated = tiff(
+        &[vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET),
+            (0x202, LONG, 1, preview.len() as u32),
+        ]],
+        &preview,
+    );
+
+    let temp = temp_dir();
+    let dated_path = write_temp(&temp, "dated.dng", &dated);
+    let undated_path = write_temp(&temp, "undated.dng", &undated);
+    let out_dir = dated_path.with_file_name("by_date_out");
+    let options = ExtractOptions {
+        name_template: Some(NameTemplate::by_date()),
+        ..Default::default()
+    };
+    process_file_with_options(&dated_path, &out_dir, "dated.dng".as_ref(), &options).await?;
+    process_file_with_options(&undated_path, &out_dir, "undated.dng".as_ref(), &options).await?;
+
+    assert!(out_dir.join("2023/2023-01-02/dated.jpg").is_file());
+    let mtime = DateTime::<Local>::from(std::fs::metadata(&undated_path)?.modified()?);
+    let expected = out_dir
+        .join(mtime.format("%Y/%Y-%m-%d").to_string())
+        .join("undated.jpg");
+    assert!(expected.is_file());
+    Ok(())
+}
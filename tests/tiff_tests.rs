@@ -0,0 +1,132 @@
+mod common;
+
+use common::{bigtiff, ifd_offset, tiff, PAYLOAD_OFFSET};
+use jpgfromraw::tiff::{FieldType, IfdReader, Rational, SRational, Value};
+
+const BYTE: u16 = 1;
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+const UNDEFINED: u16 = 7;
+const SRATIONAL: u16 = 10;
+const LONG8: u16 = 16;
+
+#[test]
+fn test_value_decoding() {
+    let mut payload = b"A longer string\0".to_vec();
+    payload.extend_from_slice(&[1, 0, 0, 0, 3, 0, 0, 0]);
+    payload.extend_from_slice(&(-5i32).to_le_bytes());
+    payload.extend_from_slice(&2i32.to_le_bytes());
+    let ifds = vec![vec![
+        (0x1, BYTE, 3, 0x00030201),
+        (0x2, ASCII, 4, u32::from_le_bytes(*b"abc\0")),
+        (0x3, ASCII, 16, PAYLOAD_OFFSET),
+        (0x4, SHORT, 2, 0x00090008),
+        (0x5, LONG, 1, 123456),
+        (0x6, RATIONAL, 1, PAYLOAD_OFFSET + 16),
+        (0x7, SRATIONAL, 1, PAYLOAD_OFFSET + 24),
+        (0x8, UNDEFINED, 2, 0xbbaa),
+    ]];
+    let buf = tiff(&ifds, &payload);
+    let reader = IfdReader::new(&buf).unwrap();
+    let ifd = reader.ifds().next().unwrap().unwrap();
+    assert_eq!(ifd.len(), 8);
+
+    let value = |tag| ifd.entry(tag).unwrap().value().unwrap();
+    assert_eq!(value(0x1), Value::Byte(&[1, 2, 3]));
+    assert_eq!(value(0x2), Value::Ascii(b"abc"));
+    assert_eq!(
+        ifd.entry(0x3).unwrap().ascii().as_deref(),
+        Some("A longer string")
+    );
+    assert_eq!(value(0x4), Value::Short(vec![8, 9]));
+    assert_eq!(ifd.entry(0x5).unwrap().u32(), Some(123456));
+    assert_eq!(
+        value(0x6),
+        Value::Rational(vec![Rational {
+            numerator: 1,
+            denominator: 3
+        }])
+    );
+    assert_eq!(
+        value(0x7),
+        Value::SRational(vec![SRational {
+            numerator: -5,
+            denominator: 2
+        }])
+    );
+    assert_eq!(value(0x8), Value::Undefined(&[0xaa, 0xbb]));
+    assert_eq!(ifd.entry(0x8).unwrap().field_type(), FieldType::Undefined);
+    assert!(ifd.entry(0x9).is_none());
+}
+
+#[test]
+fn test_out_of_bounds_values_are_errors() {
+    let ifds = vec![vec![
+        (0x1, LONG, 4, 0xffff_fff0),
+        (0x2, RATIONAL, 0x4000_0000, PAYLOAD_OFFSET),
+        (0x3, 0xff, 1, 0),
+    ]];
+    let buf = tiff(&ifds, &[]);
+    let reader = IfdReader::new(&buf).unwrap();
+    let ifd = reader.ifds().next().unwrap().unwrap();
+    assert!(ifd.entry(0x1).unwrap().data().is_err());
+    assert!(ifd.entry(0x2).unwrap().value().is_err());
+    assert!(ifd.entry(0x3).unwrap().value().is_err());
+}
+
+#[test]
+fn test_ifd_chain_loops_are_detected() {
+    let ifds = vec![vec![(0x1, SHORT, 1, 1)], vec![(0x1, SHORT, 1, 2)]];
+    let mut buf = tiff(&ifds, &[]);
+    // Point IFD1's next IFD offset back at IFD0.
+    let next = ifd_offset(&ifds, 2) as usize - 4;
+    buf[next..next + 4].copy_from_slice(&8u32.to_le_bytes());
+
+    let reader = IfdReader::new(&buf).unwrap();
+    let results: Vec<_> = reader.ifds().collect();
+    assert_eq!(results.len(), 3);
+    assert!(results[0].is_ok() && results[1].is_ok());
+    assert!(results[2].is_err());
+}
+
+#[test]
+fn test_invalid_header() {
+    assert!(IfdReader::new(b"II+\0\x08\0\0\0").is_err());
+    assert!(IfdReader::new(b"XX*\0\x08\0\0\0").is_err());
+    assert!(IfdReader::new(b"II*\0").is_err());
+}
+
+#[test]
+fn test_bigtiff() {
+    let payload = b"A string longer than eight bytes\0";
+    let ifds = vec![
+        vec![
+            (0x1, ASCII, 8, u64::from_le_bytes(*b"inline\0\0")),
+            (0x2, ASCII, payload.len() as u64, PAYLOAD_OFFSET.into()),
+            (0x3, LONG8, 1, 0x1_2345_6789),
+            (0x4, LONG, 2, 0x0000_0002_0000_0001),
+        ],
+        vec![(0x5, SHORT, 1, 7)],
+    ];
+    let buf = bigtiff(&ifds, payload);
+    let reader = IfdReader::new(&buf).unwrap();
+    assert!(reader.is_big());
+
+    let chain: Vec<_> = reader.ifds().map(Result::unwrap).collect();
+    assert_eq!(chain.len(), 2);
+    let ifd = chain[0];
+    assert_eq!(ifd.len(), 4);
+    assert_eq!(ifd.entry(0x1).unwrap().ascii().as_deref(), Some("inline"));
+    assert_eq!(
+        ifd.entry(0x2).unwrap().ascii().as_deref(),
+        Some("A string longer than eight bytes")
+    );
+    let long8 = ifd.entry(0x3).unwrap();
+    assert_eq!(long8.value().unwrap(), Value::Long8(vec![0x1_2345_6789]));
+    assert_eq!(long8.u64(), Some(0x1_2345_6789));
+    assert_eq!(long8.u32(), None);
+    assert_eq!(ifd.entry(0x4).unwrap().u64s(), Some(vec![1, 2]));
+    assert_eq!(chain[1].entry(0x5).unwrap().u32(), Some(7));
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use anyhow::Result;
+use common::{ifd_bytes, temp_dir, tiff, write_temp, PAYLOAD_OFFSET, TINY_JPEG};
+use filetime::FileTime;
+use jpgfromraw::parser::process_file_with_options;
+use jpgfromraw::{ExtractOptions, RawMetadata, Timestamps};
+use std::time::{Duration, UNIX_EPOCH};
+
+const ASCII: u16 = 2;
+const LONG: u16 = 4;
+
+/// 2024-06-01 09:15:30 UTC.
+const CAPTURED: u64 = 1_717_233_330;
+
+#[test]
+fn test_capture_time() {
+    let metadata = RawMetadata {
+        date_time_original: Some("2024:06:01 18:15:30".into()),
+        offset_time_original: Some("+09:00".into()),
+        ..Default::default()
+    };
+    assert_eq!(
+        metadata.capture_time(),
+        Some(UNIX_EPOCH + Duration::from_secs(CAPTURED))
+    );
+
+    let metadata = RawMetadata {
+        date_time_original: Some("not a date".into()),
+        ..Default::default()
+    };
+    assert_eq!(metadata.capture_time(), None);
+}
+
+#[tokio::test]
+async fn test_timestamps() -> Result<()> {
+    let mut payload = b"2024:06:01 11:15:30\0+02:00\0".to_vec();
+    payload.resize(0x40, 0);
+    payload.extend(ifd_bytes(&[
+        (0x9003, ASCII, 20, PAYLOAD_OFFSET),
+        (0x9011, ASCII, 7, PAYLOAD_OFFSET + 20),
+    ]));
+    payload.resize(0x80, 0);
+    payload.extend_from_slice(TINY_JPEG);
+    let raw = tiff(
+        &[vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET + 0x80),
+            (0x202, LONG, 1, TINY_JPEG.len() as u32),
+            (0x8769, LONG, 1, PAYLOAD_OFFSET + 0x40),
+        ]],
+        &payload,
+    );
+    let temp = temp_dir();
+    let path = write_temp(&temp, "timestamps.nef", &raw);
+    let source = FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_times(&path, source, source)?;
+
+    for (timestamps, expected) in [
+        (Timestamps::Source, source),
+        (
+            Timestamps::Capture,
+            FileTime::from_unix_time(CAPTURED as i64, 0),
+        ),
+    ] {
+        let out_dir = path.with_file_name(format!("timestamps_{:?}_out", timestamps));
+        let options = ExtractOptions {
+            timestamps,
+            ..Default::default()
+        };
+        let outcome =
+            process_file_with_options(&path, &out_dir, "timestamps.nef".as_ref(), &options).await?;
+        let written = std::fs::metadata(outcome.written().unwrap())?;
+        assert_eq!(FileTime::from_last_modification_time(&written), expected);
+    }
+    Ok(())
+}
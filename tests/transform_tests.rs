@@ -0,0 +1,116 @@
+use anyhow::Result;
+use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+use jpgfromraw::transform::apply_orientation;
+
+const WIDTH: usize = 48;
+const HEIGHT: usize = 32;
+
+fn gradient(channels: usize) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(WIDTH * HEIGHT * channels);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            for c in 0..channels {
+                pixels.push((x * 5 + y * 3 + c * 40) as u8);
+            }
+        }
+    }
+    pixels
+}
+
+fn encode(pixels: &[u8], color: ColorType, sampling: SamplingFactor) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut encoder = Encoder::new(&mut out, 90);
+    encoder.set_sampling_factor(sampling);
+    // Camera previews often use restart intervals, so make sure we handle them.
+    encoder.set_restart_interval(2);
+    encoder.encode(pixels, WIDTH as u16, HEIGHT as u16, color)?;
+    Ok(out)
+}
+
+fn decode(jpeg: &[u8]) -> Result<(Vec<u8>, usize, usize)> {
+    let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+    let pixels = decoder.decode()?;
+    let info = decoder.info().unwrap();
+    Ok((pixels, info.width.into(), info.height.into()))
+}
+
+/// Where pixel (x, y) of an image stored with `orientation` ends up once displayed upright.
+fn displayed_at(orientation: u16, x: usize, y: usize, w: usize, h: usize) -> (usize, usize) {
+    match orientation {
+        1 => (x, y),
+        2 => (w - 1 - x, y),
+        3 => (w - 1 - x, h - 1 - y),
+        4 => (x, h - 1 - y),
+        5 => (y, x),
+        6 => (h - 1 - y, x),
+        7 => (h - 1 - y, w - 1 - x),
+        8 => (y, w - 1 - x),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn test_apply_orientation_grayscale_is_lossless() -> Result<()> {
+    let jpeg = encode(&gradient(1), ColorType::Luma, SamplingFactor::R_4_4_4)?;
+    let (orig, w, h) = decode(&jpeg)?;
+
+    for orientation in 1..=8 {
+        let rotated = apply_orientation(&jpeg, orientation)?;
+        let (pixels, rw, _) = decode(&rotated)?;
+        let transposed = orientation >= 5;
+        assert_eq!((rw, pixels.len()), (if transposed { h } else { w }, w * h));
+        for y in 0..h {
+            for x in 0..w {
+                let (dx, dy) = displayed_at(orientation, x, y, w, h);
+                let got = pixels[dy * rw + dx];
+                let want = orig[y * w + x];
+                assert!(
+                    got.abs_diff(want) <= 1,
+                    "orientation {orientation}: ({x}, {y}) was {want}, now {got}"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_apply_orientation_subsampled_dimensions() -> Result<()> {
+    let jpeg = encode(&gradient(3), ColorType::Rgb, SamplingFactor::R_4_2_0)?;
+    for orientation in 1..=8 {
+        let rotated = apply_orientation(&jpeg, orientation)?;
+        let (_, w, h) = decode(&rotated)?;
+        let expected = if orientation >= 5 {
+            (HEIGHT, WIDTH)
+        } else {
+            (WIDTH, HEIGHT)
+        };
+        assert_eq!((w, h), expected, "orientation {orientation}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_apply_orientation_trims_partial_mcus() -> Result<()> {
+    let pixels = vec![128; 20 * 20];
+    let mut jpeg = Vec::new();
+    Encoder::new(&mut jpeg, 90).encode(&pixels, 20, 20, ColorType::Luma)?;
+
+    let (_, w, h) = decode(&apply_orientation(&jpeg, 3)?)?;
+    assert_eq!((w, h), (16, 16));
+
+    // Transposing on its own moves nothing across an edge, so nothing is lost.
+    let (_, w, h) = decode(&apply_orientation(&jpeg, 5)?)?;
+    assert_eq!((w, h), (20, 20));
+    Ok(())
+}
+
+#[test]
+fn test_apply_orientation_rejects_progressive() -> Result<()> {
+    let mut jpeg = Vec::new();
+    let mut encoder = Encoder::new(&mut jpeg, 90);
+    encoder.set_progressive(true);
+    encoder.encode(&gradient(1), WIDTH as u16, HEIGHT as u16, ColorType::Luma)?;
+    assert!(apply_orientation(&jpeg, 6).is_err());
+    Ok(())
+}
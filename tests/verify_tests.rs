@@ -0,0 +1,182 @@
+mod common;
+
+use anyhow::Result;
+use common::{temp_dir, tiff, PAYLOAD_OFFSET};
+use jpeg_encoder::{ColorType, Encoder};
+use jpgfromraw::parser::process_file_with_options;
+use jpgfromraw::verify::{check_jpeg, verify, Problem};
+use jpgfromraw::{formats, process_file, ExtractOptions, FindJpegType, OutputFormat};
+use std::collections::HashSet;
+use std::ffi::OsString;
+
+const LONG: u16 = 4;
+
+fn encode(width: u16, height: u16) -> Result<Vec<u8>> {
+    let pixels = vec![128; usize::from(width) * usize::from(height) * 3];
+    let mut out = Vec::new();
+    Encoder::new(&mut out, 90).encode(&pixels, width, height, ColorType::Rgb)?;
+    Ok(out)
+}
+
+/// A RAW with `preview` in IFD0.
+fn raw_with_preview(preview: &[u8]) -> Vec<u8> {
+    tiff(
+        &[vec![
+            (0x201, LONG, 1, PAYLOAD_OFFSET),
+            (0x202, LONG, 1, preview.len() as u32),
+        ]],
+        preview,
+    )
+}
+
+#[test]
+fn test_check_jpeg() -> Result<()> {
+    let jpeg = encode(24, 16)?;
+    assert_eq!(check_jpeg(&jpeg)?, (24, 16));
+    assert!(check_jpeg(&jpeg[..jpeg.len() - 10]).is_err());
+    assert!(check_jpeg(b"not a jpeg").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_raw_extensions_are_unique() {
+    let extensions: Vec<_> = formats::raw_extensions().collect();
+    let unique: HashSet<_> = extensions.iter().collect();
+    assert_eq!(extensions.len(), unique.len());
+    assert!(extensions.contains(&"cr3"));
+}
+
+#[tokio::test]
+async fn test_verify() -> Result<()> {
+    let temp = temp_dir();
+    let dir = temp.path();
+    let in_dir = dir.join("in");
+    let out_dir = dir.join("out");
+    std::fs::create_dir_all(in_dir.join("sub"))?;
+    std::fs::write(in_dir.join("a.nef"), raw_with_preview(&encode(8, 8)?))?;
+    std::fs::write(in_dir.join("sub/b.NEF"), b"raw")?;
+    std::fs::create_dir_all(out_dir.join("sub"))?;
+    process_file(
+        &in_dir.join("a.nef"),
+        &out_dir,
+        "a.nef".as_ref(),
+        FindJpegType::Largest,
+    )
+    .await?;
+    let truncated = encode(8, 8)?;
+    std::fs::write(out_dir.join("stray.jpg"), &truncated[..truncated.len() / 2])?;
+
+    let raw_extensions: HashSet<_> = formats::raw_extensions().map(OsString::from).collect();
+    let verification = verify(
+        &out_dir,
+        Some(&in_dir),
+        &raw_extensions,
+        &ExtractOptions::default(),
+    )
+    .await?;
+    assert_eq!(verification.checked, 2);
+    assert_eq!(
+        verification.problems,
+        [
+            Problem::Invalid {
+                path: out_dir.join("stray.jpg"),
+                reason: "No EOI marker, truncated".into(),
+            },
+            Problem::Missing {
+                source: in_dir.join("sub/b.NEF"),
+                // Without a preview, it's copied as-is.
+                expected: out_dir.join("sub/b.NEF"),
+            },
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verify_outputs_match_sources() -> Result<()> {
+    let temp = temp_dir();
+    let dir = temp.path();
+    let in_dir = dir.join("in");
+    let out_dir = dir.join("out");
+    std::fs::create_dir_all(&in_dir)?;
+    std::fs::create_dir_all(&out_dir)?;
+    let raw = raw_with_preview(&encode(8, 8)?);
+    for name in ["kept.dng", "replaced.dng"] {
+        std::fs::write(in_dir.join(name), &raw)?;
+        process_file(
+            &in_dir.join(name),
+            &out_dir,
+            name.as_ref(),
+            FindJpegType::Largest,
+        )
+        .await?;
+    }
+    // Still a perfectly valid JPEG, just not this RAW's.
+    std::fs::write(out_dir.join("replaced.jpg"), encode(16, 8)?)?;
+
+    let raw_extensions: HashSet<_> = formats::raw_extensions().map(OsString::from).collect();
+    let options = ExtractOptions::default();
+    let verification = verify(&out_dir, Some(&in_dir), &raw_extensions, &options).await?;
+    assert_eq!(verification.checked, 2);
+    assert_eq!(
+        verification.problems,
+        [Problem::Mismatch {
+            source: in_dir.join("replaced.dng"),
+            output: out_dir.join("replaced.jpg"),
+        }]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verify_finds_outputs_by_name() -> Result<()> {
+    let temp = temp_dir();
+    let in_dir = temp.path().join("in");
+    std::fs::create_dir_all(&in_dir)?;
+    // The same stem, so one of them is renamed.
+    std::fs::write(in_dir.join("a.cr2"), raw_with_preview(&encode(8, 8)?))?;
+    std::fs::write(in_dir.join("a.dng"), raw_with_preview(&encode(16, 8)?))?;
+    let raw_extensions: HashSet<_> = formats::raw_extensions().map(OsString::from).collect();
+
+    let plain = ExtractOptions::default();
+    let templated = ExtractOptions {
+        name_template: Some("{ext}/{stem}_{seq}.jpg".parse()?),
+        format: OutputFormat::Png,
+        ..Default::default()
+    };
+    for (name, options) in [("plain", plain), ("templated", templated)] {
+        let out_dir = temp.path().join(name);
+        std::fs::create_dir_all(&out_dir)?;
+        for raw in ["a.cr2", "a.dng"] {
+            let input = in_dir.join(raw);
+            process_file_with_options(&input, &out_dir, raw.as_ref(), &options).await?;
+        }
+        let verification = verify(&out_dir, Some(&in_dir), &raw_extensions, &options).await?;
+        assert_eq!(verification.problems, [], "{}", name);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_verify_carries_on_past_extraction_errors() -> Result<()> {
+    let temp = temp_dir();
+    let in_dir = temp.path().join("in");
+    let out_dir = temp.path().join("out");
+    std::fs::create_dir_all(&in_dir)?;
+    std::fs::create_dir_all(&out_dir)?;
+    std::os::unix::fs::symlink(in_dir.join("nowhere"), in_dir.join("a.nef"))?;
+    std::fs::write(in_dir.join("b.nef"), raw_with_preview(&encode(8, 8)?))?;
+
+    let raw_extensions: HashSet<_> = formats::raw_extensions().map(OsString::from).collect();
+    let options = ExtractOptions::default();
+    let verification = verify(&out_dir, Some(&in_dir), &raw_extensions, &options).await?;
+    let [Problem::Extract { source, .. }, Problem::Missing { expected, .. }] =
+        &verification.problems[..]
+    else {
+        panic!("unexpected problems: {:?}", verification.problems);
+    };
+    assert_eq!(source, &in_dir.join("a.nef"));
+    assert_eq!(expected, &out_dir.join("b.jpg"));
+    Ok(())
+}